@@ -1,26 +1,55 @@
-use crate::config::{Config, ParseError, System};
+use crate::config::{
+    ArtifactMode, CacheBackend, CheckNaming, Config, InvalidConfig, OutputPath, ParseError,
+    PushMode, System,
+};
 use crate::graph::Graph;
-use crate::nix::{run, run_stream};
-use anyhow::{bail, Result};
-use log::{debug, info, warn};
-use std::collections::{HashMap, HashSet};
+use crate::nix::{
+    interrupted, run, run_stderr, run_stream, run_stream_stdout, run_with_env, try_run, Captured,
+    Interrupted, Ran,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn};
+use s_string::s;
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
-use std::fmt::Display;
+use std::fmt::{Display, Write};
 use std::fs;
-use std::os::unix::fs::symlink;
+use std::os::unix::fs::{symlink, PermissionsExt};
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use which::which;
+mod eval_cache;
+mod events;
+mod init;
+use eval_cache::EvalCache;
+use events::{Event, Events};
+pub use init::init;
 mod summary;
-use summary::Summary;
+pub use summary::{ColorChoice, GitRevision, Summary, SummaryFormat};
+mod tracker;
+use tracker::run_levels;
 use winnow::prelude::*;
 
+const LEGACY_PACKAGES: &str = "legacyPackages";
+/// Most legacyPackages attributes that may be built at once, so a loose pattern can't end up
+/// evaluating all of nixpkgs
+const MAX_LEGACY_PACKAGES: usize = 500;
+const SPINNER_TICK: Duration = Duration::from_millis(100);
 const CACHIX_AUTH_KEY: &str = "CACHIX_AUTH_TOKEN";
 const CACHIX_SIGNING_KEY: &str = "CACHIX_SIGNING_KEY";
+pub const GITHUB_STEP_SUMMARY: &str = "GITHUB_STEP_SUMMARY";
 
+/// One attribute of a flake output, like `.#packages.x86_64-linux.foo`
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct Derivation {
+pub struct Derivation {
     output: String,
     system: System,
     name: String,
@@ -49,14 +78,693 @@ fn derivation(s: &mut &str) -> winnow::Result<Derivation> {
 
 impl FromStr for Derivation {
     type Err = ParseError;
+    /// Parses `output.system.name`, with or without the `.#` it's displayed with
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.strip_prefix(".#").unwrap_or(s);
         derivation.parse(s).map_err(|e| ParseError::from_parse(&e))
     }
 }
 
+impl Derivation {
+    /// The attribute path within the flake, without the `.#` it's displayed with
+    pub fn attribute(&self) -> String {
+        if is_singular(&self.output) {
+            format!("{}.{}", self.output, self.system)
+        } else if is_per_system(&self.output) {
+            format!("{}.{}.{}", self.output, self.system, self.name)
+        } else {
+            format!("{}.{}", self.output, self.name)
+        }
+    }
+}
+
 impl Display for Derivation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, ".#{}.{}.{}", self.output, self.system, self.name)
+        write!(f, ".#{}", self.attribute())
+    }
+}
+
+/// What a derivation is pinned as in cachix. Its attribute path, so the same package on two
+/// systems gets two pins, and singular outputs, which have no name, still get one
+fn pin_name(derivation: &Derivation) -> String {
+    derivation.attribute()
+}
+
+/// Sleep, waking early if interrupted
+fn sleep_unless_interrupted(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !interrupted() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        thread::sleep(remaining.min(Duration::from_millis(100)));
+    }
+}
+
+/// Configuration outputs are keyed by name only, everything else by system then name
+fn is_per_system(output: &str) -> bool {
+    !matches!(
+        output,
+        "nixosConfigurations" | "darwinConfigurations" | "homeConfigurations"
+    )
+}
+
+/// Legacy outputs with a single derivation per system rather than an attribute set of them.
+/// Their derivations have an empty name
+fn is_singular(output: &str) -> bool {
+    matches!(output, "defaultPackage" | "devShell" | "formatter")
+}
+
+/// A derivation and its drv path
+pub type Node = (Derivation, String);
+
+/// A successfully built derivation and its out paths
+type Built = (Derivation, Vec<PathBuf>);
+
+/// Pull store paths out of `nix build --print-out-paths` output, ignoring anything else that
+/// ended up on stdout (like cachix chatter)
+fn out_paths(stdout: &str) -> Vec<PathBuf> {
+    stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with('/'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Out paths listed in `nix derivation show` output. None if any output doesn't have a known path
+/// yet (like content addressed derivations)
+fn derivation_out_paths(stdout: &str) -> Result<Option<Vec<String>>> {
+    let json: serde_json::Value = serde_json::from_str(stdout)?;
+    let Some(derivations) = json.as_object() else {
+        bail!("Unexpected `nix derivation show` output: {stdout}");
+    };
+
+    let mut out_paths = Vec::new();
+    for derivation in derivations.values() {
+        let Some(outputs) = derivation["outputs"].as_object() else {
+            return Ok(None);
+        };
+        for output in outputs.values() {
+            let Some(path) = output["path"].as_str() else {
+                return Ok(None);
+            };
+            // Newer nix versions leave off the store dir
+            let path = if path.starts_with('/') {
+                path.to_owned()
+            } else {
+                format!("/nix/store/{path}")
+            };
+            out_paths.push(path);
+        }
+    }
+    Ok(Some(out_paths))
+}
+
+/// The closure sizes in `nix path-info --json --closure-size` output, added up. Older nix
+/// versions print a list of paths, newer ones an object keyed by path
+fn closure_size(stdout: &str) -> Result<u64> {
+    let json: serde_json::Value = serde_json::from_str(stdout)?;
+    let infos: Vec<&serde_json::Value> = match &json {
+        serde_json::Value::Array(infos) => infos.iter().collect(),
+        serde_json::Value::Object(infos) => infos.values().collect(),
+        _ => bail!("Unexpected `nix path-info` output: {stdout}"),
+    };
+    let mut total = 0;
+    for info in infos {
+        let Some(size) = info["closureSize"].as_u64() else {
+            bail!("No closure size in `nix path-info` output: {stdout}");
+        };
+        total += size;
+    }
+    Ok(total)
+}
+
+/// Attribute names of an output (for one system, if it's per-system) in `nix flake show --json`
+/// output. None if the output isn't there, or nix didn't descend into it
+fn shown_attributes(show: &serde_json::Value, output: &str, system: System) -> Option<Vec<String>> {
+    let mut tree = show.get(output)?;
+    if is_per_system(output) {
+        tree = tree.get(system.to_string())?;
+    }
+    if is_singular(output) {
+        return tree.get("type").map(|_| vec![String::new()]);
+    }
+    let attributes = tree.as_object()?;
+    // Things like legacyPackages are listed but left empty
+    if attributes.is_empty() || attributes.values().any(|value| value.get("type").is_none()) {
+        return None;
+    }
+    Some(attributes.keys().cloned().collect())
+}
+
+/// Systems named under the per-system outputs among `outputs` in `nix flake show --json` output
+fn shown_systems<'a>(
+    show: &serde_json::Value,
+    outputs: impl IntoIterator<Item = &'a String>,
+) -> BTreeSet<System> {
+    outputs
+        .into_iter()
+        .filter(|output| is_per_system(output))
+        .filter_map(|output| show.get(output)?.as_object())
+        .flat_map(|tree| tree.keys())
+        .filter_map(|system| System::from_str(system).ok())
+        .collect()
+}
+
+/// What nix said differed, if a failed `nix build --rebuild` failed because the outputs came out
+/// different
+fn nondeterminism(error: &str) -> Option<&str> {
+    let line = error
+        .lines()
+        .find(|line| line.contains("may not be deterministic"))?;
+    let message = line
+        .split_once("error: ")
+        .map_or(line, |(_, message)| message);
+    Some(message.trim())
+}
+
+/// Cut levels down to the nodes in `keep`, dropping any left empty
+fn keep_in_levels(levels: Vec<Vec<Node>>, keep: &HashSet<&Node>) -> Vec<Vec<Node>> {
+    levels
+        .into_iter()
+        .map(|level| -> Vec<Node> {
+            level
+                .into_iter()
+                .filter(|node| keep.contains(node))
+                .collect()
+        })
+        .filter(|level| !level.is_empty())
+        .collect()
+}
+
+/// Cut chains down to the nodes matching a filter plus everything they depend on, so the checks
+/// gating a filtered package still run first
+fn filter_chains(
+    chains: Vec<Vec<Node>>,
+    dependencies: &HashMap<Node, Vec<Node>>,
+    filters: &[OutputPath],
+) -> Vec<Vec<Node>> {
+    if filters.is_empty() {
+        return chains;
+    }
+
+    let mut keep: HashSet<&Node> = HashSet::new();
+    let mut stack: Vec<&Node> = chains
+        .iter()
+        .flatten()
+        .filter(|(derivation, _)| {
+            filters.iter().any(|filter| {
+                filter.matches(&derivation.output, derivation.system, &derivation.name)
+            })
+        })
+        .collect();
+    while let Some(node) = stack.pop() {
+        if keep.insert(node) {
+            if let Some(parents) = dependencies.get(node) {
+                stack.extend(parents);
+            }
+        }
+    }
+
+    let mut filtered: Vec<Vec<Node>> = chains
+        .iter()
+        .map(|chain| {
+            chain
+                .iter()
+                .filter(|node| keep.contains(node))
+                .cloned()
+                .collect()
+        })
+        .collect();
+    filtered.retain(|chain: &Vec<Node>| !chain.is_empty());
+    filtered
+}
+
+/// A warning for each `[[checks]]` gate that matches nothing in the graph: the package it names
+/// doesn't exist for the check's system, or wasn't evaluated
+fn dangling_gates(graph: &Graph<Node>, config: &Config) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (check, _) in graph.nodes().filter(|(node, _)| node.output == "checks") {
+        let Some(gates) = config.check_gates(&check.name) else {
+            continue;
+        };
+        for gate in gates {
+            let matched = graph.nodes().any(|(derivation, _)| {
+                gate.matches(&derivation.output, derivation.system, &derivation.name)
+            });
+            if !matched {
+                warnings.push(format!(
+                    "{check} gates {gate}, but nothing matching it is being built for {}",
+                    check.system
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+/// The legacyPackages attributes selected by `patterns`. legacyPackages is usually all of nixpkgs,
+/// so it's never built without a pattern and a pattern can only select so much of it
+fn select_legacy_packages(
+    names: Vec<String>,
+    patterns: &[OutputPath],
+    system: System,
+) -> Result<Vec<String>> {
+    if patterns.is_empty() {
+        bail!(InvalidConfig(format!(
+            "{LEGACY_PACKAGES} is too big to build all of. Pick attributes with [build] \
+             legacy-packages or --filter"
+        )));
+    }
+
+    let output = s!(LEGACY_PACKAGES);
+    let selected: Vec<String> = names
+        .into_iter()
+        .filter(|name| {
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches(&output, system, name))
+        })
+        .collect();
+    if selected.len() > MAX_LEGACY_PACKAGES {
+        bail!(InvalidConfig(format!(
+            "{} {LEGACY_PACKAGES} attributes match, which is more than the limit of \
+             {MAX_LEGACY_PACKAGES}. Use a narrower pattern",
+            selected.len()
+        )));
+    }
+    Ok(selected)
+}
+
+/// One block per chain, listing each derivation and its drv path in build order
+pub fn format_chains(chains: &[Vec<Node>]) -> String {
+    let mut out = String::new();
+    for (i, chain) in chains.iter().enumerate() {
+        writeln!(out, "Chain {}", i + 1).expect("Writing to a String can't fail");
+        for (derivation, path) in chain {
+            writeln!(out, "  {derivation} {path}").expect("Writing to a String can't fail");
+        }
+    }
+    out
+}
+
+/// Every drv path in build order, one per line and each only once, for `xargs nix build`
+pub fn format_drv_paths(chains: &[Vec<Node>]) -> String {
+    let mut seen = HashSet::new();
+    let mut out = String::new();
+    for (_, path) in chains.iter().flatten() {
+        if seen.insert(path) {
+            writeln!(out, "{path}").expect("Writing to a String can't fail");
+        }
+    }
+    out
+}
+
+/// `f` applied to every item on up to `jobs` threads, with the results in the same order as
+/// the items
+fn map_in_parallel<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    thread::scope(|s| {
+        for _ in 0..jobs.min(items.len()).max(1) {
+            s.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("Every item is mapped"))
+        .collect()
+}
+
+/// The packages that changed files belong to, going by `package_dirs` holding a directory or
+/// .nix file per package. None if any file isn't in one, since there's no telling what that
+/// changes
+fn changed_packages(files: &[&str], package_dirs: &[String]) -> Option<BTreeSet<String>> {
+    let mut packages = BTreeSet::new();
+    for file in files {
+        let package = package_dirs.iter().find_map(|dir| {
+            let rest = file
+                .strip_prefix(dir.trim_end_matches('/'))?
+                .strip_prefix('/')?;
+            match rest.split_once('/') {
+                Some((package, _)) => Some(package),
+                None => rest.strip_suffix(".nix"),
+            }
+        })?;
+        packages.insert(package.to_owned());
+    }
+    Some(packages)
+}
+
+/// What `nix build --dry-run` would do for a derivation
+#[derive(Debug, Default, PartialEq, Eq)]
+struct BuildPlan {
+    builds: usize,
+    fetches: usize,
+    /// How much fetching would download, like "12.34 MiB"
+    download: Option<String>,
+}
+
+impl Display for BuildPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.builds == 0 && self.fetches == 0 {
+            return write!(f, "dry run, nothing to build or fetch");
+        }
+        write!(
+            f,
+            "dry run, would build {} and fetch {}",
+            self.builds, self.fetches
+        )?;
+        if let Some(download) = &self.download {
+            write!(f, " ({download} download)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Read what would be built and fetched from `nix build --dry-run` stderr, which lists
+/// derivations under "this derivation will be built:" or "these N derivations will be built:"
+/// and paths under "these N paths will be fetched (X MiB download, Y MiB unpacked):"
+fn parse_build_plan(stderr: &str) -> BuildPlan {
+    let mut plan = BuildPlan::default();
+    let mut counting = None;
+    for line in stderr.lines() {
+        if line.starts_with(' ') {
+            match counting {
+                Some(true) => plan.builds += 1,
+                Some(false) => plan.fetches += 1,
+                None => {}
+            }
+        } else if line.contains("will be built") {
+            counting = Some(true);
+        } else if line.contains("will be fetched") {
+            counting = Some(false);
+            plan.download = line
+                .split_once('(')
+                .and_then(|(_, sizes)| sizes.split_once(" download"))
+                .map(|(download, _)| download.to_owned());
+        } else {
+            counting = None;
+        }
+    }
+    plan
+}
+
+/// Put the configured systems that aren't getting built in the summary
+fn record_skipped_systems(configured: &[System], building: &[System], summary: &mut Summary) {
+    for system in configured {
+        if !building.contains(system) {
+            summary.skip_system(system.to_string());
+        }
+    }
+}
+
+/// Quote an argument for a POSIX shell, if it needs it
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:@+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// What a dry run would build for a system: each chain in order, with every derivation's drv
+/// path and the command that builds it
+fn format_plan(system: System, chains: &[Vec<Node>], command: impl Fn(&str) -> String) -> String {
+    let mut out = String::new();
+    let mut write = |line: String| writeln!(out, "{line}").expect("Writing to a String can't fail");
+    write(format!("==== Plan for {system} ===="));
+    for (i, chain) in chains.iter().enumerate() {
+        write(format!("Chain {}", i + 1));
+        for (step, (derivation, path)) in chain.iter().enumerate() {
+            write(format!("  {}. {derivation}", step + 1));
+            write(format!("     drv: {path}"));
+            write(format!("     run: {}", command(path)));
+        }
+    }
+    write(format!("==== End of plan for {system} ===="));
+    out
+}
+
+/// Name of a store path with the hash stripped
+fn store_name(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match name.split_once('-') {
+        Some((_, name)) => name.to_owned(),
+        None => name,
+    }
+}
+
+/// The default (`out`) output. Other outputs are named `<out name>-<output>`, so it's the
+/// shortest name
+fn main_out_path(out_paths: &[PathBuf]) -> Option<&PathBuf> {
+    out_paths.iter().min_by_key(|path| store_name(path).len())
+}
+
+/// Link names for each out path, mirroring nix's `result`, `result-dev`, etc. naming
+fn artifact_links<'a>(link_name: &str, out_paths: &'a [PathBuf]) -> Vec<(String, &'a PathBuf)> {
+    let Some(main) = main_out_path(out_paths) else {
+        return Vec::new();
+    };
+    let main_name = store_name(main);
+
+    out_paths
+        .iter()
+        .map(|path| {
+            if path == main {
+                return (link_name.to_owned(), path);
+            }
+            let name = store_name(path);
+            let suffix = name
+                .strip_prefix(&format!("{main_name}-"))
+                .unwrap_or(&name)
+                .to_owned();
+            (format!("{link_name}-{suffix}"), path)
+        })
+        .collect()
+}
+
+/// Save out paths in the output dir under names starting with `link_name`. Anything already
+/// there under the same name is replaced rather than failing the build
+fn save_artifacts(
+    output_dir: &Path,
+    mode: ArtifactMode,
+    link_name: &str,
+    out_paths: &[PathBuf],
+) -> Result<Vec<PathBuf>> {
+    let mut links = Vec::new();
+    for (name, artifact) in artifact_links(link_name, out_paths) {
+        debug!("artifact to save: {}", artifact.display());
+        let link = match mode {
+            ArtifactMode::Tar => output_dir.join(format!("{name}.tar.gz")),
+            ArtifactMode::Symlink | ArtifactMode::Copy => output_dir.join(&name),
+        };
+        debug!("link: {}", link.display());
+        remove_existing(&link).with_context(|| format!("Failed to replace {}", link.display()))?;
+        match mode {
+            ArtifactMode::Symlink => symlink(artifact, &link)?,
+            ArtifactMode::Copy => copy_tree(artifact, &link).with_context(|| {
+                format!(
+                    "Failed to copy {} to {}",
+                    artifact.display(),
+                    link.display()
+                )
+            })?,
+            ArtifactMode::Tar => archive_tree(artifact, &name, &link).with_context(|| {
+                format!(
+                    "Failed to archive {} to {}",
+                    artifact.display(),
+                    link.display()
+                )
+            })?,
+        }
+        links.push(link);
+    }
+    Ok(links)
+}
+
+/// Remove a file, link or directory if there is one
+fn remove_existing(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Copy a file or directory, following symlinks so nothing points back into the store. Store
+/// files are read-only, so copies are made writable for the next run to clean up
+fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    if fs::metadata(from)?.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
+        let mut permissions = fs::metadata(to)?.permissions();
+        permissions.set_mode(permissions.mode() | 0o200);
+        fs::set_permissions(to, permissions)?;
+    }
+    Ok(())
+}
+
+/// Write a gzipped tarball of a file or directory, with everything under `root`. Symlinks are
+/// followed like when copying
+fn archive_tree(from: &Path, root: &str, archive: &Path) -> Result<()> {
+    let file = fs::File::create(archive)?;
+    let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    tar.follow_symlinks(true);
+    if fs::metadata(from)?.is_dir() {
+        tar.append_dir_all(root, from)?;
+    } else {
+        tar.append_path_with_name(from, root)?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Where a flake ref points on disk, if it's a plain path like `/path/to/flake` or `../flake`.
+/// Anything else (`github:`, `git+https:`, registry names, ...) isn't a local checkout
+fn local_flake_dir(reference: &str) -> Option<&Path> {
+    reference
+        .starts_with(['/', '.'])
+        .then(|| Path::new(reference))
+}
+
+/// What's wrong with a flake's lock file: usually that someone forgot to commit an update
+fn flake_lock_problem(flake_dir: &Path) -> Result<Option<String>> {
+    let Ok(nix) = fs::metadata(flake_dir.join("flake.nix")) else {
+        // Not a flake, nix will have more to say about that
+        return Ok(None);
+    };
+    let nix = nix.modified()?;
+    let lock = match fs::metadata(flake_dir.join("flake.lock")) {
+        Ok(lock) => lock.modified()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Some(s!("flake.lock is missing")));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if lock < nix {
+        return Ok(Some(s!("flake.lock is older than flake.nix")));
+    }
+    Ok(None)
+}
+
+/// Revisions of the root's direct inputs in `nix flake metadata --json` output. Inputs that
+/// follow another input, or are locked without a revision (like paths), are left out
+fn locked_revisions(metadata: &serde_json::Value) -> BTreeMap<String, String> {
+    let nodes = &metadata["locks"]["nodes"];
+    let Some(inputs) = nodes["root"]["inputs"].as_object() else {
+        return BTreeMap::new();
+    };
+    inputs
+        .iter()
+        .filter_map(|(name, node)| {
+            let rev = nodes[node.as_str()?]["locked"]["rev"].as_str()?;
+            Some((name.clone(), rev.to_owned()))
+        })
+        .collect()
+}
+
+/// Everything a run did
+#[derive(Debug)]
+pub struct Finished {
+    pub exit: Exit,
+    pub summary: Summary,
+    /// On a dry run, the plan for each system that would have been built
+    pub plan: String,
+}
+
+/// How a run ended. Each variant is the process's exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Success = 0,
+    BuildFailure = 1,
+    Config = 2,
+    MissingTool = 3,
+    Interrupted = 130,
+}
+
+impl Exit {
+    /// Exit code for a run that stopped with an error
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        if error.is::<Interrupted>() {
+            Self::Interrupted
+        } else if error.is::<MissingTool>() {
+            Self::MissingTool
+        } else if error.is::<InvalidConfig>() {
+            Self::Config
+        } else {
+            Self::BuildFailure
+        }
+    }
+
+    /// Exit code for a run that finished, whether everything `built` and the summary passed
+    pub fn from_run(built: bool, summary: &Summary) -> Self {
+        if built && summary.passed() {
+            Self::Success
+        } else {
+            Self::BuildFailure
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+/// A binary flake-ci needs couldn't be found
+#[derive(Debug)]
+pub struct MissingTool(pub String);
+
+impl Display for MissingTool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for MissingTool {}
+
+/// Where a system's derivations get built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Builder {
+    Native,
+    Remote,
+    Emulated,
+}
+
+impl Display for Builder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Native => write!(f, "native"),
+            Self::Remote => write!(f, "remote builders"),
+            Self::Emulated => write!(f, "emulated"),
+        }
     }
 }
 
@@ -64,9 +772,22 @@ impl Display for Derivation {
 pub enum Status {
     Skipped,
     Success,
+    /// Everything was already in the store, so nothing had to be built
+    Cached,
     Fail,
 }
 
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Skipped => "skipped",
+            Self::Success => "success",
+            Self::Cached => "cached",
+            Self::Fail => "failed",
+        }
+    }
+}
+
 fn get_version(bin: &Path) -> Result<String> {
     let output = run(bin, &["--version"])?;
     let version = output.lines().next().unwrap();
@@ -77,45 +798,199 @@ fn nix_version(nix: &Path) -> Result<String> {
     get_version(nix)
 }
 
+/// A nix release, ignoring any pre-release suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Version {
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+fn version(s: &mut &str) -> winnow::Result<Version> {
+    use winnow::ascii::dec_uint;
+    use winnow::combinator::{opt, preceded};
+    winnow::combinator::seq! {Version {
+        major: dec_uint,
+        _: ".",
+        minor: dec_uint,
+        patch: opt(preceded(".", dec_uint)).map(Option::unwrap_or_default),
+    }}
+    .parse_next(s)
+}
+
+/// Pull the `x.y.z` out of a version, which is the last thing on the `nix --version` line
+fn parse_version(input: &str) -> Option<Version> {
+    input
+        .split_whitespace()
+        .rev()
+        .find_map(|mut word| version.parse_next(&mut word).ok())
+}
+
+/// Bail if the installed nix is older than the configured minimum
+fn check_nix_version(nix_version: &str, min_version: &str) -> Result<()> {
+    let Some(min) = parse_version(min_version) else {
+        bail!(InvalidConfig(format!(
+            "Can't parse min-nix-version '{min_version}'"
+        )));
+    };
+    let Some(installed) = parse_version(nix_version) else {
+        warn!("Can't tell what version '{nix_version}' is, assuming it's new enough");
+        return Ok(());
+    };
+    if installed < min {
+        bail!(MissingTool(format!(
+            "nix {installed} is installed, but the config requires at least {min}"
+        )));
+    }
+    Ok(())
+}
+
+/// The store check's args. It's a store command, so it only gets global flags; eval flags like
+/// `--override-input` would make nix reject it
+fn store_check_args(offline: bool) -> Vec<&'static str> {
+    let mut args = vec!["store", "ping"];
+    if offline {
+        args.push("--offline");
+    }
+    args
+}
+
 fn cachix_version(cachix: &Path) -> Result<String> {
     get_version(cachix)
 }
 
-fn git_revision() -> Result<String> {
-    let git = which("git")?;
-    let commit_hash = run(&git, &["rev-parse", "--short", "HEAD"])?;
-    let dirty = if run(&git, &["status", "--porcelain"])?.is_empty() {
-        ""
-    } else {
-        " (dirty)"
+/// Where the repo in `dir` is at. Only for the summary, so without git, outside of a git repo,
+/// or before the first commit it's unknown instead of an error
+fn git_revision(dir: &Path) -> GitRevision {
+    let Ok(git) = which("git") else {
+        warn!("Unable to find git on the $PATH, so the git revision is unknown");
+        return GitRevision::unknown();
+    };
+    let dir = dir.to_string_lossy();
+    let git_in = |args: &[&str]| run(&git, &[&["-C", &dir], args].concat());
+    let Ok(hash) = git_in(&["rev-parse", "HEAD"]) else {
+        warn!("Not in a git repo with any commits, so the git revision is unknown");
+        return GitRevision::unknown();
+    };
+    // `HEAD` when detached
+    let branch = git_in(&["rev-parse", "--abbrev-ref", "HEAD"])
+        .ok()
+        .filter(|branch| branch != "HEAD");
+    // Falls back to the short hash when there are no tags to describe it with
+    let describe = git_in(&["describe", "--tags", "--always"])
+        .ok()
+        .filter(|describe| !hash.starts_with(describe.as_str()));
+    let dirty = git_in(&["status", "--porcelain"]).is_ok_and(|status| !status.is_empty());
+    GitRevision::new(hash, branch, describe, dirty)
+}
+
+/// Use the configured binary if there is one, otherwise look for it on the $PATH
+pub(crate) fn find_tool(name: &str, configured: Option<&Path>) -> Result<PathBuf> {
+    let Some(path) = configured else {
+        let Ok(path) = which(name) else {
+            bail!(MissingTool(format!("Unable to find {name} on the $PATH")));
+        };
+        return Ok(path);
     };
-    let revision = format!("{commit_hash}{dirty}");
-    Ok(revision)
+
+    let executable = fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0);
+    if !executable {
+        bail!(MissingTool(format!(
+            "Configured {name} '{}' is not an executable file",
+            path.display()
+        )));
+    }
+    Ok(path.to_owned())
 }
 
 fn env_set(key: &str) -> bool {
     env::var(key).is_ok()
 }
 
-fn setup_cachix(cachix: &Path, cache: &str, dry_run: bool) -> Result<()> {
-    if !(env_set(CACHIX_AUTH_KEY) || env_set(CACHIX_SIGNING_KEY)) {
-        bail!("Neither env var {CACHIX_AUTH_KEY} or {CACHIX_SIGNING_KEY} set. At least one is required for cachix support");
+/// A secret from a file, without the trailing newline editors and `echo` leave
+fn read_secret(path: &Path) -> Result<String> {
+    let secret = fs::read_to_string(path).with_context(|| {
+        InvalidConfig(format!("Failed to read secret file '{}'", path.display()))
+    })?;
+    Ok(secret.trim_end().to_owned())
+}
+
+/// Which cachix secrets are around, from the env or secret files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CachixCredentials {
+    auth_token: bool,
+    signing_key: bool,
+}
+
+impl CachixCredentials {
+    /// Secrets in `env` (which has the ones read from files) or flake-ci's own environment
+    fn from_env(env: &HashMap<String, String>) -> Self {
+        let set = |key| env.contains_key(key) || env_set(key);
+        Self {
+            auth_token: set(CACHIX_AUTH_KEY),
+            signing_key: set(CACHIX_SIGNING_KEY),
+        }
+    }
+
+    /// Make sure the secrets cover what the config does with cachix. Either one pushes (a
+    /// signing key alone can push to caches with their own signing key), but only a token can
+    /// pin
+    fn check(self, publishing: bool, pinning: bool) -> Result<()> {
+        if !(self.auth_token || self.signing_key) {
+            bail!(InvalidConfig(format!(
+                "Neither env var {CACHIX_AUTH_KEY} or {CACHIX_SIGNING_KEY} set, and no \
+                 auth-token-file or signing-key-file configured. At least one is required for \
+                 cachix support"
+            )));
+        }
+        if pinning && !self.auth_token {
+            let why = if publishing {
+                "only a signing key is set, which can push but not pin"
+            } else {
+                "it isn't set"
+            };
+            bail!(InvalidConfig(format!(
+                "[cachix] pin needs {CACHIX_AUTH_KEY} (or auth-token-file), but {why}"
+            )));
+        }
+        if publishing && !self.auth_token {
+            info!("Pushing to cachix with {CACHIX_SIGNING_KEY} only");
+        }
+        Ok(())
     }
+}
 
+fn setup_cachix(
+    cachix: &Path,
+    caches: &[String],
+    env: &HashMap<String, String>,
+    dry_run: bool,
+) -> Result<()> {
     info!("Using cachix");
 
-    run_stream(cachix, &["use", cache], None, dry_run)?;
+    for cache in caches {
+        run_stream(cachix, &["use", cache], Some(env), dry_run)?;
+    }
     Ok(())
 }
 
-fn find_check_type(input: &str) -> Result<&'static str> {
-    let mut input = input.to_lowercase();
-    if let Some(stripped) = input.strip_suffix('s') {
-        input = stripped.to_string();
-    };
+fn find_check_type<'a>(input: &str, naming: &'a CheckNaming) -> Result<&'a str> {
+    let input = input.to_lowercase();
+    let singular = input.strip_suffix('s').unwrap_or(&input);
+    if let Some(output) = naming.alias(&input).or_else(|| naming.alias(singular)) {
+        return Ok(output);
+    }
 
-    let res = match input.as_str() {
+    let res = match singular {
         "pkg" | "package" => "packages",
+        "app" => "apps",
         "devshell" | "shell" => "devShells",
         "nixo" | "nixosconfig" | "nixosconfiguration" => "nixosConfigurations",
         "darwin" | "darwinconfig" | "darwinconfiguration" => "darwinConfigurations",
@@ -127,19 +1002,39 @@ fn find_check_type(input: &str) -> Result<&'static str> {
     Ok(res)
 }
 
-fn get_type_of_check(derivation: &Derivation) -> Result<&'static str> {
+/// Which output a check gates, going by the `<type>-<name>` naming convention
+fn get_type_of_check<'a>(derivation: &Derivation, naming: &'a CheckNaming) -> Result<&'a str> {
     let name = &derivation.name;
-    let Some((prefix, _)) = name.split_once('-') else {
-        bail!("TODO: better error message");
+    let separator = naming.separator();
+    let Some((prefix, _)) = name.split_once(separator) else {
+        bail!(
+            "Check '{derivation}' doesn't gate anything: its name isn't `<type>{separator}<name>` \
+             (like `pkg{separator}foo`). Use [[checks]] gates to pick what it gates"
+        );
     };
 
-    find_check_type(&prefix)
+    find_check_type(prefix, naming).with_context(|| {
+        format!("Check '{derivation}' doesn't gate anything: '{prefix}' isn't a known type")
+    })
+}
+
+/// Nix function mapping an attribute of `output` to its drv path
+fn drv_path_expr(output: &str) -> &'static str {
+    match output {
+        // An app's program is a string, but its context remembers the derivation it came from
+        "apps" => "app: builtins.head (builtins.attrNames (builtins.getContext app.program))",
+        "nixosConfigurations" | "darwinConfigurations" => {
+            "cfg: cfg.config.system.build.toplevel.drvPath"
+        }
+        "homeConfigurations" => "cfg: cfg.activationPackage.drvPath",
+        _ => "pkg: pkg.drvPath",
+    }
 }
 
-fn check_checks_derivation(check: &Derivation, drv: &Derivation) -> bool {
+fn check_checks_derivation(check: &Derivation, drv: &Derivation, naming: &CheckNaming) -> bool {
     if check.system == drv.system {
-        if let Some((prefix, suffix)) = check.name.split_once('-') {
-            if let Ok(check_type) = find_check_type(prefix) {
+        if let Some((prefix, suffix)) = check.name.split_once(naming.separator()) {
+            if let Ok(check_type) = find_check_type(prefix, naming) {
                 if check_type.to_lowercase() == drv.output.to_lowercase() {
                     return suffix.to_lowercase() == drv.name.to_lowercase();
                 }
@@ -150,16 +1045,85 @@ fn check_checks_derivation(check: &Derivation, drv: &Derivation) -> bool {
     false
 }
 
+/// Flags that change how a run behaves, as opposed to what gets built
+#[derive(Debug, Default, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct RunOptions {
+    /// Print what would be done without doing anything
+    pub dry_run: bool,
+    /// Record errors against the derivation that caused them instead of aborting the run
+    pub keep_going: bool,
+    /// Stop building anything more at the first failure
+    pub fail_fast: bool,
+    /// Don't check that the nix store is reachable before starting
+    pub skip_store_check: bool,
+    /// Keep nix off the network, and don't use or push to caches
+    pub offline: bool,
+    /// Report to GitHub Actions with annotations and a job summary
+    pub github: bool,
+    /// Also write a JUnit XML report here
+    pub junit: Option<PathBuf>,
+    /// Build even when the outputs are already in the store
+    pub rebuild: bool,
+    /// When to color the summary
+    pub color: ColorChoice,
+    /// Print the summary's jobs under a header for each system
+    pub group_by_system: bool,
+    /// Rebuild every success and record whether its outputs came out the same
+    pub check_reproducibility: bool,
+    /// Record the closure size of every success
+    pub sizes: bool,
+    /// Leave the artifact dir alone and don't save anything into it
+    pub no_artifacts: bool,
+    /// Pass build logs through as nix prints them, without the derivation in front of each line
+    pub no_prefix: bool,
+    /// On a dry run, ask nix what each derivation would build and fetch
+    pub explain: bool,
+    /// Evaluate everything instead of reusing attributes and drv paths from earlier runs
+    pub no_eval_cache: bool,
+    /// Flake ref to build instead of the flake in the working dir
+    pub flake: Option<String>,
+    /// Only build this one of the configured systems
+    pub only_system: Option<System>,
+    /// Only build derivations matching one of these (and what they depend on)
+    pub filters: Vec<OutputPath>,
+    /// Build exactly these (and the checks gating them) instead of enumerating outputs
+    pub targets: Vec<Derivation>,
+    /// Only build packages changed since this git ref (and their checks), going by
+    /// `[build] package-dirs`
+    pub changed_since: Option<String>,
+    /// Flake inputs to override, as (input name, flake ref), for every eval and build
+    pub override_inputs: Vec<(String, String)>,
+    /// Write build events here as they happen, one JSON object per line. `-` is stdout
+    pub events: Option<PathBuf>,
+    /// Show a spinner while evaluating and a progress bar while building
+    pub progress: bool,
+    /// Fail instead of warning when flake.lock is missing or stale, or when none of the
+    /// configured systems can build here
+    pub strict: bool,
+}
+
 #[derive(Debug)]
 pub struct App {
     cwd: PathBuf,
+    /// The flake ref every installable starts with, `.` unless `--flake` says otherwise
+    flake: String,
+    /// Where the flake is checked out. None for flakes that aren't on disk, like `github:` refs
+    flake_dir: Option<PathBuf>,
     output_dir: PathBuf,
-    nix_result_dir: PathBuf,
     config: Config,
     nix: PathBuf,
     cachix: Option<PathBuf>,
+    /// The `[env]` table, interpolated
+    env: HashMap<String, String>,
+    /// `env` plus cachix secrets read from files, for cachix calls
+    cachix_env: HashMap<String, String>,
+    events: Option<Events>,
+    /// Attributes and drv paths from earlier runs, unless turned off
+    eval_cache: Option<EvalCache>,
     system: System,
     width: usize,
+    options: RunOptions,
 }
 
 impl App {
@@ -169,252 +1133,1384 @@ impl App {
         system: System,
         width: usize,
         config: Config,
+        options: RunOptions,
     ) -> Result<Self> {
         let output_dir = working_dir.join(config.artifact_dir());
-        let nix_result_dir = working_dir.join("result");
-        let Ok(nix) = which::which("nix") else {
-            bail!("Unable to find nix on the $PATH");
-        };
+        let nix = find_tool("nix", config.nix())?;
 
         let cachix = match config.cache() {
-            Some(_) => {
-                let Ok(cachix) = which::which("cachix") else {
-                    bail!("Unable to find cachix on the $PATH (config has cachix set)");
-                };
-                Some(cachix)
+            Some(CacheBackend::Cachix(_)) => Some(
+                find_tool("cachix", config.cachix())
+                    .context("Config has cachix set, but cachix can't be used")?,
+            ),
+            Some(CacheBackend::NixCopy(_)) | None => None,
+        };
+
+        let env = config.env()?;
+        let mut cachix_env = env.clone();
+        if cachix.is_some() {
+            let files = [
+                (CACHIX_AUTH_KEY, config.auth_token_file()),
+                (CACHIX_SIGNING_KEY, config.signing_key_file()),
+            ];
+            for (key, file) in files {
+                if let Some(file) = file {
+                    cachix_env.insert(key.to_owned(), read_secret(file)?);
+                }
             }
-            None => None,
+        }
+        let events = options.events.as_deref().map(Events::open).transpose()?;
+        let (flake, flake_dir) = match options.flake.as_deref() {
+            None => (s!("."), Some(working_dir.to_owned())),
+            Some(reference) => match local_flake_dir(reference) {
+                Some(dir) => {
+                    let dir = cwd.join(dir);
+                    (dir.display().to_string(), Some(dir))
+                }
+                None => (reference.to_owned(), None),
+            },
         };
 
-        Ok(Self {
+        let mut app = Self {
             cwd,
+            flake,
+            flake_dir,
             output_dir,
-            nix_result_dir,
             config,
             nix,
             cachix,
+            env,
+            cachix_env,
+            events,
+            eval_cache: None,
             system,
             width,
-        })
-    }
-
-    fn attributes(&self, ttype: &str, system: System) -> Result<Vec<String>> {
-        let args = &[
-            "eval",
-            &format!(".#{ttype}.{system}"),
-            "--apply",
-            "builtins.attrNames",
+            options,
+        };
+        if app.config.auto_systems() {
+            app.discover_systems()?;
+        }
+        if let Some(system) = app.options.only_system {
+            if !app.config.systems().contains(&system) {
+                bail!(InvalidConfig(format!(
+                    "--only-system {system} isn't one of the configured systems"
+                )));
+            }
+            app.config.set_systems(vec![system]);
+        }
+        if !app.options.targets.is_empty() {
+            app.select_targets()?;
+        }
+        if let Some(reference) = app.options.changed_since.clone() {
+            app.options.filters = app.changed_filters(&reference)?;
+        }
+        if app.flake_dir.is_none() && !app.options.no_artifacts {
+            info!("Not saving artifacts, {} isn't a local checkout", app.flake);
+            app.options.no_artifacts = true;
+        }
+        if !app.options.no_eval_cache {
+            app.eval_cache = app.open_eval_cache();
+        }
+        Ok(app)
+    }
+
+    /// Build only the systems the targets are for, and only the targets out of those
+    fn select_targets(&mut self) -> Result<()> {
+        let configured = self.config.systems();
+        if let Some(target) = self
+            .options
+            .targets
+            .iter()
+            .find(|target| !configured.contains(&target.system))
+        {
+            bail!(InvalidConfig(format!(
+                "Can't build {target}, {} isn't one of the configured systems",
+                target.system
+            )));
+        }
+        let systems = configured
+            .into_iter()
+            .filter(|system| self.options.targets.iter().any(|t| t.system == *system))
+            .collect();
+        self.config.set_systems(systems);
+        self.options.filters = self
+            .options
+            .targets
+            .iter()
+            .map(|target| OutputPath::exact(&target.output, target.system, &target.name))
+            .collect();
+        Ok(())
+    }
+
+    /// Filters for the packages changed since a git ref. Empty, so everything gets built, when
+    /// nothing changed or a change can't be pinned on a package
+    fn changed_filters(&self, reference: &str) -> Result<Vec<OutputPath>> {
+        let Some(flake_dir) = &self.flake_dir else {
+            bail!(InvalidConfig(format!(
+                "--changed-since needs a local checkout, {} isn't one",
+                self.flake
+            )));
+        };
+        let Ok(git) = which("git") else {
+            bail!(MissingTool(s!("Unable to find git on the $PATH")));
+        };
+        let flake_dir = flake_dir.to_string_lossy();
+        let args = [
+            "-C",
+            &flake_dir,
+            "diff",
+            "--name-only",
+            "--relative",
+            reference,
+        ];
+        let stdout =
+            run(&git, &args).with_context(|| format!("Failed to diff against '{reference}'"))?;
+        let files: Vec<&str> = stdout.lines().filter(|line| !line.is_empty()).collect();
+        if files.is_empty() {
+            info!("Nothing changed since {reference}, building everything");
+            return Ok(Vec::new());
+        }
+        let Some(packages) = changed_packages(&files, self.config.package_dirs()) else {
+            info!("Changes since {reference} aren't all in package dirs, building everything");
+            return Ok(Vec::new());
+        };
+        info!(
+            "Building packages changed since {reference}: {}",
+            packages.iter().cloned().collect::<Vec<_>>().join(", ")
+        );
+        let filters = packages
+            .iter()
+            .map(|package| OutputPath::from_str(&format!("packages.*.{package}")))
+            .collect::<std::result::Result<Vec<_>, _>>();
+        match filters {
+            Ok(filters) => Ok(filters),
+            Err(e) => {
+                info!("Can't filter on a changed package, building everything: {e}");
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// The eval cache for the flake at its locked revision. None if the flake has uncommitted
+    /// changes, since nothing says whether they changed what evaluates
+    fn open_eval_cache(&self) -> Option<EvalCache> {
+        let metadata: serde_json::Value = match self
+            .nix(&["flake", "metadata", &self.flake, "--json"])
+            .and_then(|stdout| Ok(serde_json::from_str(&stdout)?))
+        {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                debug!("Not caching evals, failed to read flake metadata: {e:#}");
+                return None;
+            }
+        };
+        let Some(revision) = metadata["revision"].as_str() else {
+            debug!("Not caching evals, the flake has uncommitted changes");
+            return None;
+        };
+        // Nix args like --override-input change what evaluates too
+        let nix_args: Vec<&str> = self.extra_nix_args().collect();
+        let key = format!("{revision} {}", nix_args.join(" "));
+        let flake_dir = self.flake_dir.as_deref();
+        EvalCache::for_flake(flake_dir.unwrap_or(Path::new(&self.flake)), key)
+    }
+
+    /// Swap the configured systems for the ones the flake has outputs for that this machine can
+    /// build
+    fn discover_systems(&mut self) -> Result<()> {
+        let show = self
+            .nix(&["flake", "show", &self.flake, "--json"])
+            .and_then(|stdout| Ok(serde_json::from_str(&stdout)?))
+            .context("Failed to read the flake's systems for auto-systems")?;
+        let systems: Vec<System> = shown_systems(&show, self.config.build_outputs())
+            .into_iter()
+            .filter(|system| {
+                let build = self.builder(*system).is_some();
+                if !build {
+                    debug!("Not building discovered system {system}, this machine can't");
+                }
+                build
+            })
+            .collect();
+        if systems.is_empty() {
+            warn!("The flake has no outputs for any system this machine can build");
+        }
+        info!(
+            "Discovered systems: {}",
+            systems
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        self.config.set_systems(systems);
+        Ok(())
+    }
+
+    /// Flags from the config and input overrides to add to every nix call
+    fn extra_nix_args(&self) -> impl Iterator<Item = &str> {
+        let overrides = self
+            .options
+            .override_inputs
+            .iter()
+            .flat_map(|(name, reference)| ["--override-input", name, reference]);
+        self.config
+            .nix_args()
+            .iter()
+            .map(String::as_str)
+            .chain(overrides)
+            .chain(self.options.offline.then_some("--offline"))
+    }
+
+    /// Whether built paths get pushed. Never offline, the cache can't be reached
+    fn publishing(&self) -> bool {
+        self.config.publish() && !self.options.offline
+    }
+
+    /// An attribute of the flake being built, as something nix can evaluate or build
+    fn installable(&self, attribute: &str) -> String {
+        format!("{}#{attribute}", self.flake)
+    }
+
+    /// Where the repo is at, unknown for flakes that aren't checked out here
+    fn git_revision(&self) -> GitRevision {
+        match &self.flake_dir {
+            Some(flake_dir) => git_revision(flake_dir),
+            None => GitRevision::unknown(),
+        }
+    }
+
+    /// Run nix with the extra nix args appended
+    fn nix(&self, args: &[&str]) -> Result<String> {
+        Ok(self.try_nix(args)?.check(&self.nix)?.stdout)
+    }
+
+    /// Like `nix`, for commands whose failure says something instead of being an error
+    fn try_nix(&self, args: &[&str]) -> Result<Ran> {
+        let mut args = args.to_vec();
+        args.extend(self.extra_nix_args());
+        if self.options.dry_run {
+            // Evaluating only reads, so dry runs still do it
+            info!("[INSPECT] nix {}", args.join(" "));
+        }
+        try_run(&self.nix, &args, &HashMap::new())
+    }
+
+    /// Make sure the nix store (usually the daemon) answers, so a dead daemon fails here instead
+    /// of as an eval error halfway through
+    fn check_store(&self) -> Result<()> {
+        run(&self.nix, &store_check_args(self.options.offline)).context(
+            "nix store unreachable. Is the nix daemon running? Pass --skip-store-check to skip \
+             this check",
+        )?;
+        Ok(())
+    }
+
+    /// Locked revision of each input, from `nix flake metadata`. Empty if that fails
+    fn input_revisions(&self, summary: &mut Summary) -> BTreeMap<String, String> {
+        match self
+            .nix(&["flake", "metadata", &self.flake, "--json"])
+            .and_then(|stdout| Ok(serde_json::from_str(&stdout)?))
+        {
+            Ok(metadata) => locked_revisions(&metadata),
+            Err(e) => {
+                let warning = format!("Failed to read flake metadata: {e:#}");
+                warn!("{warning}");
+                summary.warn(warning);
+                BTreeMap::new()
+            }
+        }
+    }
+
+    /// Evaluate the whole flake's output tree at once with `nix flake show`. None if that fails,
+    /// in which case outputs are evaluated one at a time instead
+    fn flake_show(&self) -> Option<serde_json::Value> {
+        let args = &["flake", "show", &self.flake, "--json"];
+        match self
+            .nix(args)
+            .and_then(|stdout| Ok(serde_json::from_str(&stdout)?))
+        {
+            Ok(show) => Some(show),
+            Err(e) => {
+                debug!("nix flake show failed, falling back to evaluating each output: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Names of everything in an output, from the eval cache if it has them. `show` is only
+    /// evaluated if they need evaluating
+    fn attributes(
+        &self,
+        show: &OnceCell<Option<serde_json::Value>>,
+        ttype: &str,
+        system: System,
+    ) -> Result<Vec<String>> {
+        let system_name = system.to_string();
+        if let Some(cache) = &self.eval_cache {
+            if let Some(attributes) = cache.attributes(ttype, &system_name) {
+                return Ok(attributes);
+            }
+        }
+        let show = show.get_or_init(|| self.flake_show());
+        let attributes = self.evaluate_attributes(show.as_ref(), ttype, system)?;
+        if let Some(cache) = &self.eval_cache {
+            cache.insert_attributes(ttype, &system_name, attributes.clone());
+        }
+        Ok(attributes)
+    }
+
+    /// Names of everything in an output. Read from `show` when it has the output, otherwise
+    /// evaluated directly
+    fn evaluate_attributes(
+        &self,
+        show: Option<&serde_json::Value>,
+        ttype: &str,
+        system: System,
+    ) -> Result<Vec<String>> {
+        if let Some(attributes) = show.and_then(|show| shown_attributes(show, ttype, system)) {
+            return Ok(attributes);
+        }
+
+        let installable = if is_per_system(ttype) {
+            self.installable(&format!("{ttype}.{system}"))
+        } else {
+            self.installable(ttype)
+        };
+        if is_singular(ttype) {
+            // Fails if there's nothing there for the system
+            self.nix(&[
+                "eval",
+                &installable,
+                "--apply",
+                "builtins.isAttrs",
+                "--json",
+            ])?;
+            return Ok(vec![String::new()]);
+        }
+        let args = &[
+            "eval",
+            &installable,
+            "--apply",
+            "builtins.attrNames",
             "--json",
         ];
-        let stdout = run(&self.nix, args)?;
+        let stdout = self.nix(args)?;
         let attributes: Vec<String> = serde_json::from_str(&stdout)?;
         Ok(attributes)
     }
 
+    /// The system a configuration output is built for
+    fn configuration_system(&self, derivation: &Derivation) -> Result<String> {
+        let args = &[
+            "eval",
+            &self.installable(&derivation.attribute()),
+            "--apply",
+            "cfg: cfg.pkgs.stdenv.hostPlatform.system",
+            "--raw",
+        ];
+        self.nix(args)
+    }
+
     fn derivation_path(&self, derivation: &Derivation) -> Result<String> {
+        let name = derivation.to_string();
+        if let Some(path) = self
+            .eval_cache
+            .as_ref()
+            .and_then(|cache| cache.drv_path(&name))
+        {
+            return Ok(path);
+        }
         let args = &[
             "eval",
-            &derivation.to_string(),
+            &self.installable(&derivation.attribute()),
             "--apply",
-            "pkg: pkg.drvPath",
+            drv_path_expr(&derivation.output),
             "--raw",
         ];
-        let path = run(&self.nix, args)?;
+        let path = self.nix(args)?;
+        if let Some(cache) = &self.eval_cache {
+            cache.insert_drv_path(name, path.clone());
+        }
         Ok(path)
     }
 
-    fn build(&self, path: &str, dry_run: bool) -> Result<Status> {
-        let nix_args = &[
-            "build",
-            &format!("{path}^*"),
-            "--log-lines",
-            "0",
-            "--print-build-logs",
-            "--print-out-paths",
-        ];
+    /// The out paths of a drv path if every one of them is already in the store
+    fn cached_out_paths(&self, path: &str) -> Result<Option<Vec<PathBuf>>> {
+        let stdout = self.nix(&["derivation", "show", path])?;
+        let Some(out_paths) = derivation_out_paths(&stdout)? else {
+            return Ok(None);
+        };
+
+        let mut args = vec!["path-info"];
+        args.extend(out_paths.iter().map(String::as_str));
+        // path-info fails if any path isn't valid
+        if !self.try_nix(&args)?.success() {
+            return Ok(None);
+        }
+        Ok(Some(out_paths.into_iter().map(PathBuf::from).collect()))
+    }
+
+    /// How a system gets built, or None if it can't be. Systems other than this machine's need
+    /// remote builders or cross building turned on
+    fn builder(&self, system: System) -> Option<Builder> {
+        if system == self.system {
+            Some(Builder::Native)
+        } else if self.config.remote_builders() {
+            Some(Builder::Remote)
+        } else if self.config.cross() {
+            Some(Builder::Emulated)
+        } else {
+            None
+        }
+    }
+
+    /// Configured systems that get built
+    fn systems_to_build(&self) -> Vec<System> {
+        let mut systems = self.config.systems();
+        systems.retain(|system| {
+            let build = self.builder(*system).is_some();
+            if !build {
+                warn!("Skipping system {system}, turn on cross or remote-builders to build it");
+            }
+            build
+        });
+        systems
+    }
+
+    /// Extra `nix build` flags for building for another system. Remote builds hand the
+    /// derivation to the builders in nix.conf. Emulated builds need the system registered with
+    /// binfmt on this machine, and a trusted user to set extra-platforms
+    fn foreign_system_args(&self, system: System) -> Vec<String> {
+        match self.builder(system) {
+            Some(Builder::Remote) => vec![s!("--system"), system.to_string()],
+            Some(Builder::Emulated) => {
+                vec![s!("--option"), s!("extra-platforms"), system.to_string()]
+            }
+            Some(Builder::Native) | None => Vec::new(),
+        }
+    }
 
-        let env = Some(self.config.env());
+    /// Build a derivation's drv path. Stdout has the out paths nix printed, or the cached out
+    /// paths if there was nothing to build
+    fn build(&self, derivation: &Derivation, path: &str) -> Result<Captured> {
+        if !self.options.rebuild {
+            if let Some(out_paths) = self.cached_out_paths(path)? {
+                let stdout: Vec<String> = out_paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                return Ok(Captured {
+                    status: Status::Cached,
+                    stdout: stdout.join("\n"),
+                    timed_out: false,
+                });
+            }
+        }
 
-        let status = if self.config.publish() {
-            // Run nix build under cachix. Cachix will push all built paths
-            let nix = self.nix.display().to_string();
-            let mut args = vec!["watch-exec", &self.config.cache().unwrap(), "--", &nix];
-            args.extend_from_slice(nix_args);
-            run_stream(&self.cachix.clone().unwrap(), &args, env, dry_run)?
+        let (exec, args) = self.build_command(derivation.system, path);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let env = if exec == self.nix {
+            &self.env
         } else {
-            run_stream(&self.nix, nix_args, env, dry_run)?
+            &self.cachix_env
         };
-        Ok(status)
+        let timeout = self.config.timeout();
+        let name = derivation.attribute();
+        let prefix = (!self.options.no_prefix).then_some(name.as_str());
+        let log_file = self.log_file(derivation);
+        if let Some(dir) = log_file.as_deref().and_then(Path::parent) {
+            fs::create_dir_all(dir)?;
+        }
+        run_stream_stdout(
+            &exec,
+            &args,
+            Some(env),
+            self.options.dry_run,
+            timeout,
+            prefix,
+            log_file.as_deref(),
+        )
     }
 
-    pub fn build_all(&self, dry_run: bool, summary: &mut Summary) -> Result<bool> {
-        let mut all_succeeded = true;
+    /// Where a derivation's build log is saved, in the artifact dir
+    fn log_file(&self, derivation: &Derivation) -> Option<PathBuf> {
+        if self.options.no_artifacts || self.options.dry_run {
+            return None;
+        }
+        let file_name = format!("{}.log", derivation.attribute());
+        Some(self.output_dir.join("logs").join(file_name))
+    }
 
-        for system in &self.config.systems() {
-            if system != &self.system {
-                // TODO: cross compiling?? Will probably also need to fix the graph stuff
-                warn!("Skipping system {}", system);
-                continue;
+    /// The program and arguments that build a drv path for a system
+    fn build_command(&self, system: System, path: &str) -> (PathBuf, Vec<String>) {
+        let mut nix_args = vec![
+            s!("build"),
+            format!("{path}^*"),
+            s!("--log-lines"),
+            s!("0"),
+            s!("--print-build-logs"),
+            s!("--print-out-paths"),
+            s!("--no-link"),
+        ];
+        nix_args.extend(self.foreign_system_args(system));
+        if let Some(max_jobs) = self.config.max_jobs() {
+            nix_args.extend([s!("--max-jobs"), max_jobs.to_string()]);
+        }
+        if let Some(cores) = self.config.cores() {
+            nix_args.extend([s!("--cores"), cores.to_string()]);
+        }
+        nix_args.extend(self.extra_nix_args().map(str::to_owned));
+
+        let (true, Some(CacheBackend::Cachix(caches)), PushMode::WatchExec, Some(cachix)) = (
+            self.publishing(),
+            self.config.cache(),
+            self.config.push_mode(),
+            &self.cachix,
+        ) else {
+            return (self.nix.clone(), nix_args);
+        };
+
+        // Run nix build under cachix. Cachix will push all built paths. With more than one
+        // cache, each watch-exec runs the next
+        let mut args = Vec::new();
+        for (i, cache) in caches.iter().enumerate() {
+            if i > 0 {
+                args.push(cachix.display().to_string());
+            }
+            args.extend([s!("watch-exec"), cache.clone(), s!("--")]);
+        }
+        args.push(self.nix.display().to_string());
+        args.extend(nix_args);
+        (cachix.clone(), args)
+    }
+
+    /// The command that builds a node, ready to paste into a shell
+    fn build_command_line(&self, system: System, path: &str) -> String {
+        let (exec, args) = self.build_command(system, path);
+        std::iter::once(exec.display().to_string())
+            .chain(args)
+            .map(|arg| shell_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Check the flake's structure with `nix flake check`, without building its checks since
+    /// those get built anyway
+    fn flake_check(&self) -> Result<Status> {
+        info!("Running nix flake check");
+        let mut args = vec!["flake", "check", &self.flake, "--no-build"];
+        args.extend(self.extra_nix_args());
+        run_stream(&self.nix, &args, Some(&self.env), self.options.dry_run)
+    }
+
+    /// Push out paths with `nix copy`, or `cachix push` in explicit push mode. Otherwise cachix
+    /// pushes as it builds instead. Returns the caches the paths are in, or None if a push failed
+    fn push(&self, out_paths: &[PathBuf]) -> Result<Option<Vec<String>>> {
+        if !self.publishing() {
+            return Ok(Some(Vec::new()));
+        }
+        let out_paths: Vec<String> = out_paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        match (self.config.cache(), &self.cachix) {
+            (Some(CacheBackend::NixCopy(to)), _) => {
+                info!("Pushing to {to}");
+                let mut args = vec!["copy", "--to", to];
+                args.extend(out_paths.iter().map(String::as_str));
+                args.extend(self.extra_nix_args());
+                let status = run_stream(&self.nix, &args, Some(&self.env), self.options.dry_run)?;
+                Ok((!matches!(status, Status::Fail)).then(|| vec![to.to_owned()]))
+            }
+            (Some(CacheBackend::Cachix(caches)), Some(cachix))
+                if self.config.push_mode() == PushMode::Explicit =>
+            {
+                for cache in caches {
+                    info!("Pushing to cachix cache {cache}");
+                    let mut args = vec!["push", cache];
+                    args.extend(out_paths.iter().map(String::as_str));
+                    let env = Some(&self.cachix_env);
+                    let status = run_stream(cachix, &args, env, self.options.dry_run)?;
+                    if matches!(status, Status::Fail) {
+                        return Ok(None);
+                    }
+                }
+                Ok(Some(caches.to_vec()))
             }
+            // Pushed by watch-exec while building
+            (Some(CacheBackend::Cachix(caches)), _) => Ok(Some(caches.to_vec())),
+            (None, _) => Ok(Some(Vec::new())),
+        }
+    }
+
+    /// Find the drv path to build. Configurations for other systems are skipped
+    fn resolve(
+        &self,
+        derivation: &Derivation,
+        summary: &Mutex<&mut Summary>,
+    ) -> Result<Option<String>> {
+        if !is_per_system(&derivation.output) {
+            let target = self.configuration_system(derivation)?;
+            let system = derivation.system;
+            if target != system.to_string() {
+                debug!("{derivation} is for {target}, not {system}");
+                // Only worth noting if it won't get built with some other system
+                let built_elsewhere = self
+                    .systems_to_build()
+                    .iter()
+                    .any(|other| other.to_string() == target);
+                if !built_elsewhere {
+                    summary.lock().unwrap().register_skip(
+                        &derivation.output,
+                        derivation.to_string(),
+                        format!("for {target}"),
+                    );
+                }
+                return Ok(None);
+            }
+        }
 
-            // TODO: build graph so that packages aren't built unless checks pass
+        self.derivation_path(derivation).map(Some)
+    }
 
-            let mut sets = HashMap::new();
-            let mut graph: Graph<(Derivation, String)> = Graph::new();
-            for output in self.config.build_outputs() {
-                sets.insert(output.to_owned(), HashSet::new());
+    /// Enumerate everything to build for a system. When keeping going, derivations that failed
+    /// to evaluate are returned alongside the graph with their error
+    fn build_graph(
+        &self,
+        system: System,
+        summary: &mut Summary,
+    ) -> Result<(Graph<Node>, HashMap<Derivation, String>)> {
+        let mut broken = HashMap::new();
+        let mut sets = HashMap::new();
+        let mut derivations = Vec::new();
+        let mut graph: Graph<Node> = Graph::new();
+        let spinner = self.spinner(format!("Evaluating {system}"));
+        let show = OnceCell::new();
+        let targeted = !self.options.targets.is_empty();
+        for output in self.config.build_outputs() {
+            // Targets are built as named, so the only output left to find is checks, which may
+            // gate them
+            if targeted && output != "checks" {
+                continue;
+            }
+            sets.insert(output.to_owned(), HashSet::new());
 
-                let Ok(attributes) = self.attributes(output, *system) else {
+            let attributes = self.attributes(&show, output, system);
+            let mut attributes = match attributes {
+                Ok(attributes) if attributes.is_empty() && self.config.required(output) => {
+                    summary.register_missing_output(output, format!("empty for {system}"));
+                    continue;
+                }
+                Ok(attributes) => attributes,
+                Err(_) if self.config.required(output) => {
+                    error!("Required output .#{output} not found");
+                    summary.register_missing_output(output, format!("not found for {system}"));
+                    continue;
+                }
+                Err(_) => {
                     warn!("No such entry: .#{output}");
                     summary.skip_output(output);
                     continue;
-                };
+                }
+            };
+            if output == LEGACY_PACKAGES {
+                let mut patterns = self.config.legacy_packages().to_vec();
+                patterns.extend(self.options.filters.iter().cloned());
+                attributes = select_legacy_packages(attributes, &patterns, system)?;
+            }
+
+            for attribute in &attributes {
+                debug!("Attr: {attribute}");
+                derivations.push(Derivation::new(
+                    output.to_owned(),
+                    system,
+                    attribute.to_owned(),
+                ));
+            }
+        }
+
+        for target in &self.options.targets {
+            if target.system == system && !derivations.contains(target) {
+                derivations.push(target.clone());
+            }
+        }
 
-                for attribute in &attributes {
-                    debug!("Attr: {attribute}");
+        // Each drv path is its own nix eval, so they're worth running side by side. Results come
+        // back in order, so the graph doesn't depend on which finished first
+        let shared_summary = Mutex::new(summary);
+        let paths = map_in_parallel(&derivations, self.config.jobs(), |derivation| {
+            spinner.set_message(format!("Evaluating {derivation}"));
+            self.resolve(derivation, &shared_summary)
+        });
+        for (derivation, path) in derivations.into_iter().zip(paths) {
+            let path = match path {
+                Ok(Some(path)) => path,
+                Ok(None) => continue,
+                Err(e) if self.options.keep_going => {
+                    // Keep the node so anything it gates gets blocked
+                    broken.insert(derivation.clone(), format!("{e:#}"));
+                    String::new()
+                }
+                Err(e) => return Err(e),
+            };
+            debug!("Path: {path}");
+
+            let similar_set = sets.entry(derivation.output.clone()).or_default();
+            similar_set.insert((derivation.clone(), path.clone()));
+
+            graph.add_node((derivation, path));
+        }
 
-                    let derivation =
-                        Derivation::new(output.to_owned(), *system, attribute.to_owned());
-                    let path = self.derivation_path(&derivation)?;
-                    debug!("Path: {path}");
+        // If there are checks, mark the things they check as dependencies of the check
+        if let Some(checks) = sets.remove(&String::from("checks")) {
+            let summary = shared_summary.into_inner().unwrap();
+            self.mark_checks(checks, &sets, &mut graph, summary)?;
+        }
 
-                    let similar_set = sets.get_mut(output).unwrap();
-                    similar_set.insert((derivation.clone(), path.clone()));
+        spinner.finish_and_clear();
+        if let Some(cache) = &self.eval_cache {
+            cache.save();
+        }
+        Ok((graph, broken))
+    }
 
-                    graph.add_node((derivation, path));
+    /// Make everything each check gates depend on the check, going by `[[checks]]` gates or
+    /// else the check's name. `sets` has the derivations of every other output, by output
+    fn mark_checks(
+        &self,
+        checks: HashSet<Node>,
+        sets: &HashMap<String, HashSet<Node>>,
+        graph: &mut Graph<Node>,
+        summary: &mut Summary,
+    ) -> Result<()> {
+        for (check, check_path) in checks {
+            if let Some(gates) = self.config.check_gates(&check.name) {
+                let gated = sets.values().flatten().filter(|(derivation, _)| {
+                    gates.iter().any(|gate| {
+                        gate.matches(&derivation.output, derivation.system, &derivation.name)
+                    })
+                });
+                for (derivation, path) in gated {
+                    graph.mark_dep(
+                        &(check.clone(), check_path.clone()),
+                        &(derivation.to_owned(), path.to_owned()),
+                    )?;
                 }
+                continue;
             }
 
-            // If there are checks, mark the things they check as dependencies of the check
-            if let Some(checks) = sets.remove(&String::from("checks")) {
-                for (check, check_path) in checks {
-                    // TODO: config should have a way to mark what output(s?) a check checks
-                    let Ok(type_of_check) = get_type_of_check(&check) else {
-                        warn!("Check '{check}' is not a pre-rec for building any packages");
-                        continue;
-                    };
+            // Otherwise guess from the name
+            let naming = self.config.check_naming();
+            let type_of_check = match get_type_of_check(&check, naming) {
+                Ok(type_of_check) => type_of_check,
+                Err(e) => {
+                    let warning = format!("{e:#}");
+                    warn!("{warning}");
+                    summary.warn(warning);
+                    continue;
+                }
+            };
 
-                    if let Some(derivations) = sets.get(type_of_check) {
-                        for (derivation, path) in derivations {
-                            if check_checks_derivation(&check, derivation) {
-                                graph.mark_dep(
-                                    &(check.clone(), check_path.clone()),
-                                    &(derivation.to_owned(), path.to_owned()),
-                                )?;
-                            }
-                        }
+            if let Some(derivations) = sets.get(type_of_check) {
+                for (derivation, path) in derivations {
+                    if check_checks_derivation(&check, derivation, naming) {
+                        graph.mark_dep(
+                            &(check.clone(), check_path.clone()),
+                            &(derivation.to_owned(), path.to_owned()),
+                        )?;
                     }
                 }
-            };
+            }
+        }
+        Ok(())
+    }
 
-            let walker = graph.walker();
-            let chains = walker.chains();
+    /// Dry run a freshly built home-manager config's activation script, if configured to, which
+    /// catches errors that building it doesn't
+    fn check_activation(
+        &self,
+        derivation: &Derivation,
+        out_paths: &[PathBuf],
+        summary: &Mutex<&mut Summary>,
+    ) {
+        if derivation.output != "homeConfigurations" || !self.config.home_activation_check() {
+            return;
+        }
+        let Some(out_path) = out_paths.first() else {
+            return;
+        };
+        info!("Dry running the activation of {derivation}");
+        // home-manager's own `switch --dry-run` sets this
+        let env = HashMap::from([(s!("DRY_RUN"), s!("1"))]);
+        let error = run_with_env(&out_path.join("activate"), &[], &env)
+            .err()
+            .map(|e| format!("{e:#}"));
+        if let Some(error) = &error {
+            error!("Activating {derivation} failed: {error}");
+        }
+        summary.lock().unwrap().record_activation(
+            &derivation.output,
+            &derivation.to_string(),
+            error,
+        );
+    }
 
-            for chain in &chains {
-                debug!("chain: {chain:?}");
+    /// Run a freshly built app with the args a `[[smoke]]` entry gives it, which catches it
+    /// failing at runtime. Only apps for this machine's system can run here
+    fn smoke_test(&self, derivation: &Derivation, summary: &Mutex<&mut Summary>) {
+        let Some(smoke_args) =
+            self.config
+                .smoke_args(&derivation.output, derivation.system, &derivation.name)
+        else {
+            return;
+        };
+        if derivation.system != self.system {
+            info!(
+                "Not smoke testing {derivation}, it can't run on {}",
+                self.system
+            );
+            return;
+        }
+        info!("Smoke testing {derivation}");
+        let installable = self.installable(&derivation.attribute());
+        let mut args = vec!["run", installable.as_str()];
+        args.extend(self.extra_nix_args());
+        args.push("--");
+        args.extend(smoke_args.iter().map(String::as_str));
+        let error = run(&self.nix, &args).err().map(|e| format!("{e:#}"));
+        if let Some(error) = &error {
+            error!("Smoke testing {derivation} failed: {error}");
+        }
+        summary
+            .lock()
+            .unwrap()
+            .record_smoke(&derivation.output, &derivation.to_string(), error);
+    }
+
+    /// Rebuild a freshly built derivation, if asked to, and record whether nix got the same
+    /// outputs again
+    fn check_reproducibility(
+        &self,
+        derivation: &Derivation,
+        path: &str,
+        summary: &Mutex<&mut Summary>,
+    ) {
+        if !self.options.check_reproducibility {
+            return;
+        }
+        info!("Rebuilding {derivation} to check it's reproducible");
+        let mut args = vec![
+            s!("build"),
+            format!("{path}^*"),
+            s!("--rebuild"),
+            s!("--log-lines"),
+            s!("0"),
+            s!("--no-link"),
+        ];
+        args.extend(self.foreign_system_args(derivation.system));
+        args.extend(self.extra_nix_args().map(str::to_owned));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let error = match run(&self.nix, &args) {
+            Ok(_) => None,
+            Err(e) => {
+                let error = format!("{e:#}");
+                let Some(difference) = nondeterminism(&error) else {
+                    // The rebuild itself failed, which says nothing either way
+                    let warning =
+                        format!("Couldn't check that {derivation} is reproducible: {error}");
+                    warn!("{warning}");
+                    summary.lock().unwrap().warn(warning);
+                    return;
+                };
+                error!("{derivation} isn't reproducible: {difference}");
+                Some(difference.to_owned())
             }
+        };
+        summary.lock().unwrap().record_reproducibility(
+            &derivation.output,
+            &derivation.to_string(),
+            error,
+        );
+    }
 
-            let mut have_ran = HashSet::new();
+    /// Look up how big a freshly built derivation's closure is, if asked to
+    fn record_closure_size(
+        &self,
+        derivation: &Derivation,
+        out_paths: &[PathBuf],
+        summary: &Mutex<&mut Summary>,
+    ) {
+        if !self.options.sizes || out_paths.is_empty() {
+            return;
+        }
+        let mut args = vec!["path-info", "--json", "--closure-size"];
+        args.extend(out_paths.iter().filter_map(|path| path.to_str()));
+        match self.nix(&args).and_then(|stdout| closure_size(&stdout)) {
+            Ok(bytes) => summary.lock().unwrap().record_closure_size(
+                &derivation.output,
+                &derivation.to_string(),
+                bytes,
+            ),
+            Err(e) => {
+                let warning = format!("Couldn't get the closure size of {derivation}: {e:#}");
+                warn!("{warning}");
+                summary.lock().unwrap().warn(warning);
+            }
+        }
+    }
+
+    /// Start the artifact dir over empty
+    fn reset_output_dir(&self) -> Result<()> {
+        if self.output_dir.is_dir() {
+            log::warn!("Removing old artifact dir");
+            if self.options.dry_run {
+                info!("[DRYRUN] Would remove old artifact dir");
+            } else {
+                fs::remove_dir_all(&self.output_dir)?;
+            }
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        Ok(())
+    }
 
-            for chain in chains {
-                let num_items = chain.len();
-                for i in 0..num_items {
-                    let (derivation, path) = &chain[i];
+    fn save_artifacts(
+        &self,
+        derivation: &Derivation,
+        out_paths: &[PathBuf],
+    ) -> Result<Vec<PathBuf>> {
+        if self.options.no_artifacts
+            || !self
+                .config
+                .save_artifact(&derivation.output, derivation.system, &derivation.name)
+        {
+            return Ok(Vec::new());
+        }
 
-                    if have_ran.contains(derivation) {
-                        continue;
+        debug!("Saving artifacts from {derivation}");
+        save_artifacts(
+            &self.output_dir,
+            self.config.artifact_mode(),
+            &derivation.to_string(),
+            out_paths,
+        )
+    }
+
+    fn build_one(
+        &self,
+        derivation: &Derivation,
+        path: &str,
+        summary: &Mutex<&mut Summary>,
+        built: &Mutex<Vec<Built>>,
+    ) -> Result<Status> {
+        info!("Building {derivation}");
+        let (captured, attempts) = self.build_with_retries(derivation, path)?;
+        info!("Done building {derivation}");
+
+        let output = &derivation.output;
+        let out_paths = out_paths(&captured.stdout);
+        let status = match captured.status {
+            Status::Skipped => {
+                let reason = if self.options.explain {
+                    match self.build_plan(path) {
+                        Ok(plan) => plan.to_string(),
+                        Err(e) => format!("dry run, couldn't work out what it needs: {e:#}"),
                     }
+                } else {
+                    s!("dry run")
+                };
+                summary
+                    .lock()
+                    .unwrap()
+                    .register_skip(output, derivation.to_string(), reason);
+                Status::Skipped
+            }
+            Status::Fail => {
+                let log_command = format!("`nix log {path}`");
+                let mut summary = summary.lock().unwrap();
+                match self.config.timeout() {
+                    Some(timeout) if captured.timed_out => summary.register_timeout(
+                        output,
+                        derivation.to_string(),
+                        log_command,
+                        timeout,
+                    ),
+                    // No log if it never got built, so say what probably went wrong
+                    _ if self.options.offline => summary.register_fail_with_error(
+                        output,
+                        derivation.to_string(),
+                        log_command,
+                        s!("failed offline, something it needs may not be in the local store"),
+                    ),
+                    _ => summary.register_fail(output, derivation.to_string(), log_command),
+                }
+                if let Some(log_file) = self.log_file(derivation) {
+                    summary.record_log_file(output, &derivation.to_string(), log_file);
+                }
+                Status::Fail
+            }
+            Status::Cached => {
+                info!("{derivation} was already in the store");
+                let artifacts = self.save_artifacts(derivation, &out_paths)?;
+                summary
+                    .lock()
+                    .unwrap()
+                    .register_cached(output, derivation.to_string(), artifacts);
+                self.record_closure_size(derivation, &out_paths, summary);
+                self.check_activation(derivation, &out_paths, summary);
+                self.smoke_test(derivation, summary);
+                self.check_reproducibility(derivation, path, summary);
+                built.lock().unwrap().push((derivation.clone(), out_paths));
+                Status::Cached
+            }
+            Status::Success => {
+                let Some(caches) = self.push(&out_paths)? else {
+                    summary.lock().unwrap().register_error(
+                        output,
+                        derivation.to_string(),
+                        s!("failed to push to the cache"),
+                    );
+                    return Ok(Status::Fail);
+                };
+                let artifacts = self.save_artifacts(derivation, &out_paths)?;
+                {
+                    let mut summary = summary.lock().unwrap();
+                    summary.register_success(output, derivation.to_string(), artifacts);
+                    summary.record_pushes(output, &derivation.to_string(), caches);
+                }
+                self.record_closure_size(derivation, &out_paths, summary);
+                self.check_activation(derivation, &out_paths, summary);
+                self.smoke_test(derivation, summary);
+                self.check_reproducibility(derivation, path, summary);
+                built.lock().unwrap().push((derivation.clone(), out_paths));
+                Status::Success
+            }
+        };
+        if attempts > 1 {
+            summary
+                .lock()
+                .unwrap()
+                .record_attempts(output, &derivation.to_string(), attempts);
+        }
+        Ok(status)
+    }
+
+    /// What building a drv path would build and fetch
+    fn build_plan(&self, path: &str) -> Result<BuildPlan> {
+        let mut args = vec![s!("build"), s!("--dry-run"), format!("{path}^*")];
+        args.extend(self.extra_nix_args().map(str::to_owned));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        Ok(parse_build_plan(&run_stderr(&self.nix, &args)?))
+    }
 
-                    info!("Building {derivation}");
-                    let status = self.build(&path, dry_run)?;
-                    info!("Done building {derivation}");
+    /// Build, rebuilding failures up to the configured number of retries. Returns the last
+    /// attempt and how many there were
+    fn build_with_retries(&self, derivation: &Derivation, path: &str) -> Result<(Captured, u32)> {
+        let mut attempts = 1;
+        loop {
+            let captured = self.build(derivation, path)?;
+            if interrupted() {
+                // Whatever nix reported, it didn't get to finish
+                bail!(Interrupted);
+            }
+            if !matches!(captured.status, Status::Fail) || attempts > self.config.retries() {
+                return Ok((captured, attempts));
+            }
 
-                    let output = &derivation.output;
-                    let attribute = &derivation.name;
+            let delay = self.config.retry_delay(attempts);
+            warn!(
+                "{derivation} failed, retrying in {}s ({attempts} of {} retries)",
+                delay.as_secs(),
+                self.config.retries()
+            );
+            sleep_unless_interrupted(delay);
+            attempts += 1;
+        }
+    }
 
-                    match status {
-                        Status::Skipped => {
-                            summary.register_skip(output, derivation.to_string());
-                        }
-                        Status::Fail => {
-                            all_succeeded = false;
-                            let log_command = format!("`nix log {path}`");
-                            summary.register_fail(output, derivation.to_string(), log_command);
-
-                            let pre_rec = derivation;
-
-                            // Mark the rest of the chain as blocked because requirement failed
-                            for j in i..num_items {
-                                let (derivation, _) = &chain[j];
-                                if have_ran.contains(derivation) {
-                                    continue;
-                                }
-                                let output = &derivation.output;
-                                summary.register_blocked(
-                                    output,
-                                    derivation.to_string(),
-                                    pre_rec.to_string(),
-                                );
-                                have_ran.insert(derivation.clone());
-                            }
-                            break;
+    /// A spinner for slow steps, hidden unless progress is on
+    fn spinner(&self, message: String) -> ProgressBar {
+        if !self.options.progress {
+            return ProgressBar::hidden();
+        }
+        let spinner = ProgressBar::new_spinner().with_message(message);
+        spinner.enable_steady_tick(SPINNER_TICK);
+        spinner
+    }
+
+    /// A bar counting derivations as they finish, hidden unless progress is on
+    fn progress_bar(&self, len: usize) -> ProgressBar {
+        if !self.options.progress {
+            return ProgressBar::hidden();
+        }
+        let style = ProgressStyle::with_template("[{bar:30}] {pos} of {len} built {wide_msg}")
+            .expect("Progress template is valid")
+            .progress_chars("=> ");
+        ProgressBar::new(len as u64).with_style(style)
+    }
+
+    fn emit(&self, event: &Event) {
+        if let Some(events) = &self.events {
+            events.emit(event);
+        }
+    }
+
+    fn allow_failure(&self, derivation: &Derivation) -> bool {
+        self.config
+            .allow_failure(&derivation.output, derivation.system, &derivation.name)
+    }
+
+    /// Report a derivation blocked by `causes`, the pre-recs from the one it depends on to the
+    /// one that failed
+    fn record_blocked(&self, summary: &Mutex<&mut Summary>, blocked: &Derivation, causes: &[Node]) {
+        let (pre_rec, _) = &causes[0];
+        let (root_cause, _) = &causes[causes.len() - 1];
+        self.emit(&Event::Blocked {
+            derivation: &blocked.to_string(),
+            pre_rec: &pre_rec.to_string(),
+        });
+        summary.lock().unwrap().register_blocked(
+            &blocked.output,
+            blocked.to_string(),
+            pre_rec.to_string(),
+            root_cause.to_string(),
+        );
+    }
+
+    /// Build every system. On a dry run, its plan is added to `plan` instead
+    fn build_all(
+        &self,
+        summary: &mut Summary,
+        built: &mut Vec<Built>,
+        plan: &mut String,
+    ) -> Result<bool> {
+        let mut all_succeeded = true;
+
+        let systems = self.systems_to_build();
+        record_skipped_systems(&self.config.systems(), &systems, summary);
+        if systems.is_empty() && self.options.strict {
+            error!("None of the configured systems can build here");
+            summary.fail_no_systems();
+            return Ok(false);
+        }
+
+        for system in &systems {
+            summary.set_system(system.to_string());
+            if let Some(builder) = self.builder(*system) {
+                summary.register_system(system.to_string(), &builder.to_string());
+            }
+            let (graph, broken) = self.build_graph(*system, summary)?;
+            self.warn_dangling_gates(&graph, summary);
+
+            let dependencies = graph.dependencies();
+            let levels = graph.topo_levels()?;
+            let walker = graph.walker();
+            let chains = filter_chains(walker.chains(), &dependencies, &self.options.filters);
+
+            for chain in &chains {
+                debug!("chain: {chain:?}");
+            }
+            let total: HashSet<&Node> = chains.iter().flatten().collect();
+            let levels = keep_in_levels(levels, &total);
+
+            if self.options.dry_run {
+                plan.push_str(&format_plan(*system, &chains, |path| {
+                    self.build_command_line(*system, path)
+                }));
+            }
+
+            let jobs = self.config.jobs();
+            debug!(
+                "Building {} derivations in {} levels with {jobs} jobs",
+                total.len(),
+                levels.len()
+            );
+
+            let shared_summary = Mutex::new(&mut *summary);
+            let shared_built = Mutex::new(Vec::new());
+            let bar = self.progress_bar(total.len());
+
+            let succeeded = run_levels(
+                &levels,
+                &dependencies,
+                jobs,
+                |(derivation, path)| {
+                    let name = derivation.to_string();
+                    bar.set_message(name.clone());
+                    self.emit(&Event::BuildStart { derivation: &name });
+                    let started = Instant::now();
+                    let result = match broken.get(derivation) {
+                        Some(error) => Err(anyhow!("{error}")),
+                        None => self.build_one(derivation, path, &shared_summary, &shared_built),
+                    };
+                    let status = result.as_ref().map_or("error", Status::as_str);
+                    self.emit(&Event::build_finish(&name, status, started.elapsed()));
+                    bar.inc(1);
+                    let result = result.map(|status| !matches!(status, Status::Fail));
+                    let result = match result {
+                        Ok(false) if self.allow_failure(derivation) => {
+                            info!("{derivation} failed, but is allowed to");
+                            shared_summary
+                                .lock()
+                                .unwrap()
+                                .allow_failure(&derivation.output, &derivation.to_string());
+                            Ok(false)
                         }
-                        Status::Success => {
-                            let artifact = if !dry_run
-                                && self.config.save_artifact(output, *system, attribute)
-                            {
-                                debug!("Saving artifacts from {}", &derivation);
-                                let artifact = &self.nix_result_dir;
-                                if !artifact.is_symlink() {
-                                    bail!("Error: todo better error message");
-                                }
-
-                                let artifact = fs::canonicalize(artifact)?;
-                                debug!("artifact to save: {}", artifact.display());
-
-                                let link = self.output_dir.join(&derivation.to_string());
-                                debug!("link: {}", link.display());
-                                symlink(&artifact, &link)?;
-
-                                Some(link)
-                            } else {
-                                None
-                            };
-
-                            summary.register_success(output, derivation.to_string(), artifact);
+                        result => result,
+                    };
+                    match result {
+                        Err(e) if self.options.keep_going && !e.is::<Interrupted>() => {
+                            warn!("Failed to build {derivation}: {e:#}");
+                            shared_summary.lock().unwrap().register_error(
+                                &derivation.output,
+                                derivation.to_string(),
+                                format!("{e:#}"),
+                            );
+                            Ok(false)
                         }
+                        result => result,
                     }
-                    have_ran.insert(derivation.clone());
-                }
+                },
+                |(blocked, _), causes| {
+                    bar.inc(1);
+                    self.record_blocked(&shared_summary, blocked, causes);
+                },
+                |(derivation, _)| self.allow_failure(derivation),
+                self.options.fail_fast,
+            );
+            bar.finish_and_clear();
+
+            all_succeeded &= succeeded?;
+            built.extend(shared_built.into_inner().unwrap());
+            if self.options.fail_fast && !all_succeeded {
+                warn!("Stopping at the first failure");
+                break;
+            }
+        }
+
+        Ok(all_succeeded && !summary.missing_required_output())
+    }
+
+    /// Warn about `[[checks]]` gates that point at nothing in the graph, so they don't gate
+    /// anything without anyone noticing. Only targets get evaluated when there are some, so the
+    /// rest of the gates are expected to point at nothing then
+    fn warn_dangling_gates(&self, graph: &Graph<Node>, summary: &mut Summary) {
+        if !self.options.targets.is_empty() {
+            return;
+        }
+        for warning in dangling_gates(graph, &self.config) {
+            warn!("{warning}");
+            summary.warn(warning);
+        }
+    }
+
+    /// Pin each built derivation that matches a configured pin so cachix won't garbage collect it
+    fn pin_all(&self, built: &[Built], summary: &mut Summary) -> Result<bool> {
+        let pins = self.config.pins();
+        if pins.is_empty() {
+            return Ok(true);
+        }
+
+        let (Some(cachix), Some(CacheBackend::Cachix(caches))) =
+            (&self.cachix, self.config.cache())
+        else {
+            bail!(InvalidConfig(s!(
+                "Pins are configured, but cachix is not set up"
+            )));
+        };
+
+        let mut all_succeeded = true;
+        for (derivation, out_paths) in built {
+            let matches = pins
+                .iter()
+                .any(|pin| pin.matches(&derivation.output, derivation.system, &derivation.name));
+            if !matches {
+                continue;
+            }
+
+            let Some(store_path) = main_out_path(out_paths) else {
+                let warning = format!("Can't pin {derivation}, nix didn't report any out paths");
+                warn!("{warning}");
+                summary.warn(warning);
+                continue;
+            };
+            let store_path = store_path.display().to_string();
+            let name = pin_name(derivation);
+            info!("Pinning {derivation} as '{name}'");
+            let mut pinned = true;
+            for cache in caches {
+                let args = &["pin", cache, &name, &store_path];
+                let status = run_stream(cachix, args, Some(&self.cachix_env), false)?;
+                pinned &= matches!(status, Status::Success);
+            }
+            if !pinned {
+                all_succeeded = false;
             }
+            summary.register_pin(name, store_path, pinned);
         }
 
         Ok(all_succeeded)
     }
 
-    pub fn run(&self, dry_run: bool) -> Result<bool> {
-        let nix_version = nix_version(&self.nix)?;
-        let git_revision = git_revision()?;
+    /// Every chain that would be built, in order, without building anything
+    pub fn list(&self) -> Result<Vec<Vec<Node>>> {
+        let mut summary = Summary::new(
+            self.cwd.clone(),
+            nix_version(&self.nix)?,
+            None,
+            self.git_revision(),
+            self.width,
+        );
+        let mut all_chains = Vec::new();
+        for system in &self.systems_to_build() {
+            let (graph, broken) = self.build_graph(*system, &mut summary)?;
+            self.warn_dangling_gates(&graph, &mut summary);
+            let dependencies = graph.dependencies();
+            let walker = graph.walker();
+            let chains = filter_chains(walker.chains(), &dependencies, &self.options.filters);
+            all_chains.extend(chains);
+            for (derivation, error) in broken {
+                warn!("{derivation} failed to evaluate: {error}");
+            }
+        }
+        Ok(all_chains)
+    }
 
-        let cachix_version = match &self.cachix {
-            Some(cachix) => {
+    /// Build everything. Stopping early by interrupt still returns what finished
+    pub fn run(&self) -> Result<Finished> {
+        let dry_run = self.options.dry_run;
+        let nix_version = nix_version(&self.nix)?;
+        if let Some(min_version) = self.config.min_nix_version() {
+            check_nix_version(&nix_version, min_version)?;
+        }
+        if !self.options.skip_store_check {
+            self.check_store()?;
+        }
+        let git_revision = self.git_revision();
+
+        // Warnings from before there's a summary to put them in
+        let mut warnings = Vec::new();
+        let cachix_version = match (&self.cachix, self.config.cache()) {
+            (Some(_), Some(CacheBackend::Cachix(_))) if self.options.offline => {
+                let warning = s!("Offline, so not using cachix");
+                warn!("{warning}");
+                warnings.push(warning);
+                None
+            }
+            (Some(cachix), Some(CacheBackend::Cachix(caches))) => {
                 info!("Setting up nix to work with cachix");
-                setup_cachix(cachix, self.config.cache().unwrap(), dry_run)?;
+                CachixCredentials::from_env(&self.cachix_env)
+                    .check(self.publishing(), !self.config.pins().is_empty())?;
+                setup_cachix(cachix, caches, &self.cachix_env, dry_run)?;
 
                 Some(cachix_version(cachix)?)
             }
-            None => None,
+            _ => None,
         };
 
-        if self.output_dir.is_dir() {
-            log::warn!("Removing old artifact dir");
-            if dry_run {
-                println!("[DRYRUN] would remove old artifact dir");
-            } else {
-                fs::remove_dir_all(&self.output_dir)?;
-            }
+        if !self.options.no_artifacts {
+            self.reset_output_dir()?;
         }
-        fs::create_dir_all(&self.output_dir)?;
 
         let mut summary = Summary::new(
             self.cwd.clone(),
@@ -423,26 +2519,90 @@ impl App {
             git_revision,
             self.width,
         );
+        summary.set_color(self.options.color);
+        summary.set_group_by_system(self.options.group_by_system);
+        for warning in warnings {
+            summary.warn(warning);
+        }
+        let flake_lock_problem = match &self.flake_dir {
+            Some(flake_dir) => flake_lock_problem(flake_dir)?,
+            None => None,
+        };
+        if let Some(problem) = flake_lock_problem {
+            if self.options.strict {
+                bail!(InvalidConfig(problem));
+            }
+            warn!("{problem}");
+            summary.warn_flake_lock(problem);
+        }
+        let inputs = self.input_revisions(&mut summary);
+        summary.set_inputs(inputs);
+
+        if self.config.flake_check() && matches!(self.flake_check()?, Status::Fail) {
+            summary.fail_flake_check();
+            if !self.options.keep_going {
+                error!("nix flake check failed, not building anything");
+                self.report(&summary)?;
+                return Ok(Finished {
+                    exit: Exit::BuildFailure,
+                    summary,
+                    plan: String::new(),
+                });
+            }
+        }
 
-        let all_succeeded = self.build_all(dry_run, &mut summary)?;
-
-        if all_succeeded {
-            for pin in self.config.pins() {
-                // TODO
+        let mut built = Vec::new();
+        let mut plan = String::new();
+        let mut all_succeeded = match self.build_all(&mut summary, &mut built, &mut plan) {
+            Ok(all_succeeded) => all_succeeded,
+            Err(e) if e.is::<Interrupted>() => {
+                // Still report whatever finished
+                self.report(&summary)?;
+                return Ok(Finished {
+                    exit: Exit::Interrupted,
+                    summary,
+                    plan,
+                });
             }
+            Err(e) => return Err(e),
+        };
+
+        // Failed verifications, like smoke tests, are only recorded in the summary
+        all_succeeded &= summary.passed();
+        if all_succeeded && !dry_run && !self.options.offline {
+            all_succeeded = self.pin_all(&built, &mut summary)?;
         }
 
-        // TODO: json output option
-        summary.print();
+        self.report(&summary)?;
+        let exit = Exit::from_run(all_succeeded, &summary);
+        Ok(Finished {
+            exit,
+            summary,
+            plan,
+        })
+    }
 
-        Ok(all_succeeded)
+    /// Write out the report files
+    fn report(&self, summary: &Summary) -> Result<()> {
+        if self.options.github {
+            if let Some(path) = env::var_os(GITHUB_STEP_SUMMARY) {
+                summary.write_github_step_summary(Path::new(&path))?;
+            }
+        }
+
+        if let Some(path) = &self.options.junit {
+            summary.save_junit(path)?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use pretty_assertions::{assert_eq, assert_ne};
+    use crate::config::{Arch, OS};
+    use crate::test_dir::TestDir;
+    use pretty_assertions::assert_eq;
     use rstest::rstest;
 
     fn mk_check(prefix: &str, name: &str) -> Derivation {
@@ -462,6 +2622,8 @@ mod tests {
     #[rstest]
     #[case("pkg", "packages")]
     #[case("package", "packages")]
+    #[case("app", "apps")]
+    #[case("App", "apps")]
     #[case("shell", "devShells")]
     #[case("devshell", "devShells")]
     #[case("devShell", "devShells")]
@@ -477,17 +2639,771 @@ mod tests {
     #[case("systemConfig", "systemConfigs")]
     #[case("systemConfiguration", "systemConfigs")]
     fn test_get_type_of_check(#[case] prefix: &str, #[case] expected: &str) {
+        let naming = CheckNaming::default();
         let name = "foo";
         let drv = mk_check(prefix, name);
         // Check that match works as-is
-        let actual = get_type_of_check(&drv).unwrap();
+        let actual = get_type_of_check(&drv, &naming).unwrap();
         assert_eq!(expected, actual);
 
         // Check that match works when plural
         let name = mk_plural(prefix);
-        let drv = mk_check(&prefix, &name);
-        let actual = get_type_of_check(&drv).unwrap();
-        assert_eq!(expected, actual)
+        let drv = mk_check(prefix, &name);
+        let actual = get_type_of_check(&drv, &naming).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_get_type_of_check_errors() {
+        let drv = Derivation::new(s!("checks"), System::x86_linux(), s!("fmt"));
+        let error = get_type_of_check(&drv, &CheckNaming::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("checks.x86_64-linux.fmt"));
+        assert!(error.contains("`<type>-<name>`"));
+
+        let drv = Derivation::new(s!("checks"), System::x86_linux(), s!("lint-foo"));
+        let error = format!(
+            "{:#}",
+            get_type_of_check(&drv, &CheckNaming::default()).unwrap_err()
+        );
+        assert!(error.contains("'lint' isn't a known type"));
+    }
+
+    #[test]
+    fn test_custom_check_naming() {
+        let naming: CheckNaming =
+            toml::from_str("separator = '_'\naliases = { test = \"packages\" }").unwrap();
+        let drv = Derivation::new(s!("checks"), System::x86_linux(), s!("pkg_foo"));
+        assert_eq!("packages", get_type_of_check(&drv, &naming).unwrap());
+        let drv = Derivation::new(s!("checks"), System::x86_linux(), s!("Tests_foo"));
+        assert_eq!("packages", get_type_of_check(&drv, &naming).unwrap());
+
+        let drv = Derivation::new(s!("checks"), System::x86_linux(), s!("pkg-foo"));
+        let error = get_type_of_check(&drv, &naming).unwrap_err().to_string();
+        assert!(error.contains("`<type>_<name>`"));
+
+        let check = Derivation::new(s!("checks"), System::x86_linux(), s!("test_foo"));
+        let drv = Derivation::new(s!("packages"), System::x86_linux(), s!("foo"));
+        assert!(check_checks_derivation(&check, &drv, &naming));
+    }
+
+    #[test]
+    fn test_parse_build_plan() {
+        let stderr = "these 2 derivations will be built:
+  /nix/store/abc-foo-1.0.drv
+  /nix/store/def-bar-1.0.drv
+these 3 paths will be fetched (12.34 MiB download, 56.78 MiB unpacked):
+  /nix/store/ghi-glibc-2.40
+  /nix/store/jkl-gcc-14
+  /nix/store/mno-bash-5.2";
+        let plan = parse_build_plan(stderr);
+        assert_eq!(
+            BuildPlan {
+                builds: 2,
+                fetches: 3,
+                download: Some(s!("12.34 MiB")),
+            },
+            plan
+        );
+        assert_eq!(
+            "dry run, would build 2 and fetch 3 (12.34 MiB download)",
+            plan.to_string()
+        );
+
+        let plan = parse_build_plan("this derivation will be built:\n  /nix/store/abc-foo.drv");
+        assert_eq!("dry run, would build 1 and fetch 0", plan.to_string());
+        assert_eq!(BuildPlan::default(), parse_build_plan(""));
+    }
+
+    #[test]
+    fn test_out_paths() {
+        let stdout = "copying path to cache\n/nix/store/abc-foo-1.0\n/nix/store/def-foo-1.0-dev\n";
+        let expected = vec![
+            PathBuf::from("/nix/store/abc-foo-1.0"),
+            PathBuf::from("/nix/store/def-foo-1.0-dev"),
+        ];
+        assert_eq!(expected, out_paths(stdout));
+    }
+
+    #[rstest]
+    #[case(true, false, true, true)]
+    #[case(false, true, true, false)]
+    #[case(true, true, true, true)]
+    fn test_cachix_credentials(
+        #[case] auth_token: bool,
+        #[case] signing_key: bool,
+        #[case] can_push: bool,
+        #[case] can_pin: bool,
+    ) {
+        let credentials = CachixCredentials {
+            auth_token,
+            signing_key,
+        };
+        assert_eq!(can_push, credentials.check(true, false).is_ok());
+        assert_eq!(can_pin, credentials.check(true, true).is_ok());
+    }
+
+    #[test]
+    fn test_cachix_credentials_missing() {
+        let credentials = CachixCredentials {
+            auth_token: false,
+            signing_key: false,
+        };
+        let error = credentials.check(false, false).unwrap_err();
+        assert!(error.is::<InvalidConfig>());
+
+        let credentials = CachixCredentials {
+            auth_token: false,
+            signing_key: true,
+        };
+        let error = credentials.check(true, true).unwrap_err().to_string();
+        assert!(error.contains("only a signing key is set, which can push but not pin"));
+    }
+
+    #[test]
+    fn test_read_secret() {
+        let dir = TestDir::new("secret");
+        let path = dir.join("secret");
+        fs::write(&path, "hunter2\n").unwrap();
+        assert_eq!("hunter2", read_secret(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let error = read_secret(&path).unwrap_err();
+        assert!(error.is::<InvalidConfig>());
+    }
+
+    #[test]
+    fn test_copy_tree() {
+        let root = TestDir::new("copy-tree");
+        let from = root.join("store-path");
+        fs::create_dir_all(from.join("bin")).unwrap();
+        fs::write(from.join("bin/foo"), "foo").unwrap();
+        let mut permissions = fs::metadata(from.join("bin/foo")).unwrap().permissions();
+        permissions.set_mode(0o555);
+        fs::set_permissions(from.join("bin/foo"), permissions).unwrap();
+        symlink("bin/foo", from.join("link")).unwrap();
+
+        let to = root.join("dist/foo");
+        copy_tree(&from, &to).unwrap();
+        assert_eq!("foo", fs::read_to_string(to.join("bin/foo")).unwrap());
+        assert!(!fs::symlink_metadata(to.join("link")).unwrap().is_symlink());
+        assert_eq!("foo", fs::read_to_string(to.join("link")).unwrap());
+        let mode = fs::metadata(to.join("bin/foo"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(0o755, mode & 0o777);
+    }
+
+    #[test]
+    fn test_save_artifacts_per_system() {
+        let root = TestDir::new("save-artifacts");
+        let dist = root.join("dist");
+        fs::create_dir_all(&dist).unwrap();
+        let x86 = root.join("aaa-foo");
+        let arm = root.join("bbb-foo");
+        fs::write(&x86, "x86").unwrap();
+        fs::write(&arm, "arm").unwrap();
+
+        let x86_links = save_artifacts(
+            &dist,
+            ArtifactMode::Symlink,
+            ".#packages.x86_64-linux.foo",
+            std::slice::from_ref(&x86),
+        )
+        .unwrap();
+        let arm_links = save_artifacts(
+            &dist,
+            ArtifactMode::Symlink,
+            ".#packages.aarch64-linux.foo",
+            std::slice::from_ref(&arm),
+        )
+        .unwrap();
+        assert_ne!(x86_links, arm_links);
+        assert_eq!("x86", fs::read_to_string(&x86_links[0]).unwrap());
+        assert_eq!("arm", fs::read_to_string(&arm_links[0]).unwrap());
+
+        // Saving again replaces what's there instead of failing
+        for mode in [ArtifactMode::Symlink, ArtifactMode::Copy] {
+            let links = save_artifacts(
+                &dist,
+                mode,
+                ".#packages.x86_64-linux.foo",
+                std::slice::from_ref(&arm),
+            )
+            .unwrap();
+            assert_eq!(x86_links, links);
+            assert_eq!("arm", fs::read_to_string(&links[0]).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_archive_tree() {
+        let root = TestDir::new("archive-tree");
+        let from = root.join("store-path");
+        fs::create_dir_all(from.join("share/doc")).unwrap();
+        fs::write(from.join("share/doc/README"), "hello").unwrap();
+        symlink("share/doc/README", from.join("README")).unwrap();
+
+        let archive = root.join("foo.tar.gz");
+        archive_tree(&from, "foo", &archive).unwrap();
+
+        let file = fs::File::open(&archive).unwrap();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut entries: Vec<(String, String)> = tar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().display().to_string();
+                let mut contents = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+                (path.trim_end_matches('/').to_owned(), contents)
+            })
+            .collect();
+        entries.sort();
+        let expected = vec![
+            (s!("foo"), s!("")),
+            (s!("foo/README"), s!("hello")),
+            (s!("foo/share"), s!("")),
+            (s!("foo/share/doc"), s!("")),
+            (s!("foo/share/doc/README"), s!("hello")),
+        ];
+        assert_eq!(expected, entries);
+    }
+
+    #[test]
+    fn test_flake_lock_problem() {
+        let dir = TestDir::new("flake-lock");
+        fs::write(dir.join("flake.nix"), "{}").unwrap();
+        assert_eq!(
+            Some(s!("flake.lock is missing")),
+            flake_lock_problem(&dir).unwrap()
+        );
+
+        let lock = fs::File::create(dir.join("flake.lock")).unwrap();
+        let now = std::time::SystemTime::now();
+        lock.set_modified(now - Duration::from_mins(1)).unwrap();
+        assert_eq!(
+            Some(s!("flake.lock is older than flake.nix")),
+            flake_lock_problem(&dir).unwrap()
+        );
+
+        lock.set_modified(now + Duration::from_mins(1)).unwrap();
+        assert_eq!(None, flake_lock_problem(&dir).unwrap());
+    }
+
+    #[test]
+    fn test_locked_revisions() {
+        let metadata = serde_json::json!({
+            "locks": {
+                "nodes": {
+                    "root": {
+                        "inputs": {
+                            "nixpkgs": "nixpkgs",
+                            "local": "local",
+                            "utils": ["nixpkgs", "utils"]
+                        }
+                    },
+                    "nixpkgs": {"locked": {"type": "github", "rev": "0123abc"}},
+                    "local": {"locked": {"type": "path", "path": "./local"}}
+                }
+            }
+        });
+        let expected = BTreeMap::from([(s!("nixpkgs"), s!("0123abc"))]);
+        assert_eq!(expected, locked_revisions(&metadata));
+        assert!(locked_revisions(&serde_json::json!({})).is_empty());
+    }
+
+    #[rstest]
+    #[case(r#"[{"path": "/nix/store/abc-foo", "narSize": 10, "closureSize": 300}]"#)]
+    #[case(
+        r#"{"/nix/store/abc-foo": {"narSize": 10, "closureSize": 100},
+               "/nix/store/def-foo-doc": {"narSize": 10, "closureSize": 200}}"#
+    )]
+    fn test_closure_size(#[case] stdout: &str) {
+        assert_eq!(300, closure_size(stdout).unwrap());
+    }
+
+    #[test]
+    fn test_closure_size_missing() {
+        assert!(closure_size(r#"[{"path": "/nix/store/abc-foo"}]"#).is_err());
+    }
+
+    #[test]
+    fn test_derivation_out_paths() {
+        let stdout = r#"{
+            "/nix/store/abc-foo-1.0.drv": {
+                "outputs": {
+                    "dev": {"path": "/nix/store/def-foo-1.0-dev"},
+                    "out": {"path": "/nix/store/ghi-foo-1.0"}
+                }
+            }
+        }"#;
+        let expected = vec![
+            s!("/nix/store/def-foo-1.0-dev"),
+            s!("/nix/store/ghi-foo-1.0"),
+        ];
+        assert_eq!(Some(expected), derivation_out_paths(stdout).unwrap());
+
+        let stdout = r#"{"abc-foo-1.0.drv": {"outputs": {"out": {"path": "ghi-foo-1.0"}}}}"#;
+        let expected = vec![s!("/nix/store/ghi-foo-1.0")];
+        assert_eq!(Some(expected), derivation_out_paths(stdout).unwrap());
+
+        // Content addressed outputs aren't known until they're built
+        let stdout = r#"{"/nix/store/abc-foo-1.0.drv": {"outputs": {"out": {"method": "nar"}}}}"#;
+        assert_eq!(None, derivation_out_paths(stdout).unwrap());
+    }
+
+    #[test]
+    fn test_shown_attributes() {
+        let show: serde_json::Value = serde_json::from_str(
+            r#"{
+                "checks": {
+                    "x86_64-linux": {
+                        "pkg-foo": {"name": "pkg-foo", "type": "derivation"}
+                    }
+                },
+                "packages": {
+                    "aarch64-linux": {
+                        "bar": {"name": "bar", "type": "derivation"}
+                    },
+                    "x86_64-linux": {
+                        "bar": {"name": "bar", "type": "derivation"},
+                        "foo": {"name": "foo", "type": "derivation"}
+                    }
+                },
+                "legacyPackages": {
+                    "x86_64-linux": {}
+                },
+                "nixosConfigurations": {
+                    "host": {"type": "nixos-configuration"}
+                }
+            }"#,
+        )
+        .unwrap();
+        let system = System::x86_linux();
+
+        assert_eq!(
+            Some(vec![s!("bar"), s!("foo")]),
+            shown_attributes(&show, "packages", system)
+        );
+        assert_eq!(
+            Some(vec![s!("pkg-foo")]),
+            shown_attributes(&show, "checks", system)
+        );
+        assert_eq!(
+            Some(vec![s!("host")]),
+            shown_attributes(&show, "nixosConfigurations", system)
+        );
+        assert_eq!(None, shown_attributes(&show, "legacyPackages", system));
+        assert_eq!(None, shown_attributes(&show, "devShells", system));
+        assert_eq!(
+            None,
+            shown_attributes(&show, "packages", System::x86_darwin())
+        );
+    }
+
+    #[test]
+    fn test_shown_singular_attributes() {
+        let show = serde_json::json!({
+            "defaultPackage": {
+                "x86_64-linux": {"name": "foo", "type": "derivation"}
+            }
+        });
+        assert_eq!(
+            Some(vec![String::new()]),
+            shown_attributes(&show, "defaultPackage", System::x86_linux())
+        );
+        assert_eq!(
+            None,
+            shown_attributes(&show, "defaultPackage", System::x86_darwin())
+        );
+        assert_eq!(
+            None,
+            shown_attributes(&show, "devShell", System::x86_linux())
+        );
+    }
+
+    #[rstest]
+    #[case(&["pkgs/foo/default.nix", "pkgs/bar.nix"], Some(vec!["bar", "foo"]))]
+    #[case(&["pkgs/foo/src/main.rs", "pkgs/foo/default.nix"], Some(vec!["foo"]))]
+    #[case(&["nix/pkgs/baz/default.nix"], Some(vec!["baz"]))]
+    #[case(&["pkgs/foo/default.nix", "flake.lock"], None)]
+    #[case(&["pkgs/README.md"], None)]
+    #[case(&["pkgsfoo/default.nix"], None)]
+    fn test_changed_packages(#[case] files: &[&str], #[case] expected: Option<Vec<&str>>) {
+        let package_dirs = vec![s!("pkgs"), s!("nix/pkgs/")];
+        let expected = expected.map(|packages| packages.into_iter().map(String::from).collect());
+        assert_eq!(expected, changed_packages(files, &package_dirs));
+    }
+
+    #[test]
+    fn test_map_in_parallel_keeps_order() {
+        let items: Vec<u64> = (0..50).collect();
+        // Later items finish first
+        let actual = map_in_parallel(&items, 8, |item| {
+            thread::sleep(Duration::from_millis(50 - item));
+            item * 2
+        });
+        let expected: Vec<u64> = (0..50).map(|item| item * 2).collect();
+        assert_eq!(expected, actual);
+        assert!(map_in_parallel(&[] as &[u64], 4, |item| *item).is_empty());
+    }
+
+    #[test]
+    fn test_shown_systems() {
+        let show = serde_json::json!({
+            "checks": {"x86_64-linux": {}, "riscv64-linux": {}},
+            "packages": {"aarch64-darwin": {}, "not-a-system": {}},
+            "devShells": {"aarch64-linux": {}},
+            "nixosConfigurations": {"x86_64-darwin": {"type": "nixos-configuration"}}
+        });
+        let outputs = [s!("checks"), s!("packages"), s!("nixosConfigurations")];
+        let expected = BTreeSet::from([
+            System::arm_darwin(),
+            System::new(Arch::Riscv64, OS::Linux),
+            System::x86_linux(),
+        ]);
+        assert_eq!(expected, shown_systems(&show, &outputs));
+    }
+
+    #[test]
+    fn test_filter_chains() {
+        let node = |input: &str| {
+            (
+                Derivation::from_str(input).unwrap(),
+                format!("/{input}.drv"),
+            )
+        };
+        let check = node("checks.x86_64-linux.pkg-foo");
+        let foo = node("packages.x86_64-linux.foo");
+        let bar = node("packages.x86_64-linux.bar");
+        let chains = vec![vec![check.clone(), foo.clone()], vec![bar.clone()]];
+        let dependencies = HashMap::from([(foo.clone(), vec![check.clone()])]);
+
+        let filters = vec![OutputPath::from_str("packages.*.foo").unwrap()];
+        let expected = vec![vec![check.clone(), foo.clone()]];
+        assert_eq!(
+            expected,
+            filter_chains(chains.clone(), &dependencies, &filters)
+        );
+
+        let filters = vec![OutputPath::from_str("checks.*.*").unwrap()];
+        let expected = vec![vec![check.clone()]];
+        assert_eq!(
+            expected,
+            filter_chains(chains.clone(), &dependencies, &filters)
+        );
+
+        assert_eq!(chains.clone(), filter_chains(chains, &dependencies, &[]));
+    }
+
+    #[test]
+    fn test_dangling_gates() {
+        let node = |input: &str| {
+            (
+                Derivation::from_str(input).unwrap(),
+                format!("/{input}.drv"),
+            )
+        };
+        let config: Config = toml::from_str(
+            r#"
+            [[checks]]
+            name = "clippy"
+            gates = ["packages.*.foo", "packages.*.bar"]
+            "#,
+        )
+        .unwrap();
+        let mut graph = Graph::new();
+        graph.add_node(node("checks.x86_64-linux.clippy"));
+        graph.add_node(node("packages.x86_64-linux.foo"));
+
+        let expected = vec![s!(
+            ".#checks.x86_64-linux.clippy gates packages.*.bar, but nothing matching it is being \
+             built for x86_64-linux"
+        )];
+        assert_eq!(expected, dangling_gates(&graph, &config));
+
+        graph.add_node(node("packages.x86_64-linux.bar"));
+        assert!(dangling_gates(&graph, &config).is_empty());
+    }
+
+    #[rstest]
+    #[case(anyhow!(Interrupted), Exit::Interrupted)]
+    #[case(anyhow!(MissingTool(s!("no nix"))), Exit::MissingTool)]
+    #[case(anyhow!(InvalidConfig(s!("bad toml"))).context("Loading config"), Exit::Config)]
+    #[case(anyhow!("nix build exploded"), Exit::BuildFailure)]
+    fn test_exit_from_error(#[case] error: anyhow::Error, #[case] expected: Exit) {
+        assert_eq!(expected, Exit::from_error(&error));
+    }
+
+    #[test]
+    fn test_failed_verification_fails_the_run() {
+        let mut summary = Summary::new(
+            PathBuf::from("/tmp"),
+            s!("nix (Nix) 2.24.12"),
+            None,
+            GitRevision::unknown(),
+            80,
+        );
+        summary.set_color(ColorChoice::Never);
+        summary.register_success("packages", s!(".#packages.x86_64-linux.foo"), Vec::new());
+        assert_eq!(Exit::Success, Exit::from_run(true, &summary));
+
+        summary.record_smoke(
+            "packages",
+            ".#packages.x86_64-linux.foo",
+            Some(s!("exited with 1")),
+        );
+        assert_eq!(Exit::BuildFailure, Exit::from_run(true, &summary));
+        assert!(summary.render().contains("0 blocked\nFAIL\n"));
+    }
+
+    #[rstest]
+    #[case(false, &["store", "ping"])]
+    #[case(true, &["store", "ping", "--offline"])]
+    fn test_store_check_args(#[case] offline: bool, #[case] expected: &[&str]) {
+        assert_eq!(expected, store_check_args(offline));
+    }
+
+    #[test]
+    fn test_format_chains() {
+        let node = |input: &str| {
+            (
+                Derivation::from_str(input).unwrap(),
+                format!("/{input}.drv"),
+            )
+        };
+        let chains = vec![
+            vec![
+                node("checks.x86_64-linux.pkg-foo"),
+                node("packages.x86_64-linux.foo"),
+            ],
+            vec![node("packages.x86_64-linux.bar")],
+        ];
+        let expected = "\
+Chain 1
+  .#checks.x86_64-linux.pkg-foo /checks.x86_64-linux.pkg-foo.drv
+  .#packages.x86_64-linux.foo /packages.x86_64-linux.foo.drv
+Chain 2
+  .#packages.x86_64-linux.bar /packages.x86_64-linux.bar.drv
+";
+        assert_eq!(expected, format_chains(&chains));
+    }
+
+    #[rstest]
+    #[case("plain", "plain")]
+    #[case("/nix/store/abc-foo.drv^*", "'/nix/store/abc-foo.drv^*'")]
+    #[case("it's", r"'it'\''s'")]
+    #[case("", "''")]
+    fn test_shell_quote(#[case] arg: &str, #[case] expected: &str) {
+        assert_eq!(expected, shell_quote(arg));
+    }
+
+    #[test]
+    fn test_format_plan() {
+        let chains = vec![vec![
+            (
+                Derivation::from_str("checks.x86_64-linux.fmt").unwrap(),
+                s!("/nix/store/a-fmt.drv"),
+            ),
+            (
+                Derivation::from_str("packages.x86_64-linux.foo").unwrap(),
+                s!("/nix/store/b-foo.drv"),
+            ),
+        ]];
+        let expected = "\
+==== Plan for x86_64-linux ====
+Chain 1
+  1. .#checks.x86_64-linux.fmt
+     drv: /nix/store/a-fmt.drv
+     run: nix build /nix/store/a-fmt.drv
+  2. .#packages.x86_64-linux.foo
+     drv: /nix/store/b-foo.drv
+     run: nix build /nix/store/b-foo.drv
+==== End of plan for x86_64-linux ====
+";
+        let plan = format_plan(System::x86_linux(), &chains, |path| {
+            format!("nix build {path}")
+        });
+        assert_eq!(expected, plan);
+    }
+
+    #[test]
+    fn test_format_drv_paths() {
+        let check = (
+            Derivation::from_str("checks.x86_64-linux.fmt").unwrap(),
+            s!("/nix/store/a-fmt.drv"),
+        );
+        let foo = (
+            Derivation::from_str("packages.x86_64-linux.foo").unwrap(),
+            s!("/nix/store/b-foo.drv"),
+        );
+        let bar = (
+            Derivation::from_str("packages.x86_64-linux.bar").unwrap(),
+            s!("/nix/store/c-bar.drv"),
+        );
+        let chains = vec![vec![check.clone(), foo], vec![check, bar]];
+        let expected = "\
+/nix/store/a-fmt.drv
+/nix/store/b-foo.drv
+/nix/store/c-bar.drv
+";
+        assert_eq!(expected, format_drv_paths(&chains));
+    }
+
+    #[test]
+    fn test_find_configured_tool() {
+        let sh = Path::new("/bin/sh");
+        assert_eq!(sh, find_tool("sh", Some(sh)).unwrap());
+
+        let error = find_tool("nix", Some(Path::new("/does/not/exist/nix"))).unwrap_err();
+        assert_eq!(Exit::MissingTool, Exit::from_error(&error));
+
+        // Not a file
+        let error = find_tool("nix", Some(Path::new("/"))).unwrap_err();
+        assert_eq!(Exit::MissingTool, Exit::from_error(&error));
+    }
+
+    #[rstest]
+    #[case("nix (Nix) 2.24.12", 2, 24, 12)]
+    #[case("nix (Nix) 2.3.16", 2, 3, 16)]
+    #[case("nix (Nix) 2.18.1pre20231102_e5c9d1f", 2, 18, 1)]
+    #[case("nix (Nix) 2.4pre20211006_53e4794", 2, 4, 0)]
+    #[case("nix (Determinate Nix 3.6.2) 2.29.0", 2, 29, 0)]
+    #[case("nix (Lix, like Nix) 2.91.1", 2, 91, 1)]
+    #[case("2.18", 2, 18, 0)]
+    fn test_parse_version(
+        #[case] input: &str,
+        #[case] major: u32,
+        #[case] minor: u32,
+        #[case] patch: u32,
+    ) {
+        let expected = Version {
+            major,
+            minor,
+            patch,
+        };
+        assert_eq!(Some(expected), parse_version(input));
+    }
+
+    #[test]
+    fn test_check_nix_version() {
+        assert!(check_nix_version("nix (Nix) 2.24.12", "2.19").is_ok());
+        assert!(check_nix_version("nix (Nix) 2.24.12", "2.24.12").is_ok());
+
+        let error = check_nix_version("nix (Nix) 2.3.16", "2.19").unwrap_err();
+        assert_eq!(Exit::MissingTool, Exit::from_error(&error));
+
+        let error = check_nix_version("nix (Nix) 2.24.12", "latest").unwrap_err();
+        assert_eq!(Exit::Config, Exit::from_error(&error));
+    }
+
+    #[test]
+    fn test_select_legacy_packages() {
+        let names = vec![s!("hello"), s!("cowsay"), s!("python3Packages")];
+        let system = System::x86_linux();
+
+        let patterns = vec![OutputPath::from_str("legacyPackages.*.hello").unwrap()];
+        let actual = select_legacy_packages(names.clone(), &patterns, system).unwrap();
+        assert_eq!(vec![s!("hello")], actual);
+
+        // Patterns for other outputs don't pick anything
+        let patterns = vec![OutputPath::from_str("packages.*.*").unwrap()];
+        let actual = select_legacy_packages(names.clone(), &patterns, system).unwrap();
+        assert!(actual.is_empty());
+
+        let error = select_legacy_packages(names, &[], system).unwrap_err();
+        assert_eq!(Exit::Config, Exit::from_error(&error));
+    }
+
+    #[test]
+    fn test_too_many_legacy_packages() {
+        let names = (0..=MAX_LEGACY_PACKAGES)
+            .map(|i| format!("pkg{i}"))
+            .collect();
+        let patterns = vec![OutputPath::from_str("legacyPackages.*.*").unwrap()];
+        let error = select_legacy_packages(names, &patterns, System::x86_linux()).unwrap_err();
+        assert_eq!(Exit::Config, Exit::from_error(&error));
+    }
+
+    #[test]
+    fn test_artifact_links() {
+        let out_paths = vec![
+            PathBuf::from("/nix/store/def-foo-1.0-dev"),
+            PathBuf::from("/nix/store/abc-foo-1.0"),
+            PathBuf::from("/nix/store/ghi-foo-1.0-man"),
+        ];
+        let expected = vec![
+            (s!(".#packages.x86_64-linux.foo-dev"), &out_paths[0]),
+            (s!(".#packages.x86_64-linux.foo"), &out_paths[1]),
+            (s!(".#packages.x86_64-linux.foo-man"), &out_paths[2]),
+        ];
+        let actual = artifact_links(".#packages.x86_64-linux.foo", &out_paths);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_configuration_display() {
+        let drv = Derivation::new(s!("nixosConfigurations"), System::x86_linux(), s!("laptop"));
+        assert_eq!(".#nixosConfigurations.laptop", drv.to_string());
+
+        let drv = Derivation::new(s!("packages"), System::x86_linux(), s!("foo"));
+        assert_eq!(".#packages.x86_64-linux.foo", drv.to_string());
+
+        let drv = Derivation::new(s!("defaultPackage"), System::x86_linux(), String::new());
+        assert_eq!(".#defaultPackage.x86_64-linux", drv.to_string());
+        assert_eq!("defaultPackage.x86_64-linux", drv.attribute());
+    }
+
+    #[test]
+    fn test_pin_name() {
+        let x86 = Derivation::new(s!("packages"), System::x86_linux(), s!("foo"));
+        let arm = Derivation::new(s!("packages"), System::new(Arch::Arm, OS::Linux), s!("foo"));
+        assert_eq!("packages.x86_64-linux.foo", pin_name(&x86));
+        assert_eq!("packages.aarch64-linux.foo", pin_name(&arm));
+
+        let singular = Derivation::new(s!("defaultPackage"), System::x86_linux(), String::new());
+        assert_eq!("defaultPackage.x86_64-linux", pin_name(&singular));
+    }
+
+    #[rstest]
+    #[case("/path/to/flake", Some("/path/to/flake"))]
+    #[case("../flake", Some("../flake"))]
+    #[case(".", Some("."))]
+    #[case("github:me/repo", None)]
+    #[case("git+https://example.com/repo.git", None)]
+    #[case("nixpkgs", None)]
+    fn test_local_flake_dir(#[case] reference: &str, #[case] expected: Option<&str>) {
+        assert_eq!(expected.map(Path::new), local_flake_dir(reference));
+    }
+
+    #[test]
+    fn test_nondeterminism() {
+        let error = "'/bin/nix' exited with non-zero exit code 1: error: derivation \
+                     '/nix/store/abc-foo.drv' may not be deterministic: output \
+                     '/nix/store/def-foo' differs";
+        assert_eq!(
+            Some(
+                "derivation '/nix/store/abc-foo.drv' may not be deterministic: output \
+                 '/nix/store/def-foo' differs"
+            ),
+            nondeterminism(error)
+        );
+
+        let error = "'/bin/nix' exited with non-zero exit code 1: error: builder for \
+                     '/nix/store/abc-foo.drv' failed with exit code 2";
+        assert_eq!(None, nondeterminism(error));
+    }
+
+    #[rstest]
+    #[case("packages.x86_64-linux.foo")]
+    #[case(".#packages.x86_64-linux.foo")]
+    fn test_parse_target(#[case] input: &str) {
+        let expected = Derivation::new(s!("packages"), System::x86_linux(), s!("foo"));
+        assert_eq!(expected, Derivation::from_str(input).unwrap());
     }
 
     #[test]
@@ -496,7 +3412,11 @@ mod tests {
         let name = "foo";
         let check = mk_check(prefix, name);
         let drv = Derivation::new("packages".to_owned(), System::x86_linux(), name.to_owned());
-        assert!(check_checks_derivation(&check, &drv));
+        assert!(check_checks_derivation(
+            &check,
+            &drv,
+            &CheckNaming::default()
+        ));
     }
 
     #[test]
@@ -510,6 +3430,10 @@ mod tests {
             name.to_owned(),
         );
         //assert_eq!(check, drv);
-        assert!(!check_checks_derivation(&check, &drv));
+        assert!(!check_checks_derivation(
+            &check,
+            &drv,
+            &CheckNaming::default()
+        ));
     }
 }
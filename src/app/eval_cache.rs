@@ -0,0 +1,165 @@
+//! Attributes and drv paths from earlier runs, reused while the flake's locked revision and nix
+//! args stay the same
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entries {
+    /// What the entries were evaluated from. Entries for any other key are thrown out
+    key: String,
+    /// Attribute names, by `<output>.<system>`
+    attributes: BTreeMap<String, Vec<String>>,
+    /// Drv paths, by derivation
+    drv_paths: BTreeMap<String, String>,
+}
+
+/// An on-disk cache of eval results for one flake
+#[derive(Debug)]
+pub struct EvalCache {
+    path: PathBuf,
+    entries: Mutex<Entries>,
+    /// Something was added since it was loaded
+    changed: AtomicBool,
+}
+
+/// `$XDG_CACHE_HOME/flake-ci`, or `~/.cache/flake-ci`
+fn cache_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("flake-ci"))
+}
+
+impl EvalCache {
+    /// The cache for the flake in `flake_dir`, in the user's cache dir
+    pub fn for_flake(flake_dir: &Path, key: String) -> Option<Self> {
+        let mut hasher = DefaultHasher::new();
+        flake_dir.hash(&mut hasher);
+        let path = cache_dir()?.join(format!("eval-{:016x}.json", hasher.finish()));
+        Some(Self::open(path, key))
+    }
+
+    /// Load the cache at `path`. Anything unreadable or for another key starts it over empty
+    pub fn open(path: PathBuf, key: String) -> Self {
+        let entries = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Entries>(&contents).ok())
+            .filter(|entries| entries.key == key);
+        let entries = entries.unwrap_or_else(|| {
+            debug!("Starting a new eval cache at '{}'", path.display());
+            Entries {
+                key,
+                ..Entries::default()
+            }
+        });
+        Self {
+            path,
+            entries: Mutex::new(entries),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn attributes(&self, output: &str, system: &str) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .attributes
+            .get(&format!("{output}.{system}"))
+            .cloned()
+    }
+
+    pub fn insert_attributes(&self, output: &str, system: &str, attributes: Vec<String>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .attributes
+            .insert(format!("{output}.{system}"), attributes);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn drv_path(&self, derivation: &str) -> Option<String> {
+        self.entries
+            .lock()
+            .unwrap()
+            .drv_paths
+            .get(derivation)
+            .cloned()
+    }
+
+    pub fn insert_drv_path(&self, derivation: String, path: String) {
+        self.entries
+            .lock()
+            .unwrap()
+            .drv_paths
+            .insert(derivation, path);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the cache out, if anything was added
+    fn write(&self) -> Result<()> {
+        if !self.changed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = serde_json::to_string(&*self.entries.lock().unwrap())?;
+        fs::write(&self.path, contents)
+            .with_context(|| format!("Failed to write '{}'", self.path.display()))?;
+        self.changed.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Write the cache out. A cache that can't be written only costs the next run time, so
+    /// this warns instead of failing
+    pub fn save(&self) {
+        if let Err(e) = self.write() {
+            warn!("Failed to save the eval cache: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_round_trip_and_invalidate() {
+        let dir = TestDir::new("eval-cache");
+        let path = dir.join("eval.json");
+
+        let cache = EvalCache::open(path.clone(), "abc".to_owned());
+        assert_eq!(None, cache.attributes("packages", "x86_64-linux"));
+        cache.insert_attributes("packages", "x86_64-linux", vec!["foo".to_owned()]);
+        cache.insert_drv_path(
+            ".#packages.x86_64-linux.foo".to_owned(),
+            "/nix/store/foo.drv".to_owned(),
+        );
+        cache.save();
+
+        let cache = EvalCache::open(path.clone(), "abc".to_owned());
+        assert_eq!(
+            Some(vec!["foo".to_owned()]),
+            cache.attributes("packages", "x86_64-linux")
+        );
+        assert_eq!(
+            Some("/nix/store/foo.drv".to_owned()),
+            cache.drv_path(".#packages.x86_64-linux.foo")
+        );
+
+        // The lock changed
+        let cache = EvalCache::open(path, "def".to_owned());
+        assert_eq!(None, cache.drv_path(".#packages.x86_64-linux.foo"));
+    }
+}
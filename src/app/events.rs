@@ -0,0 +1,107 @@
+//! Newline delimited JSON events, written as builds start and finish so tools can follow along
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Something that happened during a run
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    BuildStart {
+        derivation: &'a str,
+    },
+    BuildFinish {
+        derivation: &'a str,
+        status: &'a str,
+        /// Seconds the build took
+        duration: f64,
+    },
+    Blocked {
+        derivation: &'a str,
+        pre_rec: &'a str,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub fn build_finish(derivation: &'a str, status: &'a str, duration: Duration) -> Self {
+        Self::BuildFinish {
+            derivation,
+            status,
+            duration: duration.as_secs_f64(),
+        }
+    }
+}
+
+/// Where events go, shared by every build worker
+pub struct Events {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events").finish_non_exhaustive()
+    }
+}
+
+impl Events {
+    /// Write events to a file, or to stdout for `-`
+    pub fn open(path: &Path) -> Result<Self> {
+        let out: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            let file = File::create(path)
+                .with_context(|| format!("Failed to create events file '{}'", path.display()))?;
+            Box::new(file)
+        };
+        Ok(Self {
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Write an event and flush it so it can be followed live. Failing to write shouldn't stop
+    /// the builds, so errors are only logged
+    pub fn emit(&self, event: &Event) {
+        let mut out = self.out.lock().unwrap();
+        let result = serde_json::to_string(event)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(out, "{line}"))
+            .and_then(|()| out.flush());
+        if let Err(e) = result {
+            warn!("Failed to write event: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_event_lines() {
+        let start = Event::BuildStart {
+            derivation: ".#packages.x86_64-linux.foo",
+        };
+        assert_eq!(
+            r#"{"event":"build_start","derivation":".#packages.x86_64-linux.foo"}"#,
+            serde_json::to_string(&start).unwrap()
+        );
+
+        let finish = Event::build_finish(
+            ".#packages.x86_64-linux.foo",
+            "success",
+            Duration::from_millis(1500),
+        );
+        assert_eq!(
+            r#"{"event":"build_finish","derivation":".#packages.x86_64-linux.foo","status":"success","duration":1.5}"#,
+            serde_json::to_string(&finish).unwrap()
+        );
+    }
+}
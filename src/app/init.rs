@@ -0,0 +1,120 @@
+//! `flake-ci init`: a starter config written from what the flake actually has
+
+use super::{find_tool, shown_systems, LEGACY_PACKAGES};
+use crate::config::{default_outputs, InvalidConfig};
+use crate::nix::run;
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+const HEADER: &str = "# Written by `flake-ci init`. Everything here can be changed\n\n";
+
+#[derive(Debug, Serialize)]
+struct Starter {
+    build: StarterBuild,
+}
+
+#[derive(Debug, Serialize)]
+struct StarterBuild {
+    outputs: Vec<String>,
+    systems: Vec<String>,
+    /// Left out when there are no packages, which saves everything
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifacts: Option<Vec<String>>,
+}
+
+/// A config building every output and system in `nix flake show --json` output, saving
+/// packages as artifacts
+fn starter_config(show: &serde_json::Value) -> Result<String> {
+    let outputs: Vec<String> = default_outputs()
+        .into_iter()
+        .filter(|output| show.get(output).is_some())
+        .collect();
+
+    let systems = shown_systems(show, &outputs);
+
+    let artifacts = outputs
+        .iter()
+        .any(|output| output == "packages")
+        .then(|| vec!["packages.*.*".to_owned()]);
+
+    let starter = Starter {
+        build: StarterBuild {
+            outputs,
+            systems: systems.iter().map(ToString::to_string).collect(),
+            artifacts,
+        },
+    };
+    Ok(format!("{HEADER}{}", toml::to_string(&starter)?))
+}
+
+/// Write a starter config for the flake in the current directory. An existing config is only
+/// replaced when forced
+pub fn init(config_file: &Path, nix: Option<&Path>, force: bool) -> Result<()> {
+    if config_file.exists() && !force {
+        bail!(InvalidConfig(format!(
+            "'{}' already exists. Pass --force to overwrite it",
+            config_file.display()
+        )));
+    }
+
+    let nix = find_tool("nix", nix)?;
+    let stdout = run(&nix, &["flake", "show", "--json"])?;
+    let show: serde_json::Value =
+        serde_json::from_str(&stdout).context("Failed to parse nix flake show output")?;
+
+    if show.get(LEGACY_PACKAGES).is_some() {
+        warn!(
+            "{LEGACY_PACKAGES} is left out, it's usually too big to build. Pick attributes \
+             with [build] legacy-packages to build some of it"
+        );
+    }
+
+    fs::write(config_file, starter_config(&show)?)
+        .with_context(|| format!("Failed to write '{}'", config_file.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_starter_config() {
+        let show = serde_json::json!({
+            "checks": {
+                "x86_64-linux": {"fmt": {"type": "derivation"}},
+                "aarch64-darwin": {"fmt": {"type": "derivation"}}
+            },
+            "packages": {
+                "x86_64-linux": {"default": {"type": "derivation"}}
+            },
+            "formatter": {
+                "x86_64-linux": {"type": "derivation"}
+            },
+            "nixosConfigurations": {
+                "box": {"type": "nixos-configuration"}
+            }
+        });
+        let expected = r#"# Written by `flake-ci init`. Everything here can be changed
+
+[build]
+outputs = ["checks", "packages", "nixosConfigurations"]
+systems = ["aarch64-darwin", "x86_64-linux"]
+artifacts = ["packages.*.*"]
+"#;
+        assert_eq!(expected, starter_config(&show).unwrap());
+    }
+
+    #[test]
+    fn test_no_packages_no_artifacts() {
+        let show = serde_json::json!({
+            "devShells": {"x86_64-linux": {"default": {"type": "derivation"}}}
+        });
+        let config = starter_config(&show).unwrap();
+        assert!(!config.contains("artifacts"));
+    }
+}
@@ -1,11 +1,36 @@
+use anyhow::Result;
 use owo_colors::{OwoColorize, Style};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io::Write as _;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use unicode_width::UnicodeWidthStr;
+
+mod format;
+mod github;
+mod junit;
+mod tap;
+pub use format::SummaryFormat;
 
 const INDENT: &str = "  ";
 const STATUS_PREFIX: &str = "> ";
 const SUBSTATUS_PREFIX: &str = "- ";
+const FLAKE_CHECK: &str = "nix flake check";
+const NO_SYSTEMS: &str = "systems";
+
+/// When the summary should use color
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Only when stdout supports it
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
 
 fn rel_to_cwd(p: &Path, cwd: &Path) -> String {
     let mut diff = pathdiff::diff_paths(p, cwd).unwrap().display().to_string();
@@ -15,25 +40,300 @@ fn rel_to_cwd(p: &Path, cwd: &Path) -> String {
     diff
 }
 
-fn register<T>(map: &mut HashMap<String, Vec<T>>, output_name: &str, job: T) {
-    if !map.contains_key(output_name) {
-        map.insert(output_name.to_string(), Vec::new());
+/// A note on jobs that needed more than one build
+fn attempts_note(attempts: u32) -> Option<String> {
+    (attempts > 1).then(|| format!("({attempts} attempts)"))
+}
+
+/// Why a job was blocked, naming the failure at the root of it
+fn block_note(job: &Block) -> String {
+    if job.pre_rec == job.root_cause {
+        format!("pre-rec '{}' failed", job.pre_rec)
+    } else {
+        format!("pre-rec '{}' blocked by '{}'", job.pre_rec, job.root_cause)
     }
-    map.get_mut(output_name).unwrap().push(job);
 }
 
-#[derive(Debug)]
+/// A note on failures that were allowed or retried
+fn fail_note(job: &Fail) -> Option<String> {
+    match (job.allowed, job.attempts) {
+        (false, attempts) => attempts_note(attempts),
+        (true, 1) => Some("(allowed)".to_owned()),
+        (true, attempts) => Some(format!("(allowed, {attempts} attempts)")),
+    }
+}
+
+/// A byte count in binary units, like "124 MiB"
+#[allow(clippy::cast_precision_loss)]
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 || size >= 10.0 {
+        format!("{size:.0} {}", UNITS[unit])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Everything is kept sorted so the summary doesn't depend on the order builds finished in
+fn insert_sorted<T: Ord>(v: &mut Vec<T>, item: T) {
+    let index = v.partition_point(|other| other < &item);
+    v.insert(index, item);
+}
+
+fn register<T: Ord>(map: &mut BTreeMap<String, Vec<T>>, output_name: &str, job: T) {
+    insert_sorted(map.entry(output_name.to_string()).or_default(), job);
+}
+
+/// How a check run on a success after building it went
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Verification {
+    Passed,
+    Failed(String),
+}
+
+impl Verification {
+    fn new(error: Option<String>) -> Self {
+        error.map_or(Self::Passed, Self::Failed)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct Success {
+    derivation: String,
+    /// The system it was built for
+    system: Option<String>,
+    artifacts: Vec<PathBuf>,
+    /// Was already in the store, so nothing was built
+    cached: bool,
+    /// Builds it took, counting retries
+    attempts: u32,
+    /// Caches the out paths were pushed to
+    pushed_to: Vec<String>,
+    /// Checked with `home-activation-check`
+    activation: Option<Verification>,
+    /// Run as configured by `[[smoke]]`
+    smoke: Option<Verification>,
+    /// Checked with `--check-reproducibility`
+    reproducibility: Option<Verification>,
+    /// Bytes in the closures of its out paths, added up. Looked up with `--sizes`
+    closure_size: Option<u64>,
+}
+
+impl Success {
+    /// What a check after the build found wrong, as a short status and why
+    fn problem(&self) -> Option<(&'static str, &str)> {
+        let verifications = [
+            ("activation failed", &self.activation),
+            ("smoke test failed", &self.smoke),
+            ("not reproducible", &self.reproducibility),
+        ];
+        verifications
+            .into_iter()
+            .find_map(|(status, verification)| match verification {
+                Some(Verification::Failed(error)) => Some((status, error.as_str())),
+                _ => None,
+            })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct Fail {
+    derivation: String,
+    /// The system it was built for
+    system: Option<String>,
+    log_command: Option<String>,
+    /// The build log, saved in the artifact dir
+    log_file: Option<PathBuf>,
+    error: Option<String>,
+    /// Configured with allow-failure, so it doesn't fail the run
+    allowed: bool,
+    /// Builds it took, counting retries
+    attempts: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct Skip {
+    derivation: String,
+    /// The system it was built for
+    system: Option<String>,
+    reason: String,
+}
+
+/// A required output that wasn't there to build
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct Missing {
+    output: String,
+    reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct Block {
+    derivation: String,
+    /// The system it was built for
+    system: Option<String>,
+    pre_rec: String,
+    /// The pre-rec that actually failed. Further up the chain than `pre_rec` when that was
+    /// blocked too
+    root_cause: String,
+}
+
+/// Where the repo the flake is in was at
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GitRevision {
+    /// Full commit hash
+    hash: String,
+    /// None on a detached HEAD
+    branch: Option<String>,
+    /// `git describe --tags`, like `v1.2.0` or `v1.2.0-3-gabc1234` for commits past it
+    describe: Option<String>,
+    /// There are uncommitted changes
+    dirty: bool,
+}
+
+impl GitRevision {
+    pub fn new(
+        hash: String,
+        branch: Option<String>,
+        describe: Option<String>,
+        dirty: bool,
+    ) -> Self {
+        Self {
+            hash,
+            branch,
+            describe,
+            dirty,
+        }
+    }
+
+    /// For a flake that isn't in a git repo
+    pub fn unknown() -> Self {
+        Self::new("unknown".to_owned(), None, None, false)
+    }
+}
+
+impl fmt::Display for GitRevision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Short like git shows it, the JSON has the full hash
+        write!(f, "{}", self.hash.get(..7).unwrap_or(&self.hash))?;
+        if let Some(branch) = &self.branch {
+            write!(f, " on {branch}")?;
+        }
+        if let Some(describe) = &self.describe {
+            write!(f, " at {describe}")?;
+        }
+        if self.dirty {
+            write!(f, " (dirty)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct Pin {
+    name: String,
+    store_path: String,
+    pinned: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Summary {
+    #[serde(skip)]
     cwd: PathBuf,
     skipped_outputs: Vec<String>,
-    successes: HashMap<String, Vec<(String, Option<PathBuf>)>>,
-    fails: HashMap<String, Vec<(String, String)>>,
-    skips: HashMap<String, Vec<String>>,
-    blocks: HashMap<String, Vec<(String, String)>>,
+    missing_outputs: Vec<Missing>,
+    successes: BTreeMap<String, Vec<Success>>,
+    fails: BTreeMap<String, Vec<Fail>>,
+    skips: BTreeMap<String, Vec<Skip>>,
+    blocks: BTreeMap<String, Vec<Block>>,
+    pins: Vec<Pin>,
+    /// How each system was built: natively, emulated or on remote builders
+    systems: BTreeMap<String, String>,
+    /// Configured systems this machine can't build
+    skipped_systems: Vec<String>,
+    /// Every configured system was skipped, and that's strictly a failure
+    no_systems_built: bool,
     nix_version: String,
     cachix_version: Option<String>,
-    git_revision: String,
+    git_revision: GitRevision,
+    /// What's wrong with flake.lock, if anything
+    flake_lock: Option<String>,
+    /// `nix flake check` ran first and failed
+    flake_check_failed: bool,
+    /// Anything else non-fatal that came up during the run
+    warnings: Vec<String>,
+    /// Locked revision of each flake input
+    inputs: BTreeMap<String, String>,
+    /// The system jobs are being registered for
+    #[serde(skip)]
+    system: Option<String>,
+    /// Print jobs under a header for each system instead of all together
+    #[serde(skip)]
+    group_by_system: bool,
+    #[serde(skip)]
     width: usize,
+    #[serde(skip)]
+    color: ColorChoice,
+}
+
+/// A registered job, borrowed from whichever map it was registered in
+#[derive(Debug)]
+enum Outcome<'a> {
+    Success(&'a Success),
+    Fail(&'a Fail),
+    Skip(&'a Skip),
+    Block(&'a Block),
+}
+
+impl<'a> Outcome<'a> {
+    fn derivation(&self) -> &'a str {
+        match self {
+            Self::Success(job) => &job.derivation,
+            Self::Fail(job) => &job.derivation,
+            Self::Skip(job) => &job.derivation,
+            Self::Block(job) => &job.derivation,
+        }
+    }
+
+    /// Counts as a failure in reports that only know pass and fail
+    fn failed(&self) -> bool {
+        match self {
+            Self::Success(job) => job.problem().is_some(),
+            Self::Fail(_) => true,
+            Self::Skip(_) | Self::Block(_) => false,
+        }
+    }
+
+    fn system(&self) -> Option<&'a str> {
+        match self {
+            Self::Success(job) => job.system.as_deref(),
+            Self::Fail(job) => job.system.as_deref(),
+            Self::Skip(job) => job.system.as_deref(),
+            Self::Block(job) => job.system.as_deref(),
+        }
+    }
+}
+
+/// The document emitted by `--json`
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    succeeded: bool,
+    #[serde(flatten)]
+    summary: &'a Summary,
+}
+
+/// A document emitted by `--json`, read back in
+#[derive(Debug, Deserialize)]
+struct LoadedReport {
+    succeeded: bool,
+    #[serde(flatten)]
+    summary: Summary,
 }
 
 impl Summary {
@@ -41,62 +341,450 @@ impl Summary {
         cwd: PathBuf,
         nix_version: String,
         cachix_version: Option<String>,
-        git_revision: String,
+        git_revision: GitRevision,
         width: usize,
     ) -> Self {
         Self {
             cwd,
             skipped_outputs: Vec::new(),
-            successes: HashMap::new(),
-            fails: HashMap::new(),
-            skips: HashMap::new(),
-            blocks: HashMap::new(),
+            missing_outputs: Vec::new(),
+            successes: BTreeMap::new(),
+            fails: BTreeMap::new(),
+            skips: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            pins: Vec::new(),
+            systems: BTreeMap::new(),
+            skipped_systems: Vec::new(),
+            no_systems_built: false,
             nix_version,
             git_revision,
             cachix_version,
+            flake_lock: None,
+            flake_check_failed: false,
+            warnings: Vec::new(),
+            inputs: BTreeMap::new(),
+            system: None,
+            group_by_system: false,
             width,
+            color: ColorChoice::Auto,
+        }
+    }
+
+    /// Every job grouped by output, sorted by derivation
+    fn by_output(&self) -> BTreeMap<&str, Vec<Outcome<'_>>> {
+        let mut outputs: BTreeMap<&str, Vec<Outcome<'_>>> = BTreeMap::new();
+        for (output, jobs) in &self.successes {
+            let entry = outputs.entry(output).or_default();
+            entry.extend(jobs.iter().map(Outcome::Success));
+        }
+        for (output, jobs) in &self.fails {
+            let entry = outputs.entry(output).or_default();
+            entry.extend(jobs.iter().map(Outcome::Fail));
+        }
+        for (output, jobs) in &self.skips {
+            let entry = outputs.entry(output).or_default();
+            entry.extend(jobs.iter().map(Outcome::Skip));
+        }
+        for (output, jobs) in &self.blocks {
+            let entry = outputs.entry(output).or_default();
+            entry.extend(jobs.iter().map(Outcome::Block));
+        }
+        for jobs in outputs.values_mut() {
+            jobs.sort_by(|a, b| a.derivation().cmp(b.derivation()));
         }
+        outputs
+    }
+
+    /// Every job grouped by system, then by output. Jobs registered before any system come first
+    fn by_system(&self) -> BTreeMap<Option<&str>, BTreeMap<&str, Vec<Outcome<'_>>>> {
+        let mut systems: BTreeMap<Option<&str>, BTreeMap<&str, Vec<Outcome<'_>>>> = BTreeMap::new();
+        for (output, jobs) in self.by_output() {
+            for job in jobs {
+                let outputs = systems.entry(job.system()).or_default();
+                outputs.entry(output).or_default().push(job);
+            }
+        }
+        systems
+    }
+
+    /// How many successes were actually built and how many were already in the store
+    fn built_and_cached(&self) -> (usize, usize) {
+        let (cached, built): (Vec<&Success>, Vec<&Success>) = self
+            .successes
+            .values()
+            .flatten()
+            .partition(|job| job.cached);
+        (built.len(), cached.len())
+    }
+
+    /// Nothing failed
+    pub fn passed(&self) -> bool {
+        self.missing_outputs.is_empty()
+            && !self.flake_check_failed
+            && !self.no_systems_built
+            && self.fails.values().flatten().all(|job| job.allowed)
+            && self.blocks.is_empty()
+            && self
+                .successes
+                .values()
+                .flatten()
+                .all(|job| job.problem().is_none())
+            && self.pins.iter().all(|pin| pin.pinned)
+    }
+
+    /// One line counting every outcome
+    fn tally(&self) -> String {
+        let (built, cached) = self.built_and_cached();
+        format!(
+            "{} succeeded ({built} built, {cached} cached), {} failed, {} skipped, {} blocked",
+            built + cached,
+            self.fails.values().map(Vec::len).sum::<usize>(),
+            self.skips.values().map(Vec::len).sum::<usize>(),
+            self.blocks.values().map(Vec::len).sum::<usize>(),
+        )
+    }
+
+    /// One line counting the outcomes for a system's derivations
+    fn system_tally(&self, system: &str) -> String {
+        let count = |f: fn(&Outcome) -> bool| -> usize {
+            self.by_output()
+                .values()
+                .flatten()
+                .filter(|job| job.system() == Some(system) && f(job))
+                .count()
+        };
+        format!(
+            "{} succeeded, {} failed, {} skipped, {} blocked",
+            count(|job| matches!(job, Outcome::Success(_))),
+            count(|job| matches!(job, Outcome::Fail(_))),
+            count(|job| matches!(job, Outcome::Skip(_))),
+            count(|job| matches!(job, Outcome::Block(_))),
+        )
+    }
+
+    pub fn set_color(&mut self, color: ColorChoice) {
+        self.color = color;
+    }
+
+    pub fn set_group_by_system(&mut self, group_by_system: bool) {
+        self.group_by_system = group_by_system;
+    }
+
+    /// Apply a style to text, if colors are on
+    fn paint(&self, text: &str, style: &Style) -> String {
+        match self.color {
+            ColorChoice::Auto => text
+                .if_supports_color(owo_colors::Stream::Stdout, |text| text.style(*style))
+                .to_string(),
+            ColorChoice::Always => text.style(*style).to_string(),
+            ColorChoice::Never => text.to_owned(),
+        }
+    }
+
+    /// Append the summary to the GitHub Actions job summary file
+    pub fn write_github_step_summary(&self, path: &Path) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        file.write_all(self.github_markdown().as_bytes())?;
+        Ok(())
     }
 
     pub fn skip_output(&mut self, output: &str) {
-        self.skipped_outputs.push(output.to_string());
+        insert_sorted(&mut self.skipped_outputs, output.to_string());
+    }
+
+    /// A required output was missing or empty, which fails the run
+    pub fn register_missing_output(&mut self, output: &str, reason: String) {
+        let missing = Missing {
+            output: output.to_owned(),
+            reason,
+        };
+        insert_sorted(&mut self.missing_outputs, missing);
+    }
+
+    /// Whether any required output was missing
+    pub fn missing_required_output(&self) -> bool {
+        !self.missing_outputs.is_empty()
     }
 
     pub fn register_success(
         &mut self,
         output_name: &str,
         job_name: String,
-        artifact: Option<PathBuf>,
+        artifacts: Vec<PathBuf>,
+    ) {
+        let job = Success {
+            derivation: job_name,
+            system: self.system.clone(),
+            artifacts,
+            cached: false,
+            attempts: 1,
+            pushed_to: Vec::new(),
+            activation: None,
+            smoke: None,
+            reproducibility: None,
+            closure_size: None,
+        };
+        register(&mut self.successes, output_name, job);
+    }
+
+    /// Register a job whose outputs were already in the store
+    pub fn register_cached(
+        &mut self,
+        output_name: &str,
+        job_name: String,
+        artifacts: Vec<PathBuf>,
     ) {
-        register(&mut self.successes, output_name, (job_name, artifact));
+        let job = Success {
+            derivation: job_name,
+            system: self.system.clone(),
+            artifacts,
+            cached: true,
+            attempts: 1,
+            pushed_to: Vec::new(),
+            activation: None,
+            smoke: None,
+            reproducibility: None,
+            closure_size: None,
+        };
+        register(&mut self.successes, output_name, job);
     }
 
     pub fn register_fail(&mut self, output_name: &str, job_name: String, log_command: String) {
-        register(&mut self.fails, output_name, (job_name, log_command));
+        let job = Fail {
+            derivation: job_name,
+            system: self.system.clone(),
+            log_command: Some(log_command),
+            log_file: None,
+            error: None,
+            allowed: false,
+            attempts: 1,
+        };
+        register(&mut self.fails, output_name, job);
+    }
+
+    /// Register a build that was killed for taking too long
+    pub fn register_timeout(
+        &mut self,
+        output_name: &str,
+        job_name: String,
+        log_command: String,
+        timeout: Duration,
+    ) {
+        let error = format!("timed out after {}s", timeout.as_secs());
+        self.register_fail_with_error(output_name, job_name, log_command, error);
+    }
+
+    /// Register a failed build along with what probably caused it
+    pub fn register_fail_with_error(
+        &mut self,
+        output_name: &str,
+        job_name: String,
+        log_command: String,
+        error: String,
+    ) {
+        let job = Fail {
+            derivation: job_name,
+            system: self.system.clone(),
+            log_command: Some(log_command),
+            log_file: None,
+            error: Some(error),
+            allowed: false,
+            attempts: 1,
+        };
+        register(&mut self.fails, output_name, job);
+    }
+
+    /// Register a job that errored out before nix could report a build failure
+    pub fn register_error(&mut self, output_name: &str, job_name: String, error: String) {
+        let job = Fail {
+            derivation: job_name,
+            system: self.system.clone(),
+            log_command: None,
+            log_file: None,
+            error: Some(error),
+            allowed: false,
+            attempts: 1,
+        };
+        register(&mut self.fails, output_name, job);
+    }
+
+    /// Record where a registered failure's build log was saved
+    pub fn record_log_file(&mut self, output_name: &str, job_name: &str, log_file: PathBuf) {
+        let mut fails = self.fails.get_mut(output_name).into_iter().flatten();
+        if let Some(job) = fails.find(|job| job.derivation == job_name) {
+            job.log_file = Some(log_file);
+        }
+    }
+
+    pub fn register_skip(&mut self, output_name: &str, job_name: String, reason: String) {
+        let job = Skip {
+            derivation: job_name,
+            system: self.system.clone(),
+            reason,
+        };
+        register(&mut self.skips, output_name, job);
+    }
+
+    /// Record how many builds a registered job took, if it was retried
+    pub fn record_attempts(&mut self, output_name: &str, job_name: &str, attempts: u32) {
+        let successes = self.successes.get_mut(output_name).into_iter().flatten();
+        for job in successes.filter(|job| job.derivation == job_name) {
+            job.attempts = attempts;
+        }
+        let fails = self.fails.get_mut(output_name).into_iter().flatten();
+        for job in fails.filter(|job| job.derivation == job_name) {
+            job.attempts = attempts;
+        }
+    }
+
+    /// Record which caches a registered success was pushed to
+    pub fn record_pushes(&mut self, output_name: &str, job_name: &str, caches: Vec<String>) {
+        let mut successes = self.successes.get_mut(output_name).into_iter().flatten();
+        if let Some(job) = successes.find(|job| job.derivation == job_name) {
+            job.pushed_to = caches;
+        }
+    }
+
+    /// Record how dry running a registered success's activation script went. `error` is why it
+    /// failed
+    pub fn record_activation(&mut self, output_name: &str, job_name: &str, error: Option<String>) {
+        let mut successes = self.successes.get_mut(output_name).into_iter().flatten();
+        if let Some(job) = successes.find(|job| job.derivation == job_name) {
+            job.activation = Some(Verification::new(error));
+        }
+    }
+
+    /// Record how smoke testing a registered success went. `error` is why it failed
+    pub fn record_smoke(&mut self, output_name: &str, job_name: &str, error: Option<String>) {
+        let mut successes = self.successes.get_mut(output_name).into_iter().flatten();
+        if let Some(job) = successes.find(|job| job.derivation == job_name) {
+            job.smoke = Some(Verification::new(error));
+        }
+    }
+
+    /// Record whether rebuilding a registered success gave the same outputs. `error` is how they
+    /// differed
+    pub fn record_reproducibility(
+        &mut self,
+        output_name: &str,
+        job_name: &str,
+        error: Option<String>,
+    ) {
+        let mut successes = self.successes.get_mut(output_name).into_iter().flatten();
+        if let Some(job) = successes.find(|job| job.derivation == job_name) {
+            job.reproducibility = Some(Verification::new(error));
+        }
+    }
+
+    /// Record how big a registered success's closure is, in bytes
+    pub fn record_closure_size(&mut self, output_name: &str, job_name: &str, bytes: u64) {
+        let mut successes = self.successes.get_mut(output_name).into_iter().flatten();
+        if let Some(job) = successes.find(|job| job.derivation == job_name) {
+            job.closure_size = Some(bytes);
+        }
+    }
+
+    /// Mark a registered failure as allowed by allow-failure
+    pub fn allow_failure(&mut self, output_name: &str, job_name: &str) {
+        let jobs = self.fails.get_mut(output_name).into_iter().flatten();
+        for job in jobs.filter(|job| job.derivation == job_name) {
+            job.allowed = true;
+        }
+    }
+
+    /// Register a job that wasn't built because a pre-rec failed. `root_cause` is the one that
+    /// actually failed, which is `pre_rec` unless that was blocked too
+    pub fn register_blocked(
+        &mut self,
+        output_name: &str,
+        job_name: String,
+        pre_rec: String,
+        root_cause: String,
+    ) {
+        let job = Block {
+            derivation: job_name,
+            system: self.system.clone(),
+            pre_rec,
+            root_cause,
+        };
+        register(&mut self.blocks, output_name, job);
     }
 
-    pub fn register_skip(&mut self, output_name: &str, job_name: String) {
-        register(&mut self.skips, output_name, job_name);
+    /// Record that `nix flake check` failed
+    pub fn fail_flake_check(&mut self) {
+        self.flake_check_failed = true;
     }
 
-    pub fn register_blocked(&mut self, output_name: &str, job_name: String, pre_rec: String) {
-        register(&mut self.blocks, output_name, (job_name, pre_rec));
+    /// Warn about a missing or stale flake.lock
+    pub fn warn_flake_lock(&mut self, problem: String) {
+        self.flake_lock = Some(problem);
     }
 
-    fn print_line(left: &str, right: &str, style: Option<&Style>, extra_note: Option<&str>) {
+    /// Record something non-fatal worth seeing at the end of the run
+    pub fn warn(&mut self, warning: String) {
+        if !self.warnings.contains(&warning) {
+            insert_sorted(&mut self.warnings, warning);
+        }
+    }
+
+    pub fn set_inputs(&mut self, inputs: BTreeMap<String, String>) {
+        self.inputs = inputs;
+    }
+
+    /// Jobs registered from here on belong to this system
+    pub fn set_system(&mut self, system: String) {
+        self.system = Some(system);
+    }
+
+    /// Note a configured system that couldn't be built here
+    pub fn skip_system(&mut self, system: String) {
+        insert_sorted(&mut self.skipped_systems, system);
+    }
+
+    /// Record that no configured system could be built, which fails a strict run
+    pub fn fail_no_systems(&mut self) {
+        self.no_systems_built = true;
+    }
+
+    /// Note a system that got built, and how
+    pub fn register_system(&mut self, system: String, builder: &str) {
+        self.systems.insert(system, builder.to_owned());
+    }
+
+    pub fn register_pin(&mut self, name: String, store_path: String, pinned: bool) {
+        let pin = Pin {
+            name,
+            store_path,
+            pinned,
+        };
+        insert_sorted(&mut self.pins, pin);
+    }
+
+    fn print_line(
+        &self,
+        out: &mut String,
+        left: &str,
+        right: &str,
+        style: Option<&Style>,
+        extra_note: Option<&str>,
+    ) -> fmt::Result {
         let extra_note = match extra_note {
             Some(note) => &format!(" {note}"),
             None => "",
         };
 
-        let used_space = left.len() + right.len() + extra_note.len();
+        // Size by display width so multibyte names still line up
+        let used_space = left.width() + right.width() + extra_note.width();
         //assert!(, "Line too big");
 
         let dots = if right.is_empty() {
             String::new()
         } else {
-            let n = if used_space < crate::MAX_WIDTH {
-                crate::MAX_WIDTH - used_space
+            let n = if used_space < self.width {
+                self.width - used_space
             } else {
                 10
             };
@@ -105,98 +793,927 @@ impl Summary {
 
         match style {
             Some(style) => {
-                println!(
-                    "{left}{dots}{}{extra_note}",
-                    right.if_supports_color(owo_colors::Stream::Stdout, |text| text.style(*style)),
-                );
+                writeln!(out, "{left}{dots}{}{extra_note}", self.paint(right, style))?;
             }
             None => {
-                println!("{left}{dots}{right}{extra_note}");
+                writeln!(out, "{left}{dots}{right}{extra_note}")?;
             }
-        };
+        }
+        Ok(())
     }
 
-    fn print_status_line(left: &str, right: &str, style: Option<&Style>, extra_note: Option<&str>) {
+    fn print_status_line(
+        &self,
+        out: &mut String,
+        left: &str,
+        right: &str,
+        style: Option<&Style>,
+        extra_note: Option<&str>,
+    ) -> fmt::Result {
         assert_eq!(STATUS_PREFIX.len(), INDENT.len());
         let left = format!("{STATUS_PREFIX}{left}");
-        Summary::print_line(&left, right, style, extra_note);
+        self.print_line(out, &left, right, style, extra_note)
     }
 
-    fn print_substatus_line(left: &str, right: &str, style: &Style, extra_note: Option<&str>) {
+    fn print_substatus_line(
+        &self,
+        out: &mut String,
+        left: &str,
+        right: &str,
+        style: &Style,
+        extra_note: Option<&str>,
+    ) -> fmt::Result {
         assert_eq!(SUBSTATUS_PREFIX.len(), INDENT.len());
         let left = format!("{INDENT}{SUBSTATUS_PREFIX}{left}");
-        Summary::print_line(&left, right, Some(style), extra_note);
+        self.print_line(out, &left, right, Some(style), extra_note)
     }
 
-    fn print_substatus_attribute(name: &str, attribute: &str) {
-        println!("{INDENT}{INDENT}{name}: {attribute}");
+    fn print_substatus_attribute(out: &mut String, name: &str, attribute: &str) -> fmt::Result {
+        writeln!(out, "{INDENT}{INDENT}{name}: {attribute}")
     }
 
-    fn print_version(slug: &str, version: &str) {
-        println!(
-            "{slug}: {}",
-            version.if_supports_color(owo_colors::Stream::Stdout, |text| text.bold())
-        );
+    fn print_version(&self, out: &mut String, slug: &str, version: &str) -> fmt::Result {
+        writeln!(out, "{slug}: {}", self.paint(version, &Style::new().bold()))
     }
 
-    pub fn print(&self) {
+    fn write(&self, out: &mut String, succeeded: bool) -> fmt::Result {
         let yellow = Style::new().yellow().bold();
         let green = Style::new().green().bold();
         let red = Style::new().red().bold();
 
         let bar = "=".repeat(self.width);
-        println!("{bar}");
-        println!("Summary");
-
-        // TODO: I think I'd rather mix failed/skipped/passed output and print by top_level instead
+        writeln!(out, "{bar}")?;
+        writeln!(out, "Summary")?;
 
+        if let Some(problem) = &self.flake_lock {
+            let note = format!("({problem})");
+            self.print_status_line(out, "flake.lock", "warning", Some(&yellow), Some(&note))?;
+        }
+        if self.flake_check_failed {
+            self.print_status_line(out, FLAKE_CHECK, "failed", Some(&red), None)?;
+        }
+        for system in &self.skipped_systems {
+            let note = "(can't build here)";
+            self.print_status_line(out, system, "skipped", Some(&yellow), Some(note))?;
+        }
+        if self.no_systems_built {
+            let note = "(none of the configured systems can build here)";
+            self.print_status_line(out, NO_SYSTEMS, "failed", Some(&red), Some(note))?;
+        }
         for output in &self.skipped_outputs {
-            Summary::print_status_line(output, "skipped", Some(&yellow), Some("(not found)"));
+            self.print_status_line(out, output, "skipped", Some(&yellow), Some("(not found)"))?;
+        }
+        for missing in &self.missing_outputs {
+            let note = format!("(required, {})", missing.reason);
+            self.print_status_line(out, &missing.output, "failed", Some(&red), Some(&note))?;
         }
 
-        for (output, jobs) in &self.successes {
-            Summary::print_status_line(output, "", None, None);
-            for (job_name, artifact) in jobs {
-                Summary::print_substatus_line(job_name, "success", &green, None);
-
-                if let Some(artifact) = artifact {
-                    let artifact = rel_to_cwd(artifact, &self.cwd);
-                    Summary::print_substatus_attribute("artifact", &artifact);
+        if self.group_by_system {
+            for (system, outputs) in self.by_system() {
+                if let Some(system) = system {
+                    writeln!(out, "{}", self.paint(system, &Style::new().bold()))?;
                 }
+                self.write_outputs(out, outputs)?;
             }
+        } else {
+            self.write_outputs(out, self.by_output())?;
         }
 
-        for (output, jobs) in &self.skips {
-            Summary::print_status_line(output, "", None, None);
-            for job in jobs {
-                Summary::print_substatus_line(job, "skipped", &yellow, Some("(dry run)"));
+        if !self.pins.is_empty() {
+            self.print_status_line(out, "pins", "", None, None)?;
+            for pin in &self.pins {
+                let (status, style) = if pin.pinned {
+                    ("pinned", &green)
+                } else {
+                    ("failed", &red)
+                };
+                self.print_substatus_line(out, &pin.name, status, style, None)?;
+                Summary::print_substatus_attribute(out, "store path", &pin.store_path)?;
             }
         }
 
-        for (output, jobs) in &self.blocks {
-            Summary::print_status_line(output, "", None, None);
-            for (job, pre_rec) in jobs {
-                Summary::print_substatus_line(
-                    job,
-                    "skipped",
-                    &yellow,
-                    Some(&format!("(pre-rec '{pre_rec}' failed)")),
-                );
+        if !self.warnings.is_empty() {
+            writeln!(out, "{}", self.paint("Warnings", &yellow))?;
+            for warning in &self.warnings {
+                writeln!(out, "{INDENT}{SUBSTATUS_PREFIX}{warning}")?;
             }
         }
 
-        for (output, jobs) in &self.fails {
-            println!("> {output}");
-            for (job, log_command) in jobs {
-                Summary::print_substatus_line(job, "failed", &red, None);
-                Summary::print_substatus_attribute("log command", log_command);
+        // Only worth breaking down when more than one system was built
+        if self.systems.len() > 1 {
+            for (system, builder) in &self.systems {
+                writeln!(out, "{system} ({builder}): {}", self.system_tally(system))?;
+            }
+        }
+        writeln!(out, "{}", self.tally())?;
+        if succeeded {
+            writeln!(out, "{}", self.paint("PASS", &green))?;
+        } else {
+            writeln!(out, "{}", self.paint("FAIL", &red))?;
+        }
+
+        self.write_versions(out)
+    }
+
+    /// Each output's status line followed by its jobs
+    fn write_outputs(
+        &self,
+        out: &mut String,
+        outputs: BTreeMap<&str, Vec<Outcome>>,
+    ) -> fmt::Result {
+        for (output, jobs) in outputs {
+            self.print_status_line(out, output, "", None, None)?;
+            for job in jobs {
+                self.write_job(out, &job)?;
             }
         }
+        Ok(())
+    }
+
+    /// A job's status line and whatever else is worth knowing about it
+    fn write_job(&self, out: &mut String, job: &Outcome) -> fmt::Result {
+        let yellow = Style::new().yellow().bold();
+        match job {
+            Outcome::Success(job) => {
+                let (status, style) = if job.cached {
+                    ("cached", Style::new().cyan().bold())
+                } else {
+                    ("success", Style::new().green().bold())
+                };
+                let note = attempts_note(job.attempts);
+                self.print_substatus_line(out, &job.derivation, status, &style, note.as_deref())?;
+                for artifact in &job.artifacts {
+                    let artifact = rel_to_cwd(artifact, &self.cwd);
+                    Summary::print_substatus_attribute(out, "artifact", &artifact)?;
+                }
+                if !job.pushed_to.is_empty() {
+                    Summary::print_substatus_attribute(
+                        out,
+                        "pushed to",
+                        &job.pushed_to.join(", "),
+                    )?;
+                }
+                if let Some(bytes) = job.closure_size {
+                    Summary::print_substatus_attribute(out, "closure size", &human_size(bytes))?;
+                }
+                let verifications = [
+                    ("activation", &job.activation),
+                    ("smoke test", &job.smoke),
+                    ("reproducibility", &job.reproducibility),
+                ];
+                for (name, verification) in verifications {
+                    match verification {
+                        Some(Verification::Passed) => {
+                            Summary::print_substatus_attribute(out, name, "passed")?;
+                        }
+                        Some(Verification::Failed(error)) => {
+                            let failed = self.paint("failed", &Style::new().red().bold());
+                            Summary::print_substatus_attribute(out, name, &failed)?;
+                            Summary::print_substatus_attribute(out, "error", error)?;
+                        }
+                        None => {}
+                    }
+                }
+            }
+            Outcome::Skip(job) => {
+                let note = format!("({})", job.reason);
+                self.print_substatus_line(out, &job.derivation, "skipped", &yellow, Some(&note))?;
+            }
+            Outcome::Block(job) => {
+                let note = format!("({})", block_note(job));
+                self.print_substatus_line(out, &job.derivation, "skipped", &yellow, Some(&note))?;
+            }
+            Outcome::Fail(job) => {
+                let style = if job.allowed {
+                    Style::new().magenta().bold()
+                } else {
+                    Style::new().red().bold()
+                };
+                let note = fail_note(job);
+                self.print_substatus_line(out, &job.derivation, "failed", &style, note.as_deref())?;
+                if let Some(log_command) = &job.log_command {
+                    Summary::print_substatus_attribute(out, "log command", log_command)?;
+                }
+                if let Some(log_file) = &job.log_file {
+                    let log_file = rel_to_cwd(log_file, &self.cwd);
+                    Summary::print_substatus_attribute(out, "log file", &log_file)?;
+                }
+                if let Some(error) = &job.error {
+                    Summary::print_substatus_attribute(out, "error", error)?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        Summary::print_version("Git revision", &self.git_revision);
-        Summary::print_version("Nix version:", &self.nix_version);
+    /// Versions of everything that went into the run
+    fn write_versions(&self, out: &mut String) -> fmt::Result {
+        self.print_version(out, "Git revision", &self.git_revision.to_string())?;
+        self.print_version(out, "Nix version:", &self.nix_version)?;
         if let Some(cachix_version) = &self.cachix_version {
-            Summary::print_version("Cachix version", cachix_version);
+            self.print_version(out, "Cachix version", cachix_version)?;
+        }
+        for (input, revision) in &self.inputs {
+            self.print_version(out, &format!("Input {input}"), revision)?;
+        }
+        Ok(())
+    }
+
+    /// The summary as a human readable table, ending in PASS or FAIL as the summary itself says
+    pub fn render(&self) -> String {
+        self.human(self.passed())
+    }
+
+    /// The summary as a human readable table, ending in PASS or FAIL by how the run went, which
+    /// is a failure when it was interrupted however clean the summary looks
+    fn human(&self, succeeded: bool) -> String {
+        let mut out = String::new();
+        self.write(&mut out, succeeded)
+            .expect("Writing to a String can't fail");
+        out
+    }
+
+    /// The summary as the json document printed by `--json`
+    pub fn json(&self, succeeded: bool) -> Result<String> {
+        let report = Report {
+            succeeded,
+            summary: self,
         };
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+
+    /// Read back a summary printed by `--json`, and whether that run succeeded. Artifact paths
+    /// are shown relative to `cwd`
+    pub fn from_json(json: &str, cwd: PathBuf, width: usize) -> Result<(Self, bool)> {
+        let LoadedReport {
+            succeeded,
+            mut summary,
+        } = serde_json::from_str(json)?;
+        summary.cwd = cwd;
+        summary.width = width;
+        Ok((summary, succeeded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    fn mk_summary() -> Summary {
+        Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            80,
+        )
+    }
+
+    #[test]
+    fn test_json_report() {
+        let mut summary = mk_summary();
+        summary.set_system("x86_64-linux".to_owned());
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            vec![PathBuf::from("/tmp/dist/foo")],
+        );
+        summary.register_fail(
+            "checks",
+            ".#checks.x86_64-linux.pkg-bar".to_owned(),
+            "`nix log /nix/store/bar.drv`".to_owned(),
+        );
+        summary.register_blocked(
+            "packages",
+            ".#packages.x86_64-linux.bar".to_owned(),
+            ".#checks.x86_64-linux.pkg-bar".to_owned(),
+            ".#checks.x86_64-linux.pkg-bar".to_owned(),
+        );
+
+        let actual: serde_json::Value =
+            serde_json::from_str(&summary.json(false).unwrap()).unwrap();
+        let expected = serde_json::json!({
+            "succeeded": false,
+            "skipped_outputs": [],
+            "missing_outputs": [],
+            "successes": {
+                "packages": [
+                    {
+                    "derivation": ".#packages.x86_64-linux.foo",
+                    "system": "x86_64-linux",
+                    "artifacts": ["/tmp/dist/foo"],
+                    "cached": false,
+                    "attempts": 1,
+                    "pushed_to": [],
+                    "activation": null,
+                    "smoke": null,
+                    "reproducibility": null,
+                    "closure_size": null
+                }
+                ]
+            },
+            "fails": {
+                "checks": [
+                    {
+                        "derivation": ".#checks.x86_64-linux.pkg-bar",
+                        "system": "x86_64-linux",
+                        "log_command": "`nix log /nix/store/bar.drv`",
+                        "log_file": null,
+                        "error": null,
+                        "allowed": false,
+                        "attempts": 1
+                    }
+                ]
+            },
+            "skips": {},
+            "blocks": {
+                "packages": [
+                    {
+                        "derivation": ".#packages.x86_64-linux.bar",
+                        "system": "x86_64-linux",
+                        "pre_rec": ".#checks.x86_64-linux.pkg-bar",
+                        "root_cause": ".#checks.x86_64-linux.pkg-bar"
+                    }
+                ]
+            },
+            "pins": [],
+            "systems": {},
+            "skipped_systems": [],
+            "no_systems_built": false,
+            "nix_version": "nix (Nix) 2.24.12",
+            "cachix_version": null,
+            "git_revision": {"hash": "abc1234", "branch": null, "describe": null, "dirty": false},
+            "flake_lock": null,
+            "flake_check_failed": false,
+            "warnings": [],
+            "inputs": {},
+        });
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_log_file() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_fail(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            "`nix log /nix/store/foo.drv`".to_owned(),
+        );
+        summary.record_log_file(
+            "packages",
+            ".#packages.x86_64-linux.foo",
+            PathBuf::from("/tmp/dist/logs/packages.x86_64-linux.foo.log"),
+        );
+        assert!(summary.render().contains(
+            "log command: `nix log /nix/store/foo.drv`\n    log file: ./dist/logs/packages.x86_64-linux.foo.log"
+        ));
+    }
+
+    #[test]
+    fn test_color_choice() {
+        let mut summary = mk_summary();
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+
+        summary.set_color(ColorChoice::Never);
+        assert!(!summary.render().contains('\x1b'));
+
+        summary.set_color(ColorChoice::Always);
+        assert!(summary.render().contains('\x1b'));
+    }
+
+    #[test]
+    fn test_cached() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.register_cached(
+            "packages",
+            ".#packages.x86_64-linux.bar".to_owned(),
+            Vec::new(),
+        );
+
+        assert_eq!((1, 1), summary.built_and_cached());
+        let rendered = summary.render();
+        let bar = rendered.lines().find(|line| line.contains("bar")).unwrap();
+        assert!(bar.ends_with("cached"), "{bar}");
+    }
+
+    #[test]
+    fn test_tally() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.register_cached(
+            "packages",
+            ".#packages.x86_64-linux.bar".to_owned(),
+            Vec::new(),
+        );
+        summary.register_skip(
+            "devShells",
+            ".#devShells.x86_64-linux.default".to_owned(),
+            "dry run".to_owned(),
+        );
+        let rendered = summary.render();
+        assert!(rendered
+            .contains("2 succeeded (1 built, 1 cached), 0 failed, 1 skipped, 0 blocked\nPASS\n"));
+
+        summary.register_fail(
+            "checks",
+            ".#checks.x86_64-linux.pkg-baz".to_owned(),
+            "`nix log /nix/store/baz.drv`".to_owned(),
+        );
+        summary.register_blocked(
+            "packages",
+            ".#packages.x86_64-linux.baz".to_owned(),
+            ".#checks.x86_64-linux.pkg-baz".to_owned(),
+            ".#checks.x86_64-linux.pkg-baz".to_owned(),
+        );
+        let rendered = summary.render();
+        assert!(rendered
+            .contains("2 succeeded (1 built, 1 cached), 1 failed, 1 skipped, 1 blocked\nFAIL\n"));
+    }
+
+    #[test]
+    fn test_system_tally() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.set_system("x86_64-linux".to_owned());
+        summary.register_system("x86_64-linux".to_owned(), "native");
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        assert!(!summary.render().contains("(native)"));
+
+        summary.set_system("aarch64-linux".to_owned());
+        summary.register_system("aarch64-linux".to_owned(), "remote builders");
+        summary.register_fail(
+            "packages",
+            ".#packages.aarch64-linux.foo".to_owned(),
+            "`nix log /nix/store/foo.drv`".to_owned(),
+        );
+        let rendered = summary.render();
+        assert!(rendered.contains(
+            "aarch64-linux (remote builders): 0 succeeded, 1 failed, 0 skipped, 0 blocked\n\
+             x86_64-linux (native): 1 succeeded, 0 failed, 0 skipped, 0 blocked\n"
+        ));
+    }
+
+    #[test]
+    fn test_allowed_failure() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_fail(
+            "checks",
+            ".#checks.x86_64-linux.flaky-test".to_owned(),
+            "`nix log /nix/store/flaky.drv`".to_owned(),
+        );
+        assert!(!summary.passed());
+
+        summary.allow_failure("checks", ".#checks.x86_64-linux.flaky-test");
+        assert!(summary.passed());
+        assert!(summary.render().contains("failed (allowed)"));
+    }
+
+    #[test]
+    fn test_missing_output() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.skip_output("apps");
+        assert!(summary.passed());
+
+        summary.register_missing_output("pakages", "not found for x86_64-linux".to_owned());
+        assert!(!summary.passed());
+        let rendered = summary.render();
+        assert!(rendered.contains("failed (required, not found for x86_64-linux)"));
+    }
+
+    #[test]
+    fn test_attempts() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.register_fail(
+            "checks",
+            ".#checks.x86_64-linux.flaky-test".to_owned(),
+            "`nix log /nix/store/flaky.drv`".to_owned(),
+        );
+        summary.record_attempts("packages", ".#packages.x86_64-linux.foo", 2);
+        summary.record_attempts("checks", ".#checks.x86_64-linux.flaky-test", 3);
+        summary.allow_failure("checks", ".#checks.x86_64-linux.flaky-test");
+
+        let rendered = summary.render();
+        assert!(rendered.contains("success (2 attempts)"));
+        assert!(rendered.contains("failed (allowed, 3 attempts)"));
+    }
+
+    #[test]
+    fn test_git_revision_display() {
+        let release = GitRevision::new(
+            "abc1234def5678abc1234def5678abc1234def5".to_owned(),
+            Some("main".to_owned()),
+            Some("v1.2.0".to_owned()),
+            true,
+        );
+        assert_eq!("abc1234 on main at v1.2.0 (dirty)", release.to_string());
+        // Detached, with no tags
+        let detached = GitRevision::new("abc1234".to_owned(), None, None, false);
+        assert_eq!("abc1234", detached.to_string());
+        assert_eq!("unknown", GitRevision::unknown().to_string());
+    }
+
+    #[test]
+    fn test_flake_lock() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.warn_flake_lock("older than flake.nix".to_owned());
+        summary.set_inputs(BTreeMap::from([(
+            "nixpkgs".to_owned(),
+            "0123abc".to_owned(),
+        )]));
+        let rendered = summary.render();
+        assert!(rendered.contains("warning (older than flake.nix)"));
+        assert!(rendered.contains("Input nixpkgs: 0123abc"));
+        // A warning, not a failure
+        assert!(summary.passed());
+    }
+
+    #[test]
+    fn test_warnings() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.warn("Offline, so not using cachix".to_owned());
+        summary.warn("Can't pin foo, nix didn't report any out paths".to_owned());
+        summary.warn("Offline, so not using cachix".to_owned());
+        let expected = "Warnings\n  \
+                        - Can't pin foo, nix didn't report any out paths\n  \
+                        - Offline, so not using cachix\n";
+        assert!(summary.render().contains(expected));
+        assert!(summary.passed());
+    }
+
+    #[test]
+    fn test_blocked_root_cause() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_blocked(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            ".#packages.x86_64-linux.bar".to_owned(),
+            ".#checks.x86_64-linux.pkg-bar".to_owned(),
+        );
+        let rendered = summary.render();
+        assert!(rendered.contains(
+            "(pre-rec '.#packages.x86_64-linux.bar' blocked by '.#checks.x86_64-linux.pkg-bar')"
+        ));
+        // Worded the same as everywhere else
+        assert!(summary.github_markdown().contains(
+            "pre-rec '.#packages.x86_64-linux.bar' blocked by '.#checks.x86_64-linux.pkg-bar'"
+        ));
+    }
+
+    #[test]
+    fn test_skipped_systems() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.skip_system("aarch64-darwin".to_owned());
+        let rendered = summary.render();
+        assert!(rendered.contains("> aarch64-darwin"));
+        assert!(rendered.contains("skipped (can't build here)"));
+        assert!(rendered.contains("PASS"));
+
+        summary.fail_no_systems();
+        let rendered = summary.render();
+        assert!(rendered.contains("failed (none of the configured systems can build here)"));
+        assert!(rendered.contains("FAIL"));
+    }
+
+    #[test]
+    fn test_flake_check_failed() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        assert!(summary.passed());
+        summary.fail_flake_check();
+        assert!(!summary.passed());
+        assert!(summary.render().contains("nix flake check"));
+        assert_eq!(1, summary.github_annotations().len());
+    }
+
+    #[test]
+    fn test_order_independent() {
+        let registrations = [
+            ("packages", ".#packages.x86_64-linux.foo"),
+            ("checks", ".#checks.x86_64-linux.pkg-foo"),
+            ("packages", ".#packages.x86_64-linux.bar"),
+            ("devShells", ".#devShells.x86_64-linux.default"),
+        ];
+
+        let mut forwards = mk_summary();
+        for (output, job) in registrations {
+            forwards.register_success(output, job.to_owned(), Vec::new());
+        }
+        let mut backwards = mk_summary();
+        for (output, job) in registrations.into_iter().rev() {
+            backwards.register_success(output, job.to_owned(), Vec::new());
+        }
+
+        assert_eq!(forwards.render(), backwards.render());
+        assert_eq!(forwards.json(true).unwrap(), backwards.json(true).unwrap());
+    }
+
+    #[test]
+    fn test_pushed_to() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.record_pushes(
+            "packages",
+            ".#packages.x86_64-linux.foo",
+            vec!["public".to_owned(), "private".to_owned()],
+        );
+        assert!(summary.render().contains("pushed to: public, private"));
+    }
+
+    #[test]
+    fn test_activation() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        for name in ["alice", "bob"] {
+            summary.register_success(
+                "homeConfigurations",
+                format!(".#homeConfigurations.{name}"),
+                Vec::new(),
+            );
+        }
+        summary.record_activation("homeConfigurations", ".#homeConfigurations.alice", None);
+        assert!(summary.passed());
+
+        summary.record_activation(
+            "homeConfigurations",
+            ".#homeConfigurations.bob",
+            Some("collision with ~/.bashrc".to_owned()),
+        );
+        assert!(!summary.passed());
+        let rendered = summary.render();
+        assert!(rendered.contains("activation: passed"));
+        assert!(rendered.contains("activation: failed\n    error: collision with ~/.bashrc"));
+        assert!(summary.tap().contains("not ok 2 - homeConfigurations.bob"));
+        assert_eq!(1, summary.github_annotations().len());
+    }
+
+    #[test]
+    fn test_reproducibility() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.record_reproducibility(
+            "packages",
+            ".#packages.x86_64-linux.foo",
+            Some("output '/nix/store/def-foo' differs".to_owned()),
+        );
+        assert!(!summary.passed());
+        assert!(summary.render().contains("reproducibility: failed"));
+        assert!(summary
+            .github_markdown()
+            .contains("| not reproducible | output '/nix/store/def-foo' differs |"));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.set_system("x86_64-linux".to_owned());
+        summary.register_system("x86_64-linux".to_owned(), "native");
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            vec![PathBuf::from("/tmp/dist/foo")],
+        );
+        summary.record_reproducibility(
+            "packages",
+            ".#packages.x86_64-linux.foo",
+            Some("output '/nix/store/def-foo' differs".to_owned()),
+        );
+        summary.record_closure_size("packages", ".#packages.x86_64-linux.foo", 1024);
+        summary.register_fail(
+            "checks",
+            ".#checks.x86_64-linux.pkg-bar".to_owned(),
+            "`nix log /nix/store/bar.drv`".to_owned(),
+        );
+        summary.register_blocked(
+            "packages",
+            ".#packages.x86_64-linux.bar".to_owned(),
+            ".#checks.x86_64-linux.pkg-bar".to_owned(),
+            ".#checks.x86_64-linux.pkg-bar".to_owned(),
+        );
+        summary.register_skip(
+            "devShells",
+            ".#devShells.x86_64-linux.default".to_owned(),
+            "dry run".to_owned(),
+        );
+        summary.register_pin("foo".to_owned(), "/nix/store/def-foo".to_owned(), true);
+        summary.warn("Failed to read flake metadata".to_owned());
+
+        let json = summary.json(false).unwrap();
+        let (mut loaded, succeeded) = Summary::from_json(&json, PathBuf::from("/tmp"), 80).unwrap();
+        loaded.set_color(ColorChoice::Never);
+        assert!(!succeeded);
+        assert_eq!(summary.render(), loaded.render());
+        assert_eq!(summary.junit(), loaded.junit());
+        assert_eq!(json, loaded.json(false).unwrap());
+    }
+
+    #[test]
+    fn test_smoke() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_success("apps", ".#apps.x86_64-linux.foo".to_owned(), Vec::new());
+        summary.record_smoke("apps", ".#apps.x86_64-linux.foo", None);
+        assert!(summary.passed());
+        assert!(summary.render().contains("smoke test: passed"));
+
+        summary.record_smoke(
+            "apps",
+            ".#apps.x86_64-linux.foo",
+            Some("'nix' exited with non-zero exit code 127".to_owned()),
+        );
+        assert!(!summary.passed());
+        assert!(summary
+            .render()
+            .contains("smoke test: failed\n    error: 'nix' exited with non-zero exit code 127"));
+    }
+
+    #[rstest]
+    #[case(0, "0 B")]
+    #[case(1023, "1023 B")]
+    #[case(1536, "1.5 KiB")]
+    #[case(124 * 1024 * 1024, "124 MiB")]
+    #[case(5 * 1024 * 1024 * 1024 / 2, "2.5 GiB")]
+    fn test_human_size(#[case] bytes: u64, #[case] expected: &str) {
+        assert_eq!(expected, human_size(bytes));
+    }
+
+    #[test]
+    fn test_closure_size() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.record_closure_size("packages", ".#packages.x86_64-linux.foo", 130_023_424);
+        assert!(summary.render().contains("closure size: 124 MiB"));
+
+        let json: serde_json::Value = serde_json::from_str(&summary.json(false).unwrap()).unwrap();
+        assert_eq!(
+            130_023_424,
+            json["successes"]["packages"][0]["closure_size"]
+        );
+    }
+
+    #[test]
+    fn test_jobs_grouped_by_output() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.register_fail(
+            "packages",
+            ".#packages.x86_64-linux.bar".to_owned(),
+            "nix log bar".to_owned(),
+        );
+        summary.register_skip(
+            "packages",
+            ".#packages.x86_64-linux.baz".to_owned(),
+            "cached".to_owned(),
+        );
+        let rendered = summary.render();
+        assert_eq!(1, rendered.matches("> packages").count());
+        let bar = rendered.find("x86_64-linux.bar").unwrap();
+        let baz = rendered.find("x86_64-linux.baz").unwrap();
+        let foo = rendered.find("x86_64-linux.foo").unwrap();
+        assert!(bar < baz && baz < foo);
+    }
+
+    #[test]
+    fn test_jobs_grouped_by_system() {
+        let mut summary = mk_summary();
+        summary.set_color(ColorChoice::Never);
+        summary.set_group_by_system(true);
+        summary.set_system("x86_64-linux".to_owned());
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.set_system("aarch64-linux".to_owned());
+        summary.register_success(
+            "packages",
+            ".#packages.aarch64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        let rendered = summary.render();
+        assert_eq!(2, rendered.matches("> packages").count());
+        let arm = rendered.find("aarch64-linux\n").unwrap();
+        let arm_foo = rendered.find("aarch64-linux.foo").unwrap();
+        let x86 = rendered.find("x86_64-linux\n").unwrap();
+        let x86_foo = rendered.find("x86_64-linux.foo").unwrap();
+        assert!(arm < arm_foo && arm_foo < x86 && x86 < x86_foo);
+    }
+
+    #[test]
+    fn test_dots_fit_width() {
+        let width = 60;
+        let mut summary = Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            width,
+        );
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.register_skip(
+            "checks",
+            ".#checks.x86_64-linux.pkg-foo".to_owned(),
+            "dry run".to_owned(),
+        );
+
+        let rendered = summary.render();
+        let status_lines: Vec<_> = rendered
+            .lines()
+            .filter(|line| line.contains(".."))
+            .collect();
+        assert_eq!(2, status_lines.len());
+        for line in status_lines {
+            assert_eq!(width, line.len(), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_dots_fit_width_multibyte() {
+        let width = 60;
+        let mut summary = Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            width,
+        );
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.café".to_owned(),
+            Vec::new(),
+        );
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.cafe".to_owned(),
+            Vec::new(),
+        );
+
+        let rendered = summary.render();
+        let status_lines: Vec<_> = rendered
+            .lines()
+            .filter(|line| line.contains(".."))
+            .collect();
+        assert_eq!(2, status_lines.len());
+        for line in status_lines {
+            assert_eq!(width, line.width(), "{line}");
+        }
     }
 }
@@ -0,0 +1,144 @@
+//! Renderers for printing the summary, picked with `--format`
+
+use super::Summary;
+use anyhow::Result;
+
+/// How to print the summary at the end of a run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SummaryFormat {
+    /// A table for people to read
+    #[default]
+    Human,
+    Json,
+    Junit,
+    /// The Markdown table written to the GitHub job summary
+    Markdown,
+    /// Test Anything Protocol
+    Tap,
+}
+
+/// Turns a summary into text. Adding a format is one of these plus a `SummaryFormat` variant
+pub trait Render {
+    /// `succeeded` is how the run went as a whole, which can differ from the summary
+    /// when it was interrupted
+    fn render(&self, summary: &Summary, succeeded: bool) -> Result<String>;
+}
+
+struct Human;
+struct Json;
+struct Junit;
+struct Markdown;
+struct Tap;
+
+impl Render for Human {
+    fn render(&self, summary: &Summary, succeeded: bool) -> Result<String> {
+        Ok(summary.human(succeeded))
+    }
+}
+
+impl Render for Json {
+    fn render(&self, summary: &Summary, succeeded: bool) -> Result<String> {
+        let mut out = summary.json(succeeded)?;
+        out.push('\n');
+        Ok(out)
+    }
+}
+
+impl Render for Junit {
+    fn render(&self, summary: &Summary, _succeeded: bool) -> Result<String> {
+        Ok(summary.junit())
+    }
+}
+
+impl Render for Markdown {
+    fn render(&self, summary: &Summary, _succeeded: bool) -> Result<String> {
+        Ok(summary.github_markdown())
+    }
+}
+
+impl Render for Tap {
+    fn render(&self, summary: &Summary, _succeeded: bool) -> Result<String> {
+        Ok(summary.tap())
+    }
+}
+
+impl SummaryFormat {
+    fn renderer(self) -> &'static dyn Render {
+        match self {
+            Self::Human => &Human,
+            Self::Json => &Json,
+            Self::Junit => &Junit,
+            Self::Markdown => &Markdown,
+            Self::Tap => &Tap,
+        }
+    }
+}
+
+impl Summary {
+    /// The summary in any format
+    pub fn render_as(&self, format: SummaryFormat, succeeded: bool) -> Result<String> {
+        format.renderer().render(self, succeeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{ColorChoice, GitRevision};
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_render_as() {
+        let mut summary = Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            80,
+        );
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+
+        assert_eq!(
+            summary.render(),
+            summary.render_as(SummaryFormat::Human, true).unwrap()
+        );
+        let json = summary.render_as(SummaryFormat::Json, true).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::json!(true), json["succeeded"]);
+        assert!(summary
+            .render_as(SummaryFormat::Junit, true)
+            .unwrap()
+            .starts_with("<?xml"));
+        assert!(summary
+            .render_as(SummaryFormat::Markdown, true)
+            .unwrap()
+            .starts_with("## flake-ci"));
+    }
+
+    #[test]
+    fn test_human_interrupted() {
+        let mut summary = Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            80,
+        );
+        summary.set_color(ColorChoice::Never);
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+
+        // Nothing in the summary failed, but the run didn't finish
+        let rendered = summary.render_as(SummaryFormat::Human, false).unwrap();
+        assert!(rendered.contains("\nFAIL\n"));
+        assert!(!rendered.contains("PASS"));
+    }
+}
@@ -0,0 +1,211 @@
+//! GitHub Actions integration: a Markdown job summary and `::error`/`::warning` annotations
+
+use super::{block_note, Summary, FLAKE_CHECK, NO_SYSTEMS};
+use std::fmt::{self, Write};
+
+/// Escape annotation message text
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape annotation property values, which additionally can't hold `:` or `,`
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// A workflow command that makes GitHub show `message` as an annotation, at a `level` like error
+fn annotation(level: &str, title: &str, message: &str) -> String {
+    format!(
+        "::{level} title={}::{}",
+        escape_property(title),
+        escape_data(message)
+    )
+}
+
+fn escape_cell(s: &str) -> String {
+    s.replace('|', "\\|").replace('\n', " ")
+}
+
+fn row(out: &mut String, output: &str, job: &str, status: &str, note: &str) -> fmt::Result {
+    writeln!(
+        out,
+        "| {} | `{}` | {status} | {} |",
+        escape_cell(output),
+        escape_cell(job),
+        escape_cell(note)
+    )
+}
+
+impl Summary {
+    fn write_markdown(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "## flake-ci")?;
+        writeln!(out)?;
+        writeln!(out, "| Output | Attribute | Status | Log command |")?;
+        writeln!(out, "| --- | --- | --- | --- |")?;
+
+        for system in &self.skipped_systems {
+            row(out, system, "*", "skipped", "can't build here")?;
+        }
+        if self.no_systems_built {
+            row(out, NO_SYSTEMS, "*", "failed", "none can build here")?;
+        }
+        for output in &self.skipped_outputs {
+            row(out, output, "*", "skipped", "not found")?;
+        }
+        if self.flake_check_failed {
+            row(out, FLAKE_CHECK, "*", "failed", "")?;
+        }
+        for missing in &self.missing_outputs {
+            row(out, &missing.output, "*", "failed", &missing.reason)?;
+        }
+        for (output, jobs) in &self.successes {
+            for job in jobs {
+                let status = if job.cached { "cached" } else { "success" };
+                match job.problem() {
+                    Some((problem, error)) => row(out, output, &job.derivation, problem, error)?,
+                    None => row(out, output, &job.derivation, status, "")?,
+                }
+            }
+        }
+        for (output, jobs) in &self.skips {
+            for job in jobs {
+                row(out, output, &job.derivation, "skipped", &job.reason)?;
+            }
+        }
+        for (output, jobs) in &self.blocks {
+            for job in jobs {
+                row(out, output, &job.derivation, "skipped", &block_note(job))?;
+            }
+        }
+        for (output, jobs) in &self.fails {
+            for job in jobs {
+                let note = job
+                    .log_command
+                    .as_deref()
+                    .or(job.error.as_deref())
+                    .unwrap_or_default();
+                let status = if job.allowed {
+                    "failed (allowed)"
+                } else {
+                    "failed"
+                };
+                row(out, output, &job.derivation, status, note)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// An `::error` annotation for every failure, to print where GitHub Actions will see it
+    pub fn github_annotations(&self) -> Vec<String> {
+        let missing = self.missing_outputs.iter().map(|missing| {
+            let message = format!("Required output {}", missing.reason);
+            annotation("error", &missing.output, &message)
+        });
+        let fails = self.fails.values().flatten().map(|job| {
+            let message = job
+                .error
+                .as_deref()
+                .or(job.log_command.as_deref())
+                .unwrap_or_default();
+            // Allowed failures shouldn't look like they broke the run
+            let level = if job.allowed { "warning" } else { "error" };
+            annotation(level, &job.derivation, message)
+        });
+        let problems = self.successes.values().flatten().filter_map(|job| {
+            let (problem, error) = job.problem()?;
+            Some(annotation(
+                "error",
+                &job.derivation,
+                &format!("{problem}: {error}"),
+            ))
+        });
+        let flake_check = self
+            .flake_check_failed
+            .then(|| annotation("error", FLAKE_CHECK, "The flake failed `nix flake check`"));
+        let no_systems = self.no_systems_built.then(|| {
+            annotation(
+                "error",
+                NO_SYSTEMS,
+                "None of the configured systems can build here",
+            )
+        });
+        let warnings = self
+            .warnings
+            .iter()
+            .map(|warning| annotation("warning", "flake-ci", warning));
+        flake_check
+            .into_iter()
+            .chain(no_systems)
+            .chain(missing)
+            .chain(fails)
+            .chain(problems)
+            .chain(warnings)
+            .collect()
+    }
+
+    /// The summary as a Markdown table for `$GITHUB_STEP_SUMMARY`
+    pub fn github_markdown(&self) -> String {
+        let mut out = String::new();
+        self.write_markdown(&mut out)
+            .expect("Writing to a String can't fail");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::GitRevision;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_annotation() {
+        let actual = annotation(
+            "error",
+            ".#packages.x86_64-linux.foo",
+            "`nix log /nix/store/foo.drv`\n50% done",
+        );
+        assert_eq!(
+            "::error title=.#packages.x86_64-linux.foo::`nix log /nix/store/foo.drv`%0A50%25 done",
+            actual
+        );
+        assert_eq!(
+            "::error title=a%3Ab%2Cc::d",
+            annotation("error", "a:b,c", "d")
+        );
+    }
+
+    #[test]
+    fn test_markdown() {
+        let mut summary = Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            80,
+        );
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            Vec::new(),
+        );
+        summary.register_fail(
+            "checks",
+            ".#checks.x86_64-linux.pkg-bar".to_owned(),
+            "`nix log /nix/store/bar.drv`".to_owned(),
+        );
+
+        let expected = "\
+## flake-ci
+
+| Output | Attribute | Status | Log command |
+| --- | --- | --- | --- |
+| packages | `.#packages.x86_64-linux.foo` | success |  |
+| checks | `.#checks.x86_64-linux.pkg-bar` | failed | `nix log /nix/store/bar.drv` |
+";
+        assert_eq!(expected, summary.github_markdown());
+    }
+}
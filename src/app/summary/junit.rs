@@ -0,0 +1,152 @@
+//! JUnit XML reports, one `<testsuite>` per output and one `<testcase>` per derivation
+
+use super::{block_note, Outcome, Summary};
+use anyhow::Result;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::Path;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn write_case(out: &mut String, output: &str, job: &Outcome) -> fmt::Result {
+    let name = escape(job.derivation());
+    let classname = escape(output);
+    let (tag, message) = match job {
+        Outcome::Success(job) if job.problem().is_some() => {
+            let (status, error) = job.problem().unwrap_or_default();
+            ("failure", format!("{status}: {error}"))
+        }
+        Outcome::Success(_) => {
+            return writeln!(
+                out,
+                r#"    <testcase name="{name}" classname="{classname}"/>"#
+            );
+        }
+        Outcome::Fail(job) => {
+            let message = job
+                .log_command
+                .as_deref()
+                .or(job.error.as_deref())
+                .unwrap_or_default();
+            ("failure", message.to_owned())
+        }
+        Outcome::Skip(job) => ("skipped", job.reason.clone()),
+        Outcome::Block(job) => ("skipped", block_note(job)),
+    };
+    writeln!(
+        out,
+        r#"    <testcase name="{name}" classname="{classname}">"#
+    )?;
+    writeln!(out, r#"      <{tag} message="{}"/>"#, escape(&message))?;
+    writeln!(out, "    </testcase>")
+}
+
+impl Summary {
+    fn write_junit(&self, out: &mut String) -> fmt::Result {
+        let outputs = self.by_output();
+        let count = |f: fn(&Outcome) -> bool| -> usize {
+            outputs.values().flatten().filter(|job| f(job)).count()
+        };
+        let tests = count(|_| true);
+        let failures = outputs
+            .values()
+            .flatten()
+            .filter(|job| job.failed())
+            .count();
+        let skipped = count(|job| matches!(job, Outcome::Skip(_) | Outcome::Block(_)));
+
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            out,
+            r#"<testsuites name="flake-ci" tests="{tests}" failures="{failures}" skipped="{skipped}">"#
+        )?;
+        for (output, jobs) in &outputs {
+            let failures = jobs.iter().filter(|job| job.failed()).count();
+            let skipped = jobs
+                .iter()
+                .filter(|job| matches!(job, Outcome::Skip(_) | Outcome::Block(_)))
+                .count();
+            writeln!(
+                out,
+                r#"  <testsuite name="{}" tests="{}" failures="{failures}" skipped="{skipped}">"#,
+                escape(output),
+                jobs.len()
+            )?;
+            for job in jobs {
+                write_case(out, output, job)?;
+            }
+            writeln!(out, "  </testsuite>")?;
+        }
+        writeln!(out, "</testsuites>")
+    }
+
+    pub fn junit(&self) -> String {
+        let mut out = String::new();
+        self.write_junit(&mut out)
+            .expect("Writing to a String can't fail");
+        out
+    }
+
+    pub fn save_junit(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.junit())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::GitRevision;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_junit() {
+        let mut summary = Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            80,
+        );
+        summary.register_fail(
+            "checks",
+            ".#checks.x86_64-linux.pkg-foo".to_owned(),
+            "`nix log /nix/store/foo.drv`".to_owned(),
+        );
+        summary.register_blocked(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            ".#checks.x86_64-linux.pkg-foo".to_owned(),
+            ".#checks.x86_64-linux.pkg-foo".to_owned(),
+        );
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.bar".to_owned(),
+            Vec::new(),
+        );
+
+        let expected = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="flake-ci" tests="3" failures="1" skipped="1">
+  <testsuite name="checks" tests="1" failures="1" skipped="0">
+    <testcase name=".#checks.x86_64-linux.pkg-foo" classname="checks">
+      <failure message="`nix log /nix/store/foo.drv`"/>
+    </testcase>
+  </testsuite>
+  <testsuite name="packages" tests="2" failures="0" skipped="1">
+    <testcase name=".#packages.x86_64-linux.bar" classname="packages"/>
+    <testcase name=".#packages.x86_64-linux.foo" classname="packages">
+      <skipped message="pre-rec &apos;.#checks.x86_64-linux.pkg-foo&apos; failed"/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#;
+        assert_eq!(expected, summary.junit());
+    }
+}
@@ -0,0 +1,143 @@
+//! TAP (Test Anything Protocol) output, one test line per derivation
+
+use super::{Outcome, Summary};
+use std::fmt::{self, Write};
+
+/// A TAP description can't hold `#`, which starts a directive, so leave off the `.#`
+fn description(derivation: &str) -> String {
+    derivation.trim_start_matches(".#").replace('#', "\\#")
+}
+
+fn write_line(out: &mut String, number: usize, job: &Outcome) -> fmt::Result {
+    let name = description(job.derivation());
+    match job {
+        Outcome::Success(job) if job.problem().is_some() => {
+            writeln!(out, "not ok {number} - {name}")?;
+            let (status, error) = job.problem().unwrap_or_default();
+            writeln!(out, "# {status}: {error}")
+        }
+        Outcome::Success(job) if job.cached => writeln!(out, "ok {number} - {name} (cached)"),
+        Outcome::Success(_) => writeln!(out, "ok {number} - {name}"),
+        Outcome::Skip(job) => writeln!(out, "ok {number} - {name} # SKIP {}", job.reason),
+        Outcome::Block(job) if job.pre_rec == job.root_cause => writeln!(
+            out,
+            "ok {number} - {name} # SKIP pre-rec '{}' failed",
+            description(&job.pre_rec)
+        ),
+        Outcome::Block(job) => writeln!(
+            out,
+            "ok {number} - {name} # SKIP pre-rec '{}' blocked by '{}'",
+            description(&job.pre_rec),
+            description(&job.root_cause)
+        ),
+        Outcome::Fail(job) => {
+            // TODO tests don't count as failures, like allowed failures
+            let directive = if job.allowed {
+                " # TODO allowed to fail"
+            } else {
+                ""
+            };
+            writeln!(out, "not ok {number} - {name}{directive}")?;
+            for diagnostic in [&job.log_command, &job.error].into_iter().flatten() {
+                writeln!(out, "# {diagnostic}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl Summary {
+    fn write_tap(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "TAP version 13")?;
+        let mut number = 0;
+        if self.flake_check_failed {
+            number += 1;
+            writeln!(out, "not ok {number} - nix flake check")?;
+        }
+        if self.no_systems_built {
+            number += 1;
+            writeln!(out, "not ok {number} - systems")?;
+            writeln!(out, "# none of the configured systems can build here")?;
+        }
+        for missing in &self.missing_outputs {
+            number += 1;
+            writeln!(out, "not ok {number} - {}", missing.output)?;
+            writeln!(out, "# required, {}", missing.reason)?;
+        }
+        for job in self.by_output().values().flatten() {
+            number += 1;
+            write_line(out, number, job)?;
+        }
+        writeln!(out, "1..{number}")
+    }
+
+    pub fn tap(&self) -> String {
+        let mut out = String::new();
+        self.write_tap(&mut out)
+            .expect("Writing to a String can't fail");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::GitRevision;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_tap() {
+        let mut summary = Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            80,
+        );
+        summary.register_fail(
+            "checks",
+            ".#checks.x86_64-linux.pkg-foo".to_owned(),
+            "`nix log /nix/store/foo.drv`".to_owned(),
+        );
+        summary.register_blocked(
+            "packages",
+            ".#packages.x86_64-linux.foo".to_owned(),
+            ".#checks.x86_64-linux.pkg-foo".to_owned(),
+            ".#checks.x86_64-linux.pkg-foo".to_owned(),
+        );
+        summary.register_success(
+            "packages",
+            ".#packages.x86_64-linux.bar".to_owned(),
+            Vec::new(),
+        );
+        summary.register_skip(
+            "packages",
+            ".#packages.x86_64-linux.baz".to_owned(),
+            "for aarch64-linux".to_owned(),
+        );
+
+        let expected = "\
+TAP version 13
+not ok 1 - checks.x86_64-linux.pkg-foo
+# `nix log /nix/store/foo.drv`
+ok 2 - packages.x86_64-linux.bar
+ok 3 - packages.x86_64-linux.baz # SKIP for aarch64-linux
+ok 4 - packages.x86_64-linux.foo # SKIP pre-rec 'checks.x86_64-linux.pkg-foo' failed
+1..4
+";
+        assert_eq!(expected, summary.tap());
+    }
+
+    #[test]
+    fn test_empty_tap() {
+        let summary = Summary::new(
+            PathBuf::from("/tmp"),
+            "nix (Nix) 2.24.12".to_owned(),
+            None,
+            GitRevision::new("abc1234".to_owned(), None, None, false),
+            80,
+        );
+        assert_eq!("TAP version 13\n1..0\n", summary.tap());
+    }
+}
@@ -0,0 +1,475 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Building,
+    Built,
+    Failed,
+}
+
+/// What a worker should do with a node it picked up
+#[derive(Debug, PartialEq, Eq)]
+pub enum Claim {
+    /// Nobody has touched the node yet, the caller is now responsible for building it
+    Build,
+    /// Another worker already built the node
+    Built,
+    /// The node failed (or was blocked) in another worker
+    Failed,
+}
+
+/// Shared record of which nodes have been built and which failed, so a node is only built once
+/// and its dependents know whether to block
+#[derive(Debug)]
+pub struct Tracker<T> {
+    states: Mutex<HashMap<T, State>>,
+    /// For blocked nodes, the pre-recs from the one it depends on to the one that failed
+    causes: Mutex<HashMap<T, Vec<T>>>,
+    changed: Condvar,
+}
+
+impl<T> Tracker<T>
+where
+    T: Hash + Eq + Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            states: Mutex::new(HashMap::new()),
+            causes: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Claim a node for building. Blocks while another worker is building it
+    pub fn claim(&self, node: &T) -> Claim {
+        let mut states = self.states.lock().unwrap();
+        loop {
+            match states.get(node) {
+                None => {
+                    states.insert(node.clone(), State::Building);
+                    return Claim::Build;
+                }
+                Some(State::Building) => {
+                    states = self.changed.wait(states).unwrap();
+                }
+                Some(State::Built) => return Claim::Built,
+                Some(State::Failed) => return Claim::Failed,
+            }
+        }
+    }
+
+    /// Record the result of a node claimed with `claim`
+    pub fn finish(&self, node: &T, succeeded: bool) {
+        let state = if succeeded {
+            State::Built
+        } else {
+            State::Failed
+        };
+        self.states.lock().unwrap().insert(node.clone(), state);
+        self.changed.notify_all();
+    }
+
+    /// Mark a node as failed because one of its pre-recs failed, with the pre-recs leading to
+    /// the one that failed. Returns false if the node was already handled elsewhere
+    pub fn block(&self, node: &T, causes: Vec<T>) -> bool {
+        let mut states = self.states.lock().unwrap();
+        if states.contains_key(node) {
+            return false;
+        }
+        states.insert(node.clone(), State::Failed);
+        self.causes.lock().unwrap().insert(node.clone(), causes);
+        true
+    }
+
+    /// The pre-recs leading to whatever blocked a node. Empty if it wasn't blocked
+    pub fn causes(&self, node: &T) -> Vec<T> {
+        self.causes
+            .lock()
+            .unwrap()
+            .get(node)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether a node failed or was blocked
+    pub fn failed(&self, node: &T) -> bool {
+        self.states.lock().unwrap().get(node) == Some(&State::Failed)
+    }
+}
+
+/// Build one node whose dependencies have all been handled, or block it if one of them failed.
+/// With `stop`, a failure sets it
+fn run_node<T, B, F, A>(
+    node: &T,
+    dependencies: &HashMap<T, Vec<T>>,
+    tracker: &Tracker<T>,
+    build: &B,
+    blocked: &F,
+    allowed: &A,
+    stop: Option<&AtomicBool>,
+) -> Result<bool>
+where
+    T: Hash + Eq + Clone,
+    B: Fn(&T) -> Result<bool>,
+    F: Fn(&T, &[T]),
+    A: Fn(&T) -> bool,
+{
+    let failed_parent = dependencies
+        .get(node)
+        .and_then(|parents| parents.iter().find(|parent| tracker.failed(parent)));
+    if let Some(pre_rec) = failed_parent {
+        let mut causes = vec![pre_rec.clone()];
+        causes.extend(tracker.causes(pre_rec));
+        if tracker.block(node, causes.clone()) {
+            blocked(node, &causes);
+        }
+        return Ok(allowed(node));
+    }
+
+    match tracker.claim(node) {
+        Claim::Built => Ok(true),
+        Claim::Failed => Ok(allowed(node)),
+        Claim::Build => {
+            let result = build(node);
+            // Wake up anyone waiting on this node before possibly bailing
+            tracker.finish(node, matches!(result, Ok(true)));
+            let succeeded = result? || allowed(node);
+            if let (false, Some(stop)) = (succeeded, stop) {
+                stop.store(true, Ordering::Relaxed);
+            }
+            Ok(succeeded)
+        }
+    }
+}
+
+/// Build levels from `Graph::topo_levels` one after another, with up to `jobs` workers building
+/// the nodes of a level at once. `dependencies` maps each node to the nodes it depends on.
+/// `build` returns whether the node succeeded, `blocked` is called with a node and the pre-recs
+/// from the one it depends on to the one that failed. Nodes `allowed` to fail still block their
+/// dependents, but don't count against the result.
+/// An `Err` from `build` stops the workers from starting new nodes and is returned. With
+/// `fail_fast`, so does the first failure
+pub fn run_levels<T, B, F, A>(
+    levels: &[Vec<T>],
+    dependencies: &HashMap<T, Vec<T>>,
+    jobs: usize,
+    build: B,
+    blocked: F,
+    allowed: A,
+    fail_fast: bool,
+) -> Result<bool>
+where
+    T: Hash + Eq + Clone + Send + Sync,
+    B: Fn(&T) -> Result<bool> + Sync,
+    F: Fn(&T, &[T]) + Sync,
+    A: Fn(&T) -> bool + Sync,
+{
+    let tracker = Tracker::new();
+    let aborted = AtomicBool::new(false);
+    let stop = fail_fast.then_some(&aborted);
+
+    let mut all_succeeded = true;
+    for level in levels {
+        if aborted.load(Ordering::Relaxed) {
+            break;
+        }
+        let jobs = jobs.min(level.len()).max(1);
+        let queue = Mutex::new(level.iter());
+        let results: Vec<Result<bool>> = thread::scope(|s| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut all_succeeded = true;
+                        while !aborted.load(Ordering::Relaxed) {
+                            let Some(node) = queue.lock().unwrap().next() else {
+                                break;
+                            };
+                            match run_node(
+                                node,
+                                dependencies,
+                                &tracker,
+                                &build,
+                                &blocked,
+                                &allowed,
+                                stop,
+                            ) {
+                                Ok(succeeded) => all_succeeded &= succeeded,
+                                Err(e) => {
+                                    aborted.store(true, Ordering::Relaxed);
+                                    return Err(e);
+                                }
+                            }
+                        }
+                        Ok(all_succeeded)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("Build worker panicked"))
+                .collect()
+        });
+        for result in results {
+            all_succeeded &= result?;
+        }
+    }
+    Ok(all_succeeded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Each node in a chain depends on the one before it
+    fn linear_dependencies(
+        chains: &[Vec<&'static str>],
+    ) -> HashMap<&'static str, Vec<&'static str>> {
+        let mut dependencies: HashMap<_, Vec<_>> = HashMap::new();
+        for chain in chains {
+            for pair in chain.windows(2) {
+                let parents = dependencies.entry(pair[1]).or_default();
+                if !parents.contains(&pair[0]) {
+                    parents.push(pair[0]);
+                }
+            }
+        }
+        dependencies
+    }
+
+    #[test]
+    fn test_claim_once() {
+        let tracker = Tracker::new();
+        assert_eq!(Claim::Build, tracker.claim(&"foo"));
+        tracker.finish(&"foo", true);
+        assert_eq!(Claim::Built, tracker.claim(&"foo"));
+    }
+
+    #[test]
+    fn test_blocked() {
+        let tracker = Tracker::new();
+        assert!(tracker.block(&"foo", vec!["bar"]));
+        assert!(!tracker.block(&"foo", vec!["baz"]));
+        assert_eq!(vec!["bar"], tracker.causes(&"foo"));
+        assert_eq!(Claim::Failed, tracker.claim(&"foo"));
+    }
+
+    #[test]
+    fn test_wait_for_other_worker() {
+        let tracker = Tracker::new();
+        assert_eq!(Claim::Build, tracker.claim(&"foo"));
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| tracker.claim(&"foo"));
+            tracker.finish(&"foo", false);
+            assert_eq!(Claim::Failed, waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_independent_nodes_run_when_one_fails() {
+        let chains = vec![vec!["check-a", "a"], vec!["check-b", "b"]];
+        let levels = vec![vec!["check-a", "check-b"], vec!["a", "b"]];
+        let ran = Mutex::new(Vec::new());
+        let blocks = Mutex::new(Vec::new());
+
+        let succeeded = run_levels(
+            &levels,
+            &linear_dependencies(&chains),
+            1,
+            |node| {
+                ran.lock().unwrap().push(*node);
+                Ok(*node != "check-a")
+            },
+            |node, causes| blocks.lock().unwrap().push((*node, causes[0])),
+            |_| false,
+            false,
+        )
+        .unwrap();
+
+        assert!(!succeeded);
+        assert_eq!(vec!["check-a", "check-b", "b"], ran.into_inner().unwrap());
+        assert_eq!(vec![("a", "check-a")], blocks.into_inner().unwrap());
+    }
+
+    #[test]
+    fn test_shared_node_built_once() {
+        let chains = vec![vec!["check", "a"], vec!["check", "b"]];
+        let levels = vec![vec!["check"], vec!["a", "b"]];
+        let ran = Mutex::new(Vec::new());
+
+        let succeeded = run_levels(
+            &levels,
+            &linear_dependencies(&chains),
+            2,
+            |node| {
+                ran.lock().unwrap().push(*node);
+                Ok(true)
+            },
+            |_, _| {},
+            |_| false,
+            false,
+        )
+        .unwrap();
+
+        assert!(succeeded);
+        let mut ran = ran.into_inner().unwrap();
+        ran.sort_unstable();
+        assert_eq!(vec!["a", "b", "check"], ran);
+    }
+
+    #[test]
+    fn test_failed_shared_node_blocks_every_dependent() {
+        let chains = vec![vec!["check", "a"], vec!["check", "b"]];
+        let levels = vec![vec!["check"], vec!["a", "b"]];
+        let blocks = Mutex::new(Vec::new());
+
+        let succeeded = run_levels(
+            &levels,
+            &linear_dependencies(&chains),
+            1,
+            |node| Ok(*node != "check"),
+            |node, causes| blocks.lock().unwrap().push((*node, causes[0])),
+            |_| false,
+            false,
+        )
+        .unwrap();
+
+        assert!(!succeeded);
+        assert_eq!(
+            vec![("a", "check"), ("b", "check")],
+            blocks.into_inner().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_blocked_through_blocked_node() {
+        let chains = vec![vec!["a", "b", "c"]];
+        let levels = vec![vec!["a"], vec!["b"], vec!["c"]];
+        let blocks = Mutex::new(Vec::new());
+
+        let succeeded = run_levels(
+            &levels,
+            &linear_dependencies(&chains),
+            1,
+            |node| Ok(*node != "a"),
+            |node, causes| blocks.lock().unwrap().push((*node, causes.to_vec())),
+            |_| false,
+            false,
+        )
+        .unwrap();
+
+        assert!(!succeeded);
+        assert_eq!(
+            vec![("b", vec!["a"]), ("c", vec!["b", "a"])],
+            blocks.into_inner().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_error_is_returned() {
+        let levels = vec![vec!["a", "b"]];
+        let dependencies = HashMap::new();
+        let result = run_levels(
+            &levels,
+            &dependencies,
+            1,
+            |_| anyhow::bail!("boom"),
+            |_, _| {},
+            |_| false,
+            false,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_diamond_only_blocks_dependents() {
+        let levels = vec![vec!["A"], vec!["B", "C"], vec!["D"]];
+        let dependencies =
+            HashMap::from([("B", vec!["A"]), ("C", vec!["A"]), ("D", vec!["B", "C"])]);
+        let ran = Mutex::new(Vec::new());
+        let blocks = Mutex::new(Vec::new());
+
+        let succeeded = run_levels(
+            &levels,
+            &dependencies,
+            1,
+            |node| {
+                ran.lock().unwrap().push(*node);
+                Ok(*node != "B")
+            },
+            |node, causes| blocks.lock().unwrap().push((*node, causes[0])),
+            |_| false,
+            false,
+        )
+        .unwrap();
+
+        assert!(!succeeded);
+        assert_eq!(vec!["A", "B", "C"], ran.into_inner().unwrap());
+        assert_eq!(vec![("D", "B")], blocks.into_inner().unwrap());
+    }
+
+    #[test]
+    fn test_allowed_failure_still_blocks() {
+        let chains = vec![vec!["flaky", "a"], vec!["check", "b"]];
+        let levels = vec![vec!["flaky", "check"], vec!["a", "b"]];
+        let blocks = Mutex::new(Vec::new());
+
+        let succeeded = run_levels(
+            &levels,
+            &linear_dependencies(&chains),
+            1,
+            |node| Ok(*node != "flaky"),
+            |node, causes| blocks.lock().unwrap().push((*node, causes[0])),
+            |node| *node == "flaky",
+            false,
+        )
+        .unwrap();
+
+        // The allowed failure is fine, but what it blocked isn't
+        assert!(!succeeded);
+        assert_eq!(vec![("a", "flaky")], blocks.into_inner().unwrap());
+
+        let chains = vec![vec!["flaky"], vec!["check", "b"]];
+        let levels = vec![vec!["flaky", "check"], vec!["b"]];
+        let succeeded = run_levels(
+            &levels,
+            &linear_dependencies(&chains),
+            1,
+            |node| Ok(*node != "flaky"),
+            |_, _| {},
+            |node| *node == "flaky",
+            false,
+        )
+        .unwrap();
+        assert!(succeeded);
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        let chains = vec![vec!["check-a", "a"], vec!["check-b", "b"]];
+        let levels = vec![vec!["check-a", "check-b"], vec!["a", "b"]];
+        let ran = Mutex::new(Vec::new());
+
+        let succeeded = run_levels(
+            &levels,
+            &linear_dependencies(&chains),
+            1,
+            |node| {
+                ran.lock().unwrap().push(*node);
+                Ok(*node != "check-a")
+            },
+            |_, _| {},
+            |_| false,
+            true,
+        )
+        .unwrap();
+
+        assert!(!succeeded);
+        assert_eq!(vec!["check-a"], ran.into_inner().unwrap());
+    }
+}
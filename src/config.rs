@@ -1,31 +1,45 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use s_string::s;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use serde_with::DisplayFromStr;
+use serde_with::OneOrMany;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::env;
 use std::fmt::Display;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use winnow::prelude::*;
 use winnow::stream::AsChar;
 
 const LINUX: &str = "linux";
 const DARWIN: &str = "darwin";
 const WINDOWS: &str = "windows";
+const FREEBSD: &str = "freebsd";
 const ARM: &str = "aarch64";
 const X86: &str = "x86_64";
+const I686: &str = "i686";
+const ARMV6L: &str = "armv6l";
+const ARMV7L: &str = "armv7l";
+const RISCV64: &str = "riscv64";
+const POWERPC64LE: &str = "powerpc64le";
 
 fn default_artifact_dir() -> String {
     s!("dist")
 }
 
-fn default_outputs() -> Vec<String> {
+fn default_systems() -> Vec<System> {
+    vec![System::x86_linux(), System::x86_darwin()]
+}
+
+pub(crate) fn default_outputs() -> Vec<String> {
     vec![
         s!("checks"),
         s!("packages"),
+        s!("apps"),
         s!("devShells"),
         s!("homeConfigurations"),
         s!("darwinConfigurations"),
@@ -35,6 +49,26 @@ fn default_outputs() -> Vec<String> {
     ]
 }
 
+/// Every output a flake can have, per the flake schema. nix complains about anything else
+const KNOWN_OUTPUTS: &[&str] = &[
+    "apps",
+    "checks",
+    "darwinConfigurations",
+    "defaultApp",
+    "defaultPackage",
+    "devShell",
+    "devShells",
+    "formatter",
+    "homeConfigurations",
+    "hydraJobs",
+    "legacyPackages",
+    "nixosConfigurations",
+    "nixosModules",
+    "overlays",
+    "packages",
+    "templates",
+];
+
 fn default_publish() -> bool {
     false
 }
@@ -61,6 +95,17 @@ impl ParseError {
     }
 }
 
+impl ParseError {
+    /// An error pointing at part of some input that parsed fine but doesn't make sense
+    fn pointed(message: String, input: String, span: std::ops::Range<usize>) -> Self {
+        Self {
+            message,
+            span,
+            input,
+        }
+    }
+}
+
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let message = annotate_snippets::Level::Error
@@ -78,16 +123,164 @@ impl std::fmt::Display for ParseError {
 
 impl std::error::Error for ParseError {}
 
+/// The config (or a setting it needs) is wrong
+#[derive(Debug)]
+pub struct InvalidConfig(pub String);
+
+impl Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+fn is_var_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Replace `$VAR` and `${VAR}` with what `lookup` finds. `$$` is a literal `$`. Unset variables
+/// are empty, or an error when strict
+fn interpolate(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+    strict: bool,
+) -> Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (var, after) = if let Some(braced) = rest.strip_prefix('{') {
+            let Some(end) = braced.find('}') else {
+                bail!("Unclosed '${{' in '{value}'");
+            };
+            (&braced[..end], &braced[end + 1..])
+        } else if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        } else {
+            let end = rest.find(|c| !is_var_char(c)).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if var.is_empty() {
+            // A lone '$' isn't a variable
+            out.push('$');
+            continue;
+        }
+
+        match lookup(var) {
+            Some(found) => out.push_str(&found),
+            None if strict => bail!("Variable '{var}' is not set"),
+            None => {}
+        }
+        rest = after;
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// How built paths get pushed to cachix
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PushMode {
+    /// Build under `cachix watch-exec`, which pushes everything realized, dependencies included
+    #[default]
+    WatchExec,
+    /// `cachix push` just the out paths of each derivation that built
+    Explicit,
+}
+
+/// How artifacts end up in the output dir
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtifactMode {
+    /// Link into the nix store. Cheap, but the links break anywhere the store isn't
+    #[default]
+    Symlink,
+    /// Copy the out path, so the output dir can be uploaded on its own
+    Copy,
+    /// Archive the out path as a `.tar.gz`, for artifact uploads that want single files
+    Tar,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct General {
     #[serde(rename = "output-dir", default = "default_artifact_dir")]
     pub artifact_dir: String,
+
+    #[serde(rename = "artifact-mode", default)]
+    pub artifact_mode: ArtifactMode,
+
+    /// Number of chains to build at once. Defaults to the number of logical CPUs
+    #[serde(default)]
+    pub jobs: Option<usize>,
+
+    /// Builds nix runs at once within each chain, passed on as `--max-jobs`. Separate from
+    /// `jobs`, which is how many chains flake-ci runs at once
+    #[serde(rename = "max-jobs", default)]
+    pub max_jobs: Option<usize>,
+
+    /// Cores each nix build may use, passed on as `--cores`
+    #[serde(default)]
+    pub cores: Option<usize>,
+
+    /// Seconds a single build may take before it's killed
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    /// Times to rebuild a failed derivation before calling it failed
+    #[serde(default)]
+    pub retries: u32,
+
+    /// Seconds to wait before the first retry. The wait doubles with each retry after that
+    #[serde(rename = "retry-delay", default)]
+    pub retry_delay: u64,
+
+    /// Nix binary to use instead of the one on the $PATH
+    #[serde(default)]
+    pub nix: Option<PathBuf>,
+
+    /// Cachix binary to use instead of the one on the $PATH
+    #[serde(default)]
+    pub cachix: Option<PathBuf>,
+
+    /// Oldest nix that can be used, like "2.19"
+    #[serde(rename = "min-nix-version", default)]
+    pub min_nix_version: Option<String>,
+
+    /// Extra flags for every nix call, like "--impure"
+    #[serde(rename = "nix-args", default)]
+    pub nix_args: Vec<String>,
+
+    /// Error on `[env]` values that use unset variables, instead of leaving them empty
+    #[serde(rename = "strict-env", default)]
+    pub strict_env: bool,
+
+    /// Run `nix flake check --no-build` before building anything
+    #[serde(rename = "flake-check", default)]
+    pub flake_check: bool,
 }
 
 impl Default for General {
     fn default() -> Self {
         Self {
             artifact_dir: default_artifact_dir(),
+            artifact_mode: ArtifactMode::default(),
+            jobs: None,
+            max_jobs: None,
+            cores: None,
+            timeout: None,
+            retries: 0,
+            retry_delay: 0,
+            nix: None,
+            cachix: None,
+            min_nix_version: None,
+            nix_args: Vec::new(),
+            strict_env: false,
+            flake_check: false,
         }
     }
 }
@@ -97,6 +290,7 @@ pub enum OS {
     Linux,
     Darwin,
     Windows,
+    FreeBSD,
 }
 
 impl Display for OS {
@@ -105,12 +299,19 @@ impl Display for OS {
             Self::Linux => write!(f, "{LINUX}"),
             Self::Darwin => write!(f, "{DARWIN}"),
             Self::Windows => write!(f, "{WINDOWS}"),
+            Self::FreeBSD => write!(f, "{FREEBSD}"),
         }
     }
 }
 
 fn os(s: &mut &str) -> winnow::Result<OS> {
-    winnow::combinator::alt((LINUX.map(|_| OS::Linux), DARWIN.map(|_| OS::Darwin))).parse_next(s)
+    winnow::combinator::alt((
+        LINUX.map(|_| OS::Linux),
+        DARWIN.map(|_| OS::Darwin),
+        WINDOWS.map(|_| OS::Windows),
+        FREEBSD.map(|_| OS::FreeBSD),
+    ))
+    .parse_next(s)
 }
 
 impl FromStr for OS {
@@ -124,6 +325,11 @@ impl FromStr for OS {
 pub enum Arch {
     X86,
     Arm,
+    I686,
+    Armv6l,
+    Armv7l,
+    Riscv64,
+    Powerpc64le,
 }
 
 impl Display for Arch {
@@ -131,12 +337,26 @@ impl Display for Arch {
         match self {
             Self::X86 => write!(f, "{X86}"),
             Self::Arm => write!(f, "{ARM}"),
+            Self::I686 => write!(f, "{I686}"),
+            Self::Armv6l => write!(f, "{ARMV6L}"),
+            Self::Armv7l => write!(f, "{ARMV7L}"),
+            Self::Riscv64 => write!(f, "{RISCV64}"),
+            Self::Powerpc64le => write!(f, "{POWERPC64LE}"),
         }
     }
 }
 
 fn arch(s: &mut &str) -> winnow::Result<Arch> {
-    winnow::combinator::alt((X86.map(|_| Arch::X86), ARM.map(|_| Arch::Arm))).parse_next(s)
+    winnow::combinator::alt((
+        X86.map(|_| Arch::X86),
+        ARM.map(|_| Arch::Arm),
+        I686.map(|_| Arch::I686),
+        ARMV6L.map(|_| Arch::Armv6l),
+        ARMV7L.map(|_| Arch::Armv7l),
+        RISCV64.map(|_| Arch::Riscv64),
+        POWERPC64LE.map(|_| Arch::Powerpc64le),
+    ))
+    .parse_next(s)
 }
 
 impl FromStr for Arch {
@@ -153,6 +373,10 @@ pub struct System {
 }
 
 impl System {
+    pub fn new(arch: Arch, os: OS) -> Self {
+        Self { os, arch }
+    }
+
     pub fn x86_linux() -> Self {
         Self {
             os: OS::Linux,
@@ -202,6 +426,19 @@ impl Display for System {
     }
 }
 
+/// Ordered like their nix names
+impl Ord for System {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.to_string().cmp(&other.to_string())
+    }
+}
+
+impl PartialOrd for System {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 pub fn system(s: &mut &str) -> winnow::Result<System> {
     winnow::combinator::seq! {System {
         arch: arch,
@@ -239,6 +476,37 @@ where
             Self::Specified(pattern) => other == pattern,
         }
     }
+
+    /// Like `matches`, but `Not` patterns match anything
+    fn matches_ignoring_not(&self, other: &T) -> bool {
+        match self {
+            Self::Not(_) => true,
+            _ => self.matches(other),
+        }
+    }
+}
+
+impl<T> Display for Pattern<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Any => write!(f, "*"),
+            Self::Not(pattern) => write!(f, "!{pattern}"),
+            Self::Specified(pattern) => write!(f, "{pattern}"),
+        }
+    }
+}
+
+impl<T> Pattern<T> {
+    /// The value a `Specified` or `Not` pattern names
+    fn named(&self) -> Option<&T> {
+        match self {
+            Self::Any => None,
+            Self::Not(pattern) | Self::Specified(pattern) => Some(pattern),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -249,9 +517,33 @@ pub struct OutputPath {
 }
 
 impl OutputPath {
-    fn matches(&self, top_level: &String, system: System, name: &String) -> bool {
+    /// The path matching exactly one attribute
+    pub fn exact(top_level: &str, system: System, name: &str) -> Self {
+        Self {
+            top_level: Pattern::Specified(top_level.to_owned()),
+            system: Pattern::Specified(system),
+            name: Pattern::Specified(name.to_owned()),
+        }
+    }
+
+    pub fn matches(&self, top_level: &String, system: System, name: &String) -> bool {
         self.top_level.matches(top_level) && self.system.matches(&system) && self.name.matches(name)
     }
+
+    /// Whether one of the path's `!` parts rules this out, e.g. `packages.*.!formatter` excludes
+    /// `packages.x86_64-linux.formatter` but says nothing about `devShells.x86_64-linux.default`
+    pub fn excludes(&self, top_level: &String, system: System, name: &String) -> bool {
+        let in_scope = self.top_level.matches_ignoring_not(top_level)
+            && self.system.matches_ignoring_not(&system)
+            && self.name.matches_ignoring_not(name);
+        in_scope && !self.matches(top_level, system, name)
+    }
+}
+
+impl Display for OutputPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.top_level, self.system, self.name)
+    }
 }
 
 pub fn name(s: &mut &str) -> winnow::Result<String> {
@@ -313,6 +605,7 @@ impl FromStr for OutputPath {
 
 #[serde_as]
 #[derive(Debug, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Build {
     #[serde(default = "default_outputs")]
     outputs: Vec<String>,
@@ -330,7 +623,45 @@ pub struct Build {
     os: Vec<OS>,
 
     #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default = "default_systems")]
     systems: Vec<System>,
+
+    /// Build the systems the flake has outputs for, as far as this machine can, instead of the
+    /// configured ones
+    #[serde(rename = "auto-systems", default)]
+    auto_systems: bool,
+
+    /// Build systems other than the one flake-ci is running on, through emulation
+    #[serde(default)]
+    cross: bool,
+
+    /// Build systems other than the one flake-ci is running on with the remote builders set up
+    /// in nix.conf. Takes priority over cross
+    #[serde(rename = "remote-builders", default)]
+    remote_builders: bool,
+
+    /// Outputs that must exist and have something in them, or the run fails
+    #[serde(default)]
+    required: Vec<String>,
+
+    /// Derivations that may fail without failing the run. They still block their dependents
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(rename = "allow-failure", default)]
+    allow_failure: Vec<OutputPath>,
+
+    /// Which legacyPackages attributes to build, since it's too big to build all of
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(rename = "legacy-packages", default)]
+    legacy_packages: Vec<OutputPath>,
+
+    /// Directories with a directory or .nix file per package named after it, like `pkgs`, for
+    /// working out what `--changed-since` changed
+    #[serde(rename = "package-dirs", default)]
+    package_dirs: Vec<String>,
+
+    /// Dry run each homeConfigurations activation script after building it
+    #[serde(rename = "home-activation-check", default)]
+    home_activation_check: bool,
 }
 
 impl Default for Build {
@@ -344,16 +675,15 @@ impl Default for Build {
             }],
             os: Vec::new(),
             architectures: Vec::new(),
-            systems: vec![
-                System {
-                    os: OS::Linux,
-                    arch: Arch::X86,
-                },
-                System {
-                    os: OS::Darwin,
-                    arch: Arch::X86,
-                },
-            ],
+            systems: default_systems(),
+            auto_systems: false,
+            cross: false,
+            remote_builders: false,
+            required: Vec::new(),
+            allow_failure: Vec::new(),
+            legacy_packages: Vec::new(),
+            package_dirs: Vec::new(),
+            home_activation_check: false,
         }
     }
 }
@@ -361,48 +691,295 @@ impl Default for Build {
 #[serde_as]
 #[derive(Debug, Deserialize)]
 pub struct Cache {
+    /// One cache, or a list of them to use and push to every one
+    #[serde_as(as = "OneOrMany<_>")]
     #[serde(rename = "cache-name")]
-    cache_name: String,
+    names: Vec<String>,
 
     #[serde(default = "default_publish")]
     publish: bool,
 
+    #[serde(rename = "push-mode", default)]
+    push_mode: PushMode,
+
+    /// File to read the auth token from, instead of `CACHIX_AUTH_TOKEN`
+    #[serde(rename = "auth-token-file", default)]
+    auth_token_file: Option<PathBuf>,
+
+    /// File to read the signing key from, instead of `CACHIX_SIGNING_KEY`
+    #[serde(rename = "signing-key-file", default)]
+    signing_key_file: Option<PathBuf>,
+
     #[serde_as(as = "Vec<DisplayFromStr>")]
     #[serde(default)]
     pin: Vec<OutputPath>,
 }
 
+/// A check and the derivations that shouldn't be built until it passes
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct Check {
+    name: String,
+
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    gates: Vec<OutputPath>,
+}
+
+fn default_smoke_args() -> Vec<String> {
+    vec![s!("--help")]
+}
+
+/// An app to `nix run` once it builds, to catch it failing at runtime
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct Smoke {
+    #[serde_as(as = "DisplayFromStr")]
+    app: OutputPath,
+
+    /// Passed to the app after `--`
+    #[serde(default = "default_smoke_args")]
+    args: Vec<String>,
+}
+
+fn default_separator() -> char {
+    '-'
+}
+
+/// How a check's name says what it gates, like `pkg-foo` gating `packages.<system>.foo`
+#[derive(Debug, Deserialize)]
+pub struct CheckNaming {
+    /// Between the type and the name
+    #[serde(default = "default_separator")]
+    separator: char,
+
+    /// Types to recognize besides the built in ones, and the output each one gates, like
+    /// `test = "packages"`
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+}
+
+impl Default for CheckNaming {
+    fn default() -> Self {
+        Self {
+            separator: default_separator(),
+            aliases: HashMap::new(),
+        }
+    }
+}
+
+impl CheckNaming {
+    pub fn separator(&self) -> char {
+        self.separator
+    }
+
+    /// The output a type gates, if it's one of the configured aliases. Case insensitive
+    pub fn alias(&self, prefix: &str) -> Option<&str> {
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(prefix))
+            .map(|(_, output)| output.as_str())
+    }
+}
+
+/// Push built paths with `nix copy`, for caches other than cachix (attic, S3, ...)
+#[derive(Debug, Deserialize)]
+pub struct NixCopy {
+    /// Store URL to copy to
+    to: String,
+
+    #[serde(default = "default_publish")]
+    publish: bool,
+}
+
+/// Where built paths get pushed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheBackend<'a> {
+    /// Cachix caches, by name
+    Cachix(&'a [String]),
+    /// Any store `nix copy --to` understands
+    NixCopy(&'a str),
+}
+
 #[derive(Debug, Deserialize, Default)]
 pub struct Config {
     #[serde(default)]
     general: General,
     #[serde(rename = "cachix")]
     cache: Option<Cache>,
+    #[serde(rename = "nix-copy")]
+    nix_copy: Option<NixCopy>,
     #[serde(default)]
     build: Build,
     #[serde(default)]
     env: HashMap<String, String>,
+    #[serde(default)]
+    checks: Vec<Check>,
+    #[serde(default)]
+    smoke: Vec<Smoke>,
+    #[serde(rename = "check-naming", default)]
+    check_naming: CheckNaming,
 }
 
 impl Config {
     pub fn from_file(config_file: &Path) -> Result<Self> {
-        let contents = fs::read_to_string(config_file)?;
-        let config: Config = toml::from_str(&contents)?;
+        let contents = fs::read_to_string(config_file).with_context(|| {
+            InvalidConfig(format!(
+                "Failed to read config file '{}'",
+                config_file.display()
+            ))
+        })?;
+        let config: Config = toml::from_str(&contents).with_context(|| {
+            InvalidConfig(format!(
+                "Failed to parse config file '{}'",
+                config_file.display()
+            ))
+        })?;
         Ok(config)
     }
 
+    /// Check the config makes sense as a whole: one cache backend, only real flake outputs, and
+    /// patterns that only name configured outputs and systems
+    pub fn validate(&self) -> Result<()> {
+        if self.cache.is_some() && self.nix_copy.is_some() {
+            bail!(InvalidConfig(s!(
+                "Only one of [cachix] and [nix-copy] can be configured"
+            )));
+        }
+
+        let mut errors = Vec::new();
+        for (option, value) in [
+            ("max-jobs", self.general.max_jobs),
+            ("cores", self.general.cores),
+        ] {
+            if value == Some(0) {
+                errors.push(format!("[general] {option}: must be at least 1"));
+            }
+        }
+        if self
+            .cache
+            .as_ref()
+            .is_some_and(|cache| cache.names.is_empty())
+        {
+            errors.push(s!("[cachix] cache-name: needs at least one cache"));
+        }
+        for output in &self.build.outputs {
+            if !KNOWN_OUTPUTS.contains(&output.as_str()) {
+                errors.push(format!("[build] outputs: '{output}' isn't a flake output"));
+            }
+        }
+
+        for output in self.check_naming.aliases.values() {
+            if !KNOWN_OUTPUTS.contains(&output.as_str()) {
+                errors.push(format!(
+                    "[check-naming] aliases: '{output}' isn't a flake output"
+                ));
+            }
+        }
+
+        for output in &self.build.required {
+            if !self.build.outputs.contains(output) {
+                errors.push(format!(
+                    "[build] required: '{output}' isn't one of the configured outputs"
+                ));
+            }
+        }
+
+        let mut patterns: Vec<(String, &OutputPath)> = Vec::new();
+        patterns.extend(
+            self.build
+                .artifacts
+                .iter()
+                .map(|p| (s!("[build] artifacts"), p)),
+        );
+        patterns.extend(
+            self.build
+                .allow_failure
+                .iter()
+                .map(|p| (s!("[build] allow-failure"), p)),
+        );
+        patterns.extend(
+            self.build
+                .legacy_packages
+                .iter()
+                .map(|p| (s!("[build] legacy-packages"), p)),
+        );
+        if let Some(cache) = &self.cache {
+            patterns.extend(cache.pin.iter().map(|p| (s!("[cachix] pin"), p)));
+        }
+        for check in &self.checks {
+            let section = format!("[[checks]] '{}' gates", check.name);
+            patterns.extend(check.gates.iter().map(|p| (section.clone(), p)));
+        }
+        patterns.extend(
+            self.smoke
+                .iter()
+                .map(|smoke| (s!("[[smoke]] app"), &smoke.app)),
+        );
+        let systems = self.systems();
+        for (section, pattern) in patterns {
+            if let Some(error) = self.check_pattern(pattern, &systems) {
+                errors.push(format!("{section}:\n{error}"));
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!(InvalidConfig(errors.join("\n")));
+        }
+        Ok(())
+    }
+
+    /// Point at the part of a pattern naming an output or system that isn't configured
+    fn check_pattern(&self, pattern: &OutputPath, systems: &[System]) -> Option<ParseError> {
+        let input = pattern.to_string();
+        let top_level = pattern.top_level.to_string();
+        let system = pattern.system.to_string();
+        let system_start = top_level.len() + 1;
+
+        if let Some(output) = pattern.top_level.named() {
+            if !self.build.outputs.contains(output) {
+                let message = format!("'{output}' isn't one of the configured outputs");
+                return Some(ParseError::pointed(message, input, 0..top_level.len()));
+            }
+        }
+        // Discovered systems aren't known until the flake is read
+        if let Some(named) = pattern.system.named() {
+            if !self.build.auto_systems && !systems.contains(named) {
+                let message = format!("'{named}' isn't one of the configured systems");
+                let span = system_start..system_start + system.len();
+                return Some(ParseError::pointed(message, input, span));
+            }
+        }
+        None
+    }
+
     pub fn publish(&self) -> bool {
-        let Some(cache_settings) = &self.cache else {
-            return false;
-        };
-        cache_settings.publish
+        match (&self.cache, &self.nix_copy) {
+            (Some(cache_settings), _) => cache_settings.publish,
+            (None, Some(nix_copy)) => nix_copy.publish,
+            (None, None) => false,
+        }
     }
 
-    pub fn cache(&self) -> Option<&String> {
-        let Some(cache_settings) = &self.cache else {
-            return None;
-        };
-        Some(&cache_settings.cache_name)
+    pub fn cache(&self) -> Option<CacheBackend<'_>> {
+        match (&self.cache, &self.nix_copy) {
+            (Some(cache_settings), _) => Some(CacheBackend::Cachix(&cache_settings.names)),
+            (None, Some(nix_copy)) => Some(CacheBackend::NixCopy(&nix_copy.to)),
+            (None, None) => None,
+        }
+    }
+
+    pub fn push_mode(&self) -> PushMode {
+        self.cache
+            .as_ref()
+            .map_or_else(PushMode::default, |cache| cache.push_mode)
+    }
+
+    pub fn auth_token_file(&self) -> Option<&Path> {
+        self.cache.as_ref()?.auth_token_file.as_deref()
+    }
+
+    pub fn signing_key_file(&self) -> Option<&Path> {
+        self.cache.as_ref()?.signing_key_file.as_deref()
     }
 
     pub fn pins(&self) -> Vec<OutputPath> {
@@ -416,14 +993,192 @@ impl Config {
         &self.general.artifact_dir
     }
 
-    pub fn env(&self) -> &HashMap<String, String> {
-        &self.env
+    pub fn artifact_mode(&self) -> ArtifactMode {
+        self.general.artifact_mode
+    }
+
+    pub fn jobs(&self) -> usize {
+        match self.general.jobs {
+            Some(jobs) => jobs,
+            None => std::thread::available_parallelism().map_or(1, std::num::NonZero::get),
+        }
+    }
+
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.general.jobs = Some(jobs);
+    }
+
+    pub fn max_jobs(&self) -> Option<usize> {
+        self.general.max_jobs
+    }
+
+    pub fn set_max_jobs(&mut self, max_jobs: usize) {
+        self.general.max_jobs = Some(max_jobs);
+    }
+
+    pub fn cores(&self) -> Option<usize> {
+        self.general.cores
+    }
+
+    pub fn set_cores(&mut self, cores: usize) {
+        self.general.cores = Some(cores);
+    }
+
+    /// What a check gates, if the config declares it
+    pub fn check_gates(&self, check: &str) -> Option<&[OutputPath]> {
+        self.checks
+            .iter()
+            .find(|c| c.name == check)
+            .map(|c| c.gates.as_slice())
+    }
+
+    pub fn check_naming(&self) -> &CheckNaming {
+        &self.check_naming
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.general.timeout.map(Duration::from_secs)
+    }
+
+    pub fn set_timeout(&mut self, seconds: u64) {
+        self.general.timeout = Some(seconds);
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.general.retries
+    }
+
+    pub fn set_retries(&mut self, retries: u32) {
+        self.general.retries = retries;
+    }
+
+    pub fn flake_check(&self) -> bool {
+        self.general.flake_check
+    }
+
+    pub fn set_flake_check(&mut self, flake_check: bool) {
+        self.general.flake_check = flake_check;
+    }
+
+    /// How long to wait before a retry, counting retries from 1
+    pub fn retry_delay(&self, retry: u32) -> Duration {
+        let delay = Duration::from_secs(self.general.retry_delay);
+        delay.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+    }
+
+    pub fn nix(&self) -> Option<&Path> {
+        self.general.nix.as_deref()
+    }
+
+    pub fn set_nix(&mut self, nix: PathBuf) {
+        self.general.nix = Some(nix);
+    }
+
+    pub fn min_nix_version(&self) -> Option<&str> {
+        self.general.min_nix_version.as_deref()
+    }
+
+    pub fn nix_args(&self) -> &[String] {
+        &self.general.nix_args
+    }
+
+    pub fn add_nix_args(&mut self, args: impl IntoIterator<Item = String>) {
+        self.general.nix_args.extend(args);
+    }
+
+    pub fn cachix(&self) -> Option<&Path> {
+        self.general.cachix.as_deref()
+    }
+
+    /// The `[env]` table with `$VAR` and `${VAR}` filled in from the process environment
+    pub fn env(&self) -> Result<HashMap<String, String>> {
+        self.env
+            .iter()
+            .map(|(key, value)| {
+                let value =
+                    interpolate(value, |var| env::var(var).ok(), self.general.strict_env)
+                        .with_context(|| InvalidConfig(format!("Bad value for {key} in [env]")))?;
+                Ok((key.clone(), value))
+            })
+            .collect()
     }
 
     pub fn build_outputs(&self) -> &[String] {
         &self.build.outputs
     }
 
+    pub fn cross(&self) -> bool {
+        self.build.cross
+    }
+
+    pub fn set_cross(&mut self, cross: bool) {
+        self.build.cross = cross;
+    }
+
+    pub fn auto_systems(&self) -> bool {
+        self.build.auto_systems
+    }
+
+    pub fn set_auto_systems(&mut self, auto_systems: bool) {
+        self.build.auto_systems = auto_systems;
+    }
+
+    /// Replace the configured systems, including ones from architectures and os
+    pub fn set_systems(&mut self, systems: Vec<System>) {
+        self.build.systems = systems;
+        self.build.architectures.clear();
+        self.build.os.clear();
+    }
+
+    pub fn remote_builders(&self) -> bool {
+        self.build.remote_builders
+    }
+
+    pub fn set_remote_builders(&mut self, remote_builders: bool) {
+        self.build.remote_builders = remote_builders;
+    }
+
+    /// Whether missing the output fails the run
+    pub fn required(&self, output: &str) -> bool {
+        self.build
+            .required
+            .iter()
+            .any(|required| required == output)
+    }
+
+    /// Whether a derivation is allowed to fail without failing the run
+    pub fn allow_failure(&self, top_level: &String, system: System, name: &String) -> bool {
+        self.build
+            .allow_failure
+            .iter()
+            .any(|rule| rule.matches(top_level, system, name))
+    }
+
+    /// What to run an app with to smoke test it, from the first `[[smoke]]` entry matching it
+    pub fn smoke_args(
+        &self,
+        top_level: &String,
+        system: System,
+        name: &String,
+    ) -> Option<&[String]> {
+        self.smoke
+            .iter()
+            .find(|smoke| smoke.app.matches(top_level, system, name))
+            .map(|smoke| smoke.args.as_slice())
+    }
+
+    pub fn legacy_packages(&self) -> &[OutputPath] {
+        &self.build.legacy_packages
+    }
+
+    pub fn package_dirs(&self) -> &[String] {
+        &self.build.package_dirs
+    }
+
+    pub fn home_activation_check(&self) -> bool {
+        self.build.home_activation_check
+    }
+
     pub fn systems(&self) -> Vec<System> {
         let mut systems = HashSet::new();
         for system in &self.build.systems {
@@ -443,13 +1198,20 @@ impl Config {
         systems.into_iter().collect()
     }
 
+    /// Artifacts are saved if any rule includes them and no rule excludes them. Everything is
+    /// saved when there are no rules
     pub fn save_artifact(&self, top_level: &String, system: System, name: &String) -> bool {
-        for a in &self.build.artifacts {
-            if !a.matches(top_level, system, name) {
-                return false;
-            }
+        let rules = &self.build.artifacts;
+        if rules.is_empty() {
+            return true;
         }
-        true
+        let included = rules
+            .iter()
+            .any(|rule| rule.matches(top_level, system, name));
+        let excluded = rules
+            .iter()
+            .any(|rule| rule.excludes(top_level, system, name));
+        included && !excluded
     }
 }
 
@@ -457,6 +1219,7 @@ impl Config {
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
+    use rstest::rstest;
 
     #[test]
     fn test_parse_any_pattern() {
@@ -464,7 +1227,7 @@ mod tests {
         let expected = Pattern::Any;
         let actual = name_pattern.parse_next(&mut input).unwrap();
         assert_eq!(expected, actual);
-        assert_eq!("", input)
+        assert_eq!("", input);
     }
 
     #[test]
@@ -473,7 +1236,7 @@ mod tests {
         let expected = Pattern::Not(s!("formatter"));
         let actual = name_pattern.parse_next(&mut input).unwrap();
         assert_eq!(expected, actual);
-        assert_eq!("", input)
+        assert_eq!("", input);
     }
 
     #[test]
@@ -482,7 +1245,345 @@ mod tests {
         let expected = Pattern::Specified(s!("packages"));
         let actual = name_pattern.parse_next(&mut input).unwrap();
         assert_eq!(expected, actual);
-        assert_eq!("", input)
+        assert_eq!("", input);
+    }
+
+    #[rstest]
+    #[case("armv7l-linux", Arch::Armv7l, OS::Linux)]
+    #[case("i686-linux", Arch::I686, OS::Linux)]
+    #[case("riscv64-linux", Arch::Riscv64, OS::Linux)]
+    #[case("powerpc64le-linux", Arch::Powerpc64le, OS::Linux)]
+    fn test_parse_other_systems(#[case] input: &str, #[case] arch: Arch, #[case] os: OS) {
+        assert_eq!(System::new(arch, os), System::from_str(input).unwrap());
+    }
+
+    #[test]
+    fn test_parse_unknown_system() {
+        assert!(System::from_str("m68k-linux").is_err());
+    }
+
+    #[test]
+    fn test_parse_windows_system() {
+        let actual = System::from_str("x86_64-windows").unwrap();
+        assert_eq!(System::x86_windows(), actual);
+    }
+
+    #[rstest]
+    #[case(System::x86_linux())]
+    #[case(System::arm_linux())]
+    #[case(System::x86_darwin())]
+    #[case(System::arm_darwin())]
+    #[case(System::x86_windows())]
+    #[case(System::arm_windows())]
+    #[case(System::new(Arch::I686, OS::Linux))]
+    #[case(System::new(Arch::Armv6l, OS::Linux))]
+    #[case(System::new(Arch::Armv7l, OS::Linux))]
+    #[case(System::new(Arch::Riscv64, OS::Linux))]
+    #[case(System::new(Arch::Powerpc64le, OS::Linux))]
+    #[case(System::new(Arch::X86, OS::FreeBSD))]
+    fn test_system_round_trip(#[case] system: System) {
+        let actual = System::from_str(&system.to_string()).unwrap();
+        assert_eq!(system, actual);
+    }
+
+    #[test]
+    fn test_check_gates() {
+        let config: Config = toml::from_str(
+            r#"
+            [[checks]]
+            name = "clippy"
+            gates = ["packages.*.foo", "packages.*.bar"]
+            "#,
+        )
+        .unwrap();
+
+        let gates = config.check_gates("clippy").unwrap();
+        assert_eq!(2, gates.len());
+        assert!(gates[0].matches(&s!("packages"), System::x86_linux(), &s!("foo")));
+        assert!(gates[1].matches(&s!("packages"), System::x86_linux(), &s!("bar")));
+        assert_eq!(None, config.check_gates("fmt"));
+    }
+
+    #[test]
+    fn test_smoke_args() {
+        let config: Config = toml::from_str(
+            r#"
+            [[smoke]]
+            app = "apps.*.foo"
+            args = ["--version"]
+
+            [[smoke]]
+            app = "apps.*.*"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let expected: &[String] = &[s!("--version")];
+        let actual = config.smoke_args(&s!("apps"), System::x86_linux(), &s!("foo"));
+        assert_eq!(Some(expected), actual);
+        let expected: &[String] = &[s!("--help")];
+        let actual = config.smoke_args(&s!("apps"), System::x86_linux(), &s!("bar"));
+        assert_eq!(Some(expected), actual);
+        let actual = config.smoke_args(&s!("packages"), System::x86_linux(), &s!("foo"));
+        assert_eq!(None, actual);
+    }
+
+    fn mk_artifacts_config(artifacts: &[&str]) -> Config {
+        let mut config = Config::default();
+        config.build.artifacts = artifacts
+            .iter()
+            .map(|rule| OutputPath::from_str(rule).unwrap())
+            .collect();
+        config
+    }
+
+    #[rstest]
+    #[case("packages", "foo", true)]
+    #[case("devShells", "default", true)]
+    #[case("checks", "foo", false)]
+    fn test_save_artifact_two_includes(
+        #[case] top_level: &str,
+        #[case] name: &str,
+        #[case] expected: bool,
+    ) {
+        let config = mk_artifacts_config(&["packages.*.*", "devShells.*.*"]);
+        let actual = config.save_artifact(&s!(top_level), System::x86_linux(), &s!(name));
+        assert_eq!(expected, actual);
+    }
+
+    #[rstest]
+    #[case("packages", "foo", true)]
+    #[case("packages", "formatter", false)]
+    #[case("devShells", "default", true)]
+    #[case("devShells", "formatter", true)]
+    #[case("checks", "foo", false)]
+    fn test_save_artifact_include_exclude(
+        #[case] top_level: &str,
+        #[case] name: &str,
+        #[case] expected: bool,
+    ) {
+        let config =
+            mk_artifacts_config(&["packages.*.*", "packages.*.!formatter", "devShells.*.*"]);
+        let actual = config.save_artifact(&s!(top_level), System::x86_linux(), &s!(name));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_allow_failure() {
+        let config: Config = toml::from_str(
+            r#"
+            [build]
+            systems = ["x86_64-linux"]
+            allow-failure = ["checks.*.flaky-test"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let system = System::x86_linux();
+        assert!(config.allow_failure(&s!("checks"), system, &s!("flaky-test")));
+        assert!(!config.allow_failure(&s!("checks"), system, &s!("fmt")));
+        assert!(!config.allow_failure(&s!("packages"), system, &s!("flaky-test")));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let config: Config = toml::from_str(
+            "
+            [general]
+            retries = 3
+            retry-delay = 5
+            ",
+        )
+        .unwrap();
+        assert_eq!(3, config.retries());
+        let delays: Vec<u64> = (1..=3).map(|n| config.retry_delay(n).as_secs()).collect();
+        assert_eq!(vec![5, 10, 20], delays);
+        assert_eq!(Duration::ZERO, Config::default().retry_delay(1));
+    }
+
+    #[rstest]
+    #[case("", ArtifactMode::Symlink)]
+    #[case("[general]\nartifact-mode = \"symlink\"", ArtifactMode::Symlink)]
+    #[case("[general]\nartifact-mode = \"copy\"", ArtifactMode::Copy)]
+    #[case("[general]\nartifact-mode = \"tar\"", ArtifactMode::Tar)]
+    fn test_artifact_mode(#[case] toml: &str, #[case] expected: ArtifactMode) {
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(expected, config.artifact_mode());
+    }
+
+    #[test]
+    fn test_nix_copy_backend() {
+        let config: Config = toml::from_str(
+            r#"
+            [nix-copy]
+            to = "s3://my-cache?region=eu-west-1"
+            publish = true
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert!(config.publish());
+        assert_eq!(
+            Some(CacheBackend::NixCopy("s3://my-cache?region=eu-west-1")),
+            config.cache()
+        );
+    }
+
+    #[test]
+    fn test_only_one_backend() {
+        let config: Config = toml::from_str(
+            r#"
+            [cachix]
+            cache-name = "foo"
+
+            [nix-copy]
+            to = "https://attic.example.com/foo"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_cachix_caches() {
+        let config: Config = toml::from_str(
+            r#"
+            [cachix]
+            cache-name = "foo"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Some(CacheBackend::Cachix(&[s!("foo")])), config.cache());
+
+        let config: Config = toml::from_str(
+            r#"
+            [cachix]
+            cache-name = ["public", "private"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            Some(CacheBackend::Cachix(&[s!("public"), s!("private")])),
+            config.cache()
+        );
+
+        let config: Config = toml::from_str("[cachix]\ncache-name = []").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[rstest]
+    #[case("", PushMode::WatchExec)]
+    #[case("push-mode = \"watch-exec\"", PushMode::WatchExec)]
+    #[case("push-mode = \"explicit\"", PushMode::Explicit)]
+    fn test_push_mode(#[case] line: &str, #[case] expected: PushMode) {
+        let config: Config =
+            toml::from_str(&format!("[cachix]\ncache-name = \"foo\"\n{line}")).unwrap();
+        assert_eq!(expected, config.push_mode());
+    }
+
+    fn lookup(var: &str) -> Option<String> {
+        match var {
+            "HOME" => Some(s!("/home/me")),
+            "CI_SECRET" => Some(s!("hunter2")),
+            _ => None,
+        }
+    }
+
+    #[rstest]
+    #[case("plain", "plain")]
+    #[case("$HOME/cache", "/home/me/cache")]
+    #[case("${CI_SECRET}", "hunter2")]
+    #[case("${HOME}_x", "/home/me_x")]
+    #[case("a${UNSET}b$UNSET", "ab")]
+    #[case("cost: $$5", "cost: $5")]
+    #[case("$$HOME", "$HOME")]
+    #[case("50$", "50$")]
+    #[case("$-x", "$-x")]
+    fn test_interpolate(#[case] value: &str, #[case] expected: &str) {
+        assert_eq!(expected, interpolate(value, lookup, false).unwrap());
+    }
+
+    #[rstest]
+    #[case("$UNSET")]
+    #[case("${UNSET}")]
+    #[case("${HOME")]
+    fn test_interpolate_strict_errors(#[case] value: &str) {
+        assert!(interpolate(value, lookup, true).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        Config::default().validate().unwrap();
+
+        let config: Config = toml::from_str(
+            r#"
+            [build]
+            outputs = ["packages", "pakages"]
+            systems = ["x86_64-linux"]
+            artifacts = ["devShells.*.*", "packages.x86_64-linux.foo", "packages.!aarch64-linux.*"]
+            required = ["apps"]
+            "#,
+        )
+        .unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.is::<InvalidConfig>());
+        let error = error.to_string();
+        assert!(error.contains("'pakages' isn't a flake output"));
+        assert!(error.contains("'devShells' isn't one of the configured outputs"));
+        assert!(error.contains("'aarch64-linux' isn't one of the configured systems"));
+        assert!(error.contains("[build] required: 'apps' isn't one of the configured outputs"));
+        assert!(!error.contains("packages.x86_64-linux.foo"));
+
+        let config: Config = toml::from_str("[general]\nmax-jobs = 0\ncores = 4").unwrap();
+        let error = config.validate().unwrap_err().to_string();
+        assert_eq!("[general] max-jobs: must be at least 1", error);
+    }
+
+    #[test]
+    fn test_validate_auto_systems() {
+        let config: Config = toml::from_str(
+            r#"
+            [build]
+            auto-systems = true
+            artifacts = ["packages.aarch64-linux.*"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert!(config.auto_systems());
+    }
+
+    #[test]
+    fn test_check_naming() {
+        let config: Config = toml::from_str(
+            r#"
+            [check-naming]
+            separator = ":"
+            aliases = { test = "packages", cfg = "nixosConfig" }
+            "#,
+        )
+        .unwrap();
+        let naming = config.check_naming();
+        assert_eq!(':', naming.separator());
+        assert_eq!(Some("packages"), naming.alias("TEST"));
+        assert_eq!(None, naming.alias("pkg"));
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("[check-naming] aliases: 'nixosConfig' isn't a flake output"));
+
+        assert_eq!('-', Config::default().check_naming().separator());
+    }
+
+    #[test]
+    fn test_output_path_round_trip() {
+        for path in [
+            "packages.*.!formatter",
+            "checks.!x86_64-linux.*",
+            "apps.aarch64-darwin.foo",
+        ] {
+            assert_eq!(path, OutputPath::from_str(path).unwrap().to_string());
+        }
     }
 
     #[test]
@@ -495,6 +1596,6 @@ mod tests {
         };
         let actual = output_path.parse_next(&mut input).unwrap();
         assert_eq!(expected, actual);
-        assert_eq!("", input)
+        assert_eq!("", input);
     }
 }
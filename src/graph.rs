@@ -1,15 +1,14 @@
 use anyhow::{bail, Result};
-use owo_colors::OwoColorize;
 use std::cmp::Eq;
-use std::fmt::{Debug, Display};
-use std::ops::IndexMut;
-use std::{collections::HashMap, hash::Hash};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
 
 #[derive(Debug)]
 pub struct Graph<T> {
     nodes: Vec<T>,
     children: Vec<Vec<usize>>,
-    parents: Vec<Option<usize>>,
+    parents: Vec<Vec<usize>>,
 }
 
 impl<T> Graph<T>
@@ -27,7 +26,11 @@ where
     pub fn add_node(&mut self, data: T) {
         self.nodes.push(data);
         self.children.push(Vec::new());
-        self.parents.push(None);
+        self.parents.push(Vec::new());
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &T> {
+        self.nodes.iter()
     }
 
     fn get_index_of(&self, data: &T) -> Option<usize> {
@@ -47,24 +50,79 @@ where
             bail!("Graph not set up for parent {parent:?}");
         };
 
+        // Marking an edge again changes nothing
+        if v.contains(&child_index) {
+            return Ok(());
+        }
         v.push(child_index);
-        self.parents[child_index] = Some(parent_index);
+        self.parents[child_index].push(parent_index);
 
         // Make sure we haven't built a circle
-        // TODO: validate this with some unit tests
-        let mut child_index = child_index;
-        let mut path = vec![child_index];
-        while let Some(parent_index) = self.parents[child_index] {
-            path.push(parent_index);
-            if parent_index == child_index {
-                bail!("Circular graph: {path:?}")
-            }
-            child_index = parent_index;
+        if let Some(cycle) = self.find_cycle() {
+            self.children[parent_index].pop();
+            self.parents[child_index].pop();
+            let path: Vec<String> = cycle
+                .iter()
+                .map(|idx| format!("{:?}", self.nodes[*idx]))
+                .collect();
+            bail!("Circular graph: {}", path.join(" -> "));
         }
 
         Ok(())
     }
 
+    /// Depth first search for a cycle, returning the nodes on it with the first node repeated at
+    /// the end
+    fn find_cycle(&self) -> Option<Vec<usize>> {
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        enum Visit {
+            NotVisited,
+            InProgress,
+            Done,
+        }
+
+        fn visit(
+            graph_children: &[Vec<usize>],
+            current: usize,
+            visits: &mut [Visit],
+            path: &mut Vec<usize>,
+        ) -> Option<Vec<usize>> {
+            visits[current] = Visit::InProgress;
+            path.push(current);
+            for &child in &graph_children[current] {
+                match visits[child] {
+                    Visit::InProgress => {
+                        // The path from the child's first appearance back to it is the cycle
+                        let start = path.iter().position(|idx| *idx == child).unwrap();
+                        let mut cycle = path[start..].to_vec();
+                        cycle.push(child);
+                        return Some(cycle);
+                    }
+                    Visit::NotVisited => {
+                        if let Some(cycle) = visit(graph_children, child, visits, path) {
+                            return Some(cycle);
+                        }
+                    }
+                    Visit::Done => {}
+                }
+            }
+            path.pop();
+            visits[current] = Visit::Done;
+            None
+        }
+
+        let mut visits = vec![Visit::NotVisited; self.len()];
+        for start in 0..self.len() {
+            if visits[start] == Visit::NotVisited {
+                let mut path = Vec::new();
+                if let Some(cycle) = visit(&self.children, start, &mut visits, &mut path) {
+                    return Some(cycle);
+                }
+            }
+        }
+        None
+    }
+
     pub fn walker(self) -> GraphWalker<T> {
         GraphWalker::new(self)
     }
@@ -73,6 +131,51 @@ where
         self.nodes.len()
     }
 
+    /// The nodes in batches that can each be built all at once: the first has the nodes that
+    /// depend on nothing, and every later one the nodes whose dependencies are all in earlier
+    /// batches (Kahn's algorithm). Nodes in a batch keep the order they were added in
+    pub fn topo_levels(&self) -> Result<Vec<Vec<T>>> {
+        let mut unmet: Vec<usize> = self.parents.iter().map(Vec::len).collect();
+        let mut level: Vec<usize> = (0..self.len()).filter(|idx| unmet[*idx] == 0).collect();
+        let mut levels = Vec::new();
+        let mut placed = 0;
+        while !level.is_empty() {
+            placed += level.len();
+            let mut next = Vec::new();
+            for &idx in &level {
+                for &child in &self.children[idx] {
+                    unmet[child] -= 1;
+                    if unmet[child] == 0 {
+                        next.push(child);
+                    }
+                }
+            }
+            next.sort_unstable();
+            levels.push(level.iter().map(|idx| self.data_of(*idx)).collect());
+            level = next;
+        }
+
+        // Whatever's left is waiting on itself
+        if placed < self.len() {
+            let stuck: Vec<String> = (0..self.len())
+                .filter(|idx| unmet[*idx] > 0)
+                .map(|idx| format!("{:?}", self.nodes[idx]))
+                .collect();
+            bail!("Circular graph through {}", stuck.join(", "));
+        }
+        Ok(levels)
+    }
+
+    /// Every node mapped to the nodes it directly depends on
+    pub fn dependencies(&self) -> HashMap<T, Vec<T>> {
+        (0..self.len())
+            .map(|idx| {
+                let parents = self.parents_of(idx).iter().map(|p| self.data_of(*p));
+                (self.data_of(idx), parents.collect())
+            })
+            .collect()
+    }
+
     fn is_leaf(&self, idx: usize) -> bool {
         self.children[idx].is_empty()
     }
@@ -81,8 +184,8 @@ where
         self.nodes[idx].clone()
     }
 
-    fn parent_of(&self, idx: usize) -> Option<usize> {
-        self.parents[idx]
+    fn parents_of(&self, idx: usize) -> &[usize] {
+        &self.parents[idx]
     }
 }
 
@@ -117,31 +220,30 @@ where
         self.walked[index] != Status::NotWalked
     }
 
+    /// Push `current` after everything it depends on
+    fn walk_ancestors(&mut self, current: usize, chain: &mut Vec<T>) {
+        if self.walked(current) {
+            return;
+        }
+        self.walked[current] = Status::Walked;
+        for parent in self.graph.parents_of(current).to_vec() {
+            self.walk_ancestors(parent, chain);
+        }
+        chain.push(self.graph.data_of(current));
+    }
+
+    /// One chain per leaf, holding the leaf and everything it depends on in topological order.
+    /// Nodes shared between leaves (like a check gating several packages) show up in each chain
     pub fn chains(mut self) -> Vec<Vec<T>> {
         let mut chains = Vec::new();
-        let mut current_chain = Vec::new();
 
-        // Find the first unwalked node node
         let num_nodes = self.size;
         for current in 0..num_nodes {
-            if self.walked(current) {
-                continue;
-            }
-
             if self.graph.is_leaf(current) {
-                let mut current = current;
-                let data = self.graph.data_of(current);
-                current_chain.push(data);
-                self.walked[current] = Status::Walked;
-                while let Some(parent) = self.graph.parent_of(current) {
-                    let parent_data = self.graph.data_of(parent);
-                    current_chain.push(parent_data);
-                    self.walked[parent] = Status::Walked;
-                    current = parent;
-                }
-                current_chain.reverse();
-                chains.push(current_chain);
-                current_chain = Vec::new();
+                let mut chain = Vec::new();
+                self.walk_ancestors(current, &mut chain);
+                chains.push(chain);
+                self.walked.fill(Status::NotWalked);
             }
         }
         chains
@@ -153,8 +255,6 @@ mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
 
-    // TODO: test that we can't have a circle
-
     // TODO: test this backwards
     #[test]
     fn test_simple_chain() {
@@ -219,4 +319,145 @@ mod tests {
         }
         assert_eq!(expected, actual);
     }
+
+    fn mk_diamond() -> Graph<&'static str> {
+        // parent -> child
+        // A -> B
+        // A -> C
+        // B -> D
+        // C -> D
+        let mut g = Graph::new();
+        for node in ["A", "B", "C", "D"] {
+            g.add_node(node);
+        }
+        g.mark_dep(&"A", &"B").unwrap();
+        g.mark_dep(&"A", &"C").unwrap();
+        g.mark_dep(&"B", &"D").unwrap();
+        g.mark_dep(&"C", &"D").unwrap();
+        g
+    }
+
+    #[test]
+    fn test_diamond_chains() {
+        let actual = mk_diamond().walker().chains();
+        assert_eq!(vec![vec!["A", "B", "C", "D"]], actual);
+    }
+
+    #[test]
+    fn test_diamond_levels() {
+        let actual = mk_diamond().topo_levels().unwrap();
+        assert_eq!(vec![vec!["A"], vec!["B", "C"], vec!["D"]], actual);
+    }
+
+    #[test]
+    fn test_levels_wait_for_every_parent() {
+        // A -> B -> C, D -> C, so C waits for the longer path
+        let mut g = Graph::new();
+        for node in ["A", "B", "C", "D"] {
+            g.add_node(node);
+        }
+        g.mark_dep(&"A", &"B").unwrap();
+        g.mark_dep(&"B", &"C").unwrap();
+        g.mark_dep(&"D", &"C").unwrap();
+
+        let actual = g.topo_levels().unwrap();
+        assert_eq!(vec![vec!["A", "D"], vec!["B"], vec!["C"]], actual);
+    }
+
+    #[test]
+    fn test_levels_reject_cycle() {
+        // mark_dep won't make a cycle, so put one in by hand
+        let mut g = mk_diamond();
+        g.children[3].push(0);
+        g.parents[0].push(3);
+
+        let err = g.topo_levels().unwrap_err();
+        assert_eq!(
+            r#"Circular graph through "A", "B", "C", "D""#,
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_diamond_dependencies() {
+        let deps = mk_diamond().dependencies();
+        assert_eq!(Vec::<&str>::new(), deps["A"]);
+        assert_eq!(vec!["A"], deps["B"]);
+        assert_eq!(vec!["A"], deps["C"]);
+        assert_eq!(vec!["B", "C"], deps["D"]);
+    }
+
+    #[test]
+    fn test_shared_parent_in_each_chain() {
+        // A -> B, A -> C, D -> C
+        let mut g = Graph::new();
+        for node in ["A", "B", "C", "D"] {
+            g.add_node(node);
+        }
+        g.mark_dep(&"A", &"B").unwrap();
+        g.mark_dep(&"A", &"C").unwrap();
+        g.mark_dep(&"D", &"C").unwrap();
+
+        let actual = g.walker().chains();
+        assert_eq!(vec![vec!["A", "B"], vec!["A", "D", "C"]], actual);
+    }
+
+    #[test]
+    fn test_two_node_cycle() {
+        let mut g = Graph::new();
+        g.add_node("A");
+        g.add_node("B");
+        g.mark_dep(&"A", &"B").unwrap();
+
+        let err = g.mark_dep(&"B", &"A").unwrap_err();
+        assert_eq!(r#"Circular graph: "A" -> "B" -> "A""#, err.to_string());
+
+        // The rejected edge isn't kept around
+        assert_eq!(vec![vec!["A", "B"]], g.walker().chains());
+    }
+
+    #[test]
+    fn test_self_cycle() {
+        let mut g = Graph::new();
+        g.add_node("A");
+        let err = g.mark_dep(&"A", &"A").unwrap_err();
+        assert_eq!(r#"Circular graph: "A" -> "A""#, err.to_string());
+    }
+
+    #[test]
+    fn test_long_cycle() {
+        let mut g = Graph::new();
+        for node in ["A", "B", "C", "D"] {
+            g.add_node(node);
+        }
+        g.mark_dep(&"A", &"B").unwrap();
+        g.mark_dep(&"B", &"C").unwrap();
+        g.mark_dep(&"C", &"D").unwrap();
+
+        let err = g.mark_dep(&"D", &"B").unwrap_err();
+        assert_eq!(
+            r#"Circular graph: "B" -> "C" -> "D" -> "B""#,
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_mark_dep_twice() {
+        let mut g = Graph::new();
+        g.add_node("A");
+        g.add_node("B");
+        g.mark_dep(&"A", &"B").unwrap();
+        g.mark_dep(&"A", &"B").unwrap();
+
+        assert_eq!(vec![1], g.children[0]);
+        assert_eq!(vec![0], g.parents[1]);
+        assert_eq!(vec![vec!["A", "B"]], g.walker().chains());
+    }
+
+    #[test]
+    fn test_diamond_is_not_a_cycle() {
+        // Two paths to the same node aren't circular
+        let g = mk_diamond();
+        assert_eq!(None, g.find_cycle());
+    }
 }
@@ -0,0 +1,40 @@
+//! Build every output of a nix flake, checks first, and summarize how it went
+
+use anyhow::{bail, Result};
+use std::env;
+
+mod app;
+pub use app::{
+    format_chains, format_drv_paths, init, App, ColorChoice, Derivation, Exit, Finished,
+    MissingTool, Node, RunOptions, Status, Summary, SummaryFormat, GITHUB_STEP_SUMMARY,
+};
+
+mod config;
+pub use config::{Arch, Config, InvalidConfig, OutputPath, System, OS};
+
+mod graph;
+mod nix;
+pub use nix::{handle_interrupts, Interrupted};
+
+#[cfg(test)]
+mod test_dir;
+
+/// The nix system this is running on
+pub fn current_system() -> Result<System> {
+    let arch = env::consts::ARCH;
+    let os = env::consts::OS;
+    let system = match (arch, os) {
+        ("x86_64", "linux") => System::x86_linux(),
+        ("aarch64", "linux") => System::arm_linux(),
+        ("x86_64", "macos") => System::x86_darwin(),
+        ("aarch64", "macos") => System::arm_darwin(),
+        ("x86" | "x86_64", "windows") => System::x86_windows(),
+        ("aarch64", "windows") => System::arm_windows(),
+        ("x86", "linux") => System::new(Arch::I686, OS::Linux),
+        ("arm", "linux") => System::new(Arch::Armv7l, OS::Linux),
+        ("riscv64", "linux") => System::new(Arch::Riscv64, OS::Linux),
+        ("x86_64", "freebsd") => System::new(Arch::X86, OS::FreeBSD),
+        _ => bail!("Unknown system: arch: '{arch}, os: '{os}'"),
+    };
+    Ok(system)
+}
@@ -1,82 +1,622 @@
-use anyhow::{bail, Result};
-use clap::Parser;
-use log::debug;
-use std::{env, fs, path::PathBuf};
-
-mod config;
-use config::{Config, System};
-
-mod app;
-use app::App;
-
-mod graph;
-mod nix;
+use anyhow::{bail, Context, Result};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand};
+use flake_ci::{
+    current_system, format_chains, format_drv_paths, handle_interrupts, init, App, ColorChoice,
+    Config, Derivation, Exit, InvalidConfig, OutputPath, RunOptions, Summary, SummaryFormat,
+    System, GITHUB_STEP_SUMMARY,
+};
+use log::{debug, warn};
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
 
 const MAX_WIDTH: usize = 100;
 const CONFIG_FILE_NAME: &str = "flake-ci.toml";
+/// Also looked for, for projects that keep their top level tidy
+const HIDDEN_CONFIG_FILE_NAME: &str = ".flake-ci.toml";
+const NO_COLOR: &str = "NO_COLOR";
 
-// TODO: make this into a lib crate. Also add a bin that calls the function and prints the system
-fn system() -> Result<System> {
-    let arch = env::consts::ARCH;
-    let os = env::consts::OS;
-    let system = match (arch, os) {
-        ("x86_64", "linux") => System::x86_linux(),
-        ("aarch64", "linux") => System::arm_linux(),
-        ("x86_64", "macos") => System::x86_darwin(),
-        ("aarch64", "macos") => System::arm_darwin(),
-        ("x86" | "x86_64", "windows") => System::x86_windows(),
-        ("aarch64", "windows") => System::arm_windows(),
-        _ => bail!("Unknown system: arch: '{arch}, os: '{os}'"),
-    };
-    Ok(system)
-}
-
+/// Build every output of a nix flake, checks first, and summarize how it went
 #[derive(Debug, Parser)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    // Without a subcommand, build
+    #[clap(flatten)]
+    build: BuildArgs,
+    #[clap(flatten)]
+    global: GlobalArgs,
+}
+
+// Options every subcommand understands
+#[derive(Debug, Args)]
+#[allow(clippy::struct_excessive_bools)]
+struct GlobalArgs {
+    /// Project directory to operate on
+    #[clap(long, global = true)]
+    dir: Option<PathBuf>,
+    /// Log more: -v for info, -vv for debug, -vvv for trace. `RUST_LOG` still wins if it's set
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Config file to use instead of looking for flake-ci.toml or .flake-ci.toml in the project
+    /// directory
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+    /// When to color output. Without this, setting the no-color env var (no-color.org) means never
+    #[clap(long, value_enum, global = true)]
+    color: Option<ColorChoice>,
+    /// Width to lay out the summary in, instead of the terminal's. At most 100
+    #[clap(long, global = true)]
+    width: Option<usize>,
+    /// Nix binary to use instead of the one on the $PATH (overrides config)
+    #[clap(long, global = true)]
+    nix: Option<PathBuf>,
+    /// Extra flag to pass to every nix call, like --nix-arg=--impure. Can be repeated
+    #[clap(long, allow_hyphen_values = true, global = true)]
+    nix_arg: Vec<String>,
+    /// Override a flake input for every eval and build, like --override-input nixpkgs
+    /// github:NixOS/nixpkgs/nixos-unstable. Can be repeated. Which systems get built still comes
+    /// from the config, so overriding an input like `systems` only changes what the flake
+    /// provides for those systems
+    #[clap(long, num_args = 2, value_names = ["NAME", "REF"], global = true)]
+    override_input: Vec<String>,
+    /// Also build configured systems this machine isn't, through emulation (overrides config)
+    #[clap(long, global = true)]
+    cross: bool,
+    /// Build the systems the flake has outputs for, as far as this machine can, instead of the
+    /// configured ones (overrides config)
+    #[clap(long, global = true)]
+    auto_systems: bool,
+    /// Build configured systems this machine isn't on the remote builders from nix.conf
+    /// (overrides config)
+    #[clap(long, global = true)]
+    remote_builders: bool,
+    /// Keep nix off the network: evaluate and build only from what's already in the local store,
+    /// and skip cachix
+    #[clap(long, global = true)]
+    offline: bool,
+    /// Evaluate everything instead of reusing attributes and drv paths cached by earlier runs
+    /// at the same flake revision
+    #[clap(long, global = true)]
+    no_eval_cache: bool,
+    /// Flake to build instead of the one in the project directory, like `github:me/repo` or
+    /// `/path/to/flake`. The config still comes from the project directory. Unless it's a path,
+    /// git info, the flake.lock check and artifacts are skipped, and --changed-since can't be used
+    #[clap(long, global = true)]
+    flake: Option<String>,
+    /// Only build this one of the configured systems, like `aarch64-linux`. Other systems still
+    /// need cross or remote-builders
+    #[clap(long, global = true)]
+    only_system: Option<System>,
+    /// Only build derivations matching this pattern, like `packages.*.foo`. Can be repeated
+    #[clap(long, global = true)]
+    filter: Vec<OutputPath>,
+    /// Only build the packages changed since this git ref, and the checks gating them. Changed
+    /// packages are found with `[build] package-dirs`. Anything else changing builds everything
+    #[clap(long, global = true, conflicts_with = "filter")]
+    changed_since: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Args)]
+#[allow(clippy::struct_excessive_bools)]
+struct BuildArgs {
+    /// Build just these, like `packages.x86_64-linux.foo`, along with the checks gating them,
+    /// instead of every output
+    #[clap(conflicts_with_all = ["filter", "changed_since"])]
+    targets: Vec<Derivation>,
     /// Print what would be done without doing anything
     #[clap(long)]
     dry_run: bool,
-    /// Project directory to operate on
-    #[clap(long)]
-    dir: Option<PathBuf>,
+    /// With --dry-run, show what each derivation would build and fetch, and how much it would
+    /// download
+    #[clap(long, requires = "dry_run")]
+    explain: bool,
     /// Publish build artifacts to cachix
     #[clap(long)]
     publish: bool,
+    /// How to print the summary at the end
+    #[clap(long, value_enum, default_value_t)]
+    format: SummaryFormat,
+    /// Print the summary's jobs under a header for each system
+    #[clap(long)]
+    group_by_system: bool,
+    /// Rebuild everything that built or was already in the store with `nix build --rebuild`, and
+    /// fail any whose outputs come out different the second time
+    #[clap(long)]
+    check_reproducibility: bool,
+    /// Look up the closure size of everything that built or was already in the store and put
+    /// it in the summary. Costs an extra nix call per derivation
+    #[clap(long)]
+    sizes: bool,
+    /// Don't touch the artifact dir or save anything into it. Results are reported the same
+    #[clap(long)]
+    no_artifacts: bool,
+    /// Pass build logs through as nix prints them, instead of starting each line with the
+    /// derivation it's from
+    #[clap(long)]
+    no_prefix: bool,
+    /// Same as --format json
+    #[clap(long, hide = true, conflicts_with = "format")]
+    json: bool,
+    /// Don't show progress while evaluating and building, and only log warnings and errors.
+    /// Progress is also hidden when stdout isn't a terminal
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Number of chains to build at once (overrides config)
+    #[clap(long)]
+    jobs: Option<usize>,
+    /// Builds nix may run at once for each chain, passed to nix as --max-jobs. Not the same as
+    /// --jobs, which is how many chains run at once (overrides config)
+    #[clap(long)]
+    max_jobs: Option<usize>,
+    /// Cores each nix build may use, passed to nix as --cores (overrides config)
+    #[clap(long)]
+    cores: Option<usize>,
+    /// Kill a build after this many seconds (overrides config)
+    #[clap(long)]
+    timeout: Option<u64>,
+    /// Rebuild a failed derivation up to this many times before calling it failed (overrides
+    /// config)
+    #[clap(long)]
+    retries: Option<u32>,
+    /// Record errors against the derivation that caused them and keep building everything else
+    #[clap(long)]
+    keep_going: bool,
+    /// Stop the whole run at the first failure instead of carrying on with other chains
+    #[clap(long, conflicts_with = "keep_going")]
+    fail_fast: bool,
+    /// Report to GitHub Actions with annotations and a job summary. On by default inside actions
+    #[clap(long)]
+    github: bool,
+    /// Write build events to this path as they happen, one JSON object per line. `-` is stdout
+    #[clap(long)]
+    events: Option<PathBuf>,
+    /// Write a JUnit XML report to this path
+    #[clap(long)]
+    junit: Option<PathBuf>,
+    /// Fail instead of warning when flake.lock is missing or older than flake.nix, or when none of
+    /// the configured systems can build here
+    #[clap(long)]
+    strict: bool,
+    /// Build derivations even if their outputs are already in the nix store
+    #[clap(long)]
+    rebuild: bool,
+    /// Don't check that the nix store is reachable before starting, for stores that don't answer
+    /// `nix store ping`
+    #[clap(long)]
+    skip_store_check: bool,
+    /// Run `nix flake check --no-build` before building, and stop if it fails unless keeping
+    /// going (overrides config)
+    #[clap(long)]
+    flake_check: bool,
+}
+
+impl BuildArgs {
+    fn summary_format(&self) -> SummaryFormat {
+        if self.json {
+            SummaryFormat::Json
+        } else {
+            self.format
+        }
+    }
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Build everything, checks first. What running without a subcommand does
+    Build(BuildArgs),
+    /// List the derivations that would be built, chain by chain, without building anything
+    List {
+        /// Only print drv paths, one per line
+        #[clap(long)]
+        print_drv_paths: bool,
+    },
+    /// Check the config and exit without touching the flake
+    Validate,
+    /// Write a starter flake-ci.toml for the flake's outputs and systems
+    Init {
+        /// Overwrite an existing config
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print the summary of an earlier run from its `--format json` output, without building
+    /// anything. Exits the way that run did
+    Report {
+        /// The JSON summary to read
+        #[clap(long)]
+        from: PathBuf,
+        /// How to print the summary
+        #[clap(long, value_enum, default_value_t)]
+        format: SummaryFormat,
+        /// Print the summary's jobs under a header for each system
+        #[clap(long)]
+        group_by_system: bool,
+    },
+}
+
+fn main() {
+    let exit = match run() {
+        Ok(exit) => exit,
+        Err(e) => {
+            let exit = Exit::from_error(&e);
+            if exit == Exit::Interrupted {
+                eprintln!("Interrupted");
+            } else {
+                eprintln!("Error: {e:?}");
+            }
+            exit
+        }
+    };
+    std::process::exit(exit.code());
+}
+
+fn run() -> Result<Exit> {
     let cwd = env::current_dir()?;
 
     let args = Cli::parse();
-    let working_dir = match args.dir {
+    if args.command.is_some() && args.build != BuildArgs::default() {
+        // Global options may come before a subcommand, but build options only go with build
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "build options go after `build`, or are used without a subcommand",
+            )
+            .exit();
+    }
+    let global = args.global;
+    let command = args.command.unwrap_or(Command::Build(args.build));
+    let (dry_run, quiet) = match &command {
+        Command::Build(build) => (build.dry_run, build.quiet),
+        _ => (false, false),
+    };
+    let filter = log_filter(global.verbose, quiet, dry_run);
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter)).init();
+    handle_interrupts()?;
+    let working_dir = match &global.dir {
         Some(dir) => {
-            let dir = fs::canonicalize(dir)?;
-            env::set_current_dir(&dir)?;
+            let dir = fs::canonicalize(dir).with_context(|| {
+                InvalidConfig(format!(
+                    "Project directory '{}' does not exist",
+                    dir.display()
+                ))
+            })?;
+            env::set_current_dir(&dir)
+                .with_context(|| format!("Failed to change directory to '{}'", dir.display()))?;
             dir
         }
         None => cwd.clone(),
     };
 
-    // TODO: search back for repo root instead of using cwd
-    let config_file = working_dir.join(CONFIG_FILE_NAME);
-    let config = if config_file.is_file() {
-        Config::from_file(&config_file)?
-    } else {
-        Config::default()
+    let width = match (global.width, term_size::dimensions()) {
+        (Some(w), _) | (None, Some((w, _))) => std::cmp::min(w, MAX_WIDTH),
+        (None, None) => MAX_WIDTH,
     };
 
-    let system = system()?;
-    let width = match term_size::dimensions() {
-        Some((w, _)) => std::cmp::min(w, MAX_WIDTH),
-        None => MAX_WIDTH,
+    let (build, list) = match command {
+        Command::Init { force } => {
+            let config_file = match &global.config {
+                Some(config_file) => cwd.join(config_file),
+                None => find_config_file(&working_dir)
+                    .unwrap_or_else(|| working_dir.join(CONFIG_FILE_NAME)),
+            };
+            let nix = global.nix.as_ref().map(|nix| cwd.join(nix));
+            init(&config_file, nix.as_deref(), force)?;
+            println!("Wrote {}", config_file.display());
+            return Ok(Exit::Success);
+        }
+        Command::Validate => {
+            load_config(&global, &BuildArgs::default(), &cwd, &working_dir)?;
+            println!("Config is valid");
+            return Ok(Exit::Success);
+        }
+        Command::Report {
+            from,
+            format,
+            group_by_system,
+        } => {
+            let color = color_choice(global.color);
+            return report(&cwd.join(from), format, group_by_system, color, cwd, width);
+        }
+        // Some(print_drv_paths) when listing
+        Command::List { print_drv_paths } => (BuildArgs::default(), Some(print_drv_paths)),
+        Command::Build(build) => (build, None),
     };
+    let config = load_config(&global, &build, &cwd, &working_dir)?;
+
+    let system = current_system()?;
 
     debug!("{config:?}");
 
-    let app = App::with_config(cwd, &working_dir, system, width, config)?;
-    if !app.run(args.dry_run)? {
-        std::process::exit(1);
+    let options = run_options(global, &build, &cwd, list.is_some());
+
+    let github = options.github;
+    let app = App::with_config(cwd, &working_dir, system, width, config, options)?;
+    if let Some(print_drv_paths) = list {
+        let chains = app.list()?;
+        if print_drv_paths {
+            print!("{}", format_drv_paths(&chains));
+        } else {
+            print!("{}", format_chains(&chains));
+        }
+        return Ok(Exit::Success);
     }
+
+    let finished = app.run()?;
+    eprint!("{}", finished.plan);
+    print_summary(
+        &finished.summary,
+        build.summary_format(),
+        finished.exit == Exit::Success,
+        github,
+        &mut io::stdout(),
+        &mut io::stderr(),
+    )?;
+    Ok(finished.exit)
+}
+
+/// Print the summary to `out`. GitHub annotations go to `err`, where actions still picks them up,
+/// so they can't break a machine-readable summary
+fn print_summary(
+    summary: &Summary,
+    format: SummaryFormat,
+    succeeded: bool,
+    github: bool,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> Result<()> {
+    if github {
+        for annotation in summary.github_annotations() {
+            writeln!(err, "{annotation}")?;
+        }
+    }
+    write!(out, "{}", summary.render_as(format, succeeded)?)?;
     Ok(())
 }
+
+/// Print the summary of an earlier run from its JSON summary at `from`
+fn report(
+    from: &Path,
+    format: SummaryFormat,
+    group_by_system: bool,
+    color: ColorChoice,
+    cwd: PathBuf,
+    width: usize,
+) -> Result<Exit> {
+    let json = fs::read_to_string(from)
+        .with_context(|| InvalidConfig(format!("Failed to read '{}'", from.display())))?;
+    let (mut summary, succeeded) = Summary::from_json(&json, cwd, width).with_context(|| {
+        InvalidConfig(format!(
+            "'{}' isn't a flake-ci JSON summary",
+            from.display()
+        ))
+    })?;
+    summary.set_color(color);
+    summary.set_group_by_system(group_by_system);
+    print!("{}", summary.render_as(format, succeeded)?);
+    Ok(if succeeded {
+        Exit::Success
+    } else {
+        Exit::BuildFailure
+    })
+}
+
+/// The log level to use when `RUST_LOG` isn't set
+fn log_filter(verbose: u8, quiet: bool, dry_run: bool) -> &'static str {
+    match verbose {
+        0 if quiet => "warn",
+        // A dry run is only useful if it says what it would have done
+        0 if dry_run => "info",
+        0 => "error",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    }
+}
+
+/// When to color the summary: as asked, or never if the no-color env var says so
+fn color_choice(color: Option<ColorChoice>) -> ColorChoice {
+    color.unwrap_or_else(|| {
+        // https://no-color.org: any non-empty value turns color off
+        if env::var_os(NO_COLOR).is_some_and(|value| !value.is_empty()) {
+            ColorChoice::Never
+        } else {
+            ColorChoice::Auto
+        }
+    })
+}
+
+/// How to run, from the command line and environment
+fn run_options(global: GlobalArgs, build: &BuildArgs, cwd: &Path, list: bool) -> RunOptions {
+    RunOptions {
+        dry_run: build.dry_run,
+        explain: build.explain,
+        keep_going: build.keep_going,
+        fail_fast: build.fail_fast,
+        skip_store_check: build.skip_store_check,
+        offline: global.offline,
+        github: build.github || env::var_os(GITHUB_STEP_SUMMARY).is_some(),
+        junit: build.junit.as_ref().map(|path| cwd.join(path)),
+        rebuild: build.rebuild,
+        color: color_choice(global.color),
+        group_by_system: build.group_by_system,
+        check_reproducibility: build.check_reproducibility,
+        sizes: build.sizes,
+        no_artifacts: build.no_artifacts,
+        no_prefix: build.no_prefix,
+        no_eval_cache: global.no_eval_cache,
+        flake: global.flake,
+        only_system: global.only_system,
+        filters: global.filter,
+        targets: build.targets.clone(),
+        changed_since: global.changed_since,
+        strict: build.strict,
+        progress: !list
+            && build.summary_format() == SummaryFormat::Human
+            && !build.quiet
+            && io::stdout().is_terminal(),
+        events: build.events.as_ref().map(|path| {
+            if path == Path::new("-") {
+                path.clone()
+            } else {
+                cwd.join(path)
+            }
+        }),
+        override_inputs: global
+            .override_input
+            .chunks_exact(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect(),
+    }
+}
+
+/// The config file in `dir`: `flake-ci.toml`, or `.flake-ci.toml` if that's the only one
+fn find_config_file(dir: &Path) -> Option<PathBuf> {
+    let visible = dir.join(CONFIG_FILE_NAME);
+    let hidden = dir.join(HIDDEN_CONFIG_FILE_NAME);
+    match (visible.is_file(), hidden.is_file()) {
+        (true, true) => {
+            warn!(
+                "Found both {CONFIG_FILE_NAME} and {HIDDEN_CONFIG_FILE_NAME}, using {CONFIG_FILE_NAME}"
+            );
+            Some(visible)
+        }
+        (true, false) => Some(visible),
+        (false, true) => Some(hidden),
+        (false, false) => None,
+    }
+}
+
+/// The config file from `--config` or the project directory, with command line overrides applied
+fn load_config(
+    global: &GlobalArgs,
+    build: &BuildArgs,
+    cwd: &Path,
+    working_dir: &Path,
+) -> Result<Config> {
+    let mut config = if let Some(config_file) = &global.config {
+        // Relative to where we were run from, not --dir
+        let config_file = cwd.join(config_file);
+        if !config_file.is_file() {
+            bail!(InvalidConfig(format!(
+                "Config file '{}' does not exist",
+                config_file.display()
+            )));
+        }
+        Config::from_file(&config_file)?
+    } else {
+        // TODO: search back for repo root instead of using cwd
+        match find_config_file(working_dir) {
+            Some(config_file) => Config::from_file(&config_file)?,
+            None => Config::default(),
+        }
+    };
+    if let Some(jobs) = build.jobs {
+        config.set_jobs(jobs);
+    }
+    if let Some(max_jobs) = build.max_jobs {
+        config.set_max_jobs(max_jobs);
+    }
+    if let Some(cores) = build.cores {
+        config.set_cores(cores);
+    }
+    if let Some(timeout) = build.timeout {
+        config.set_timeout(timeout);
+    }
+    if let Some(retries) = build.retries {
+        config.set_retries(retries);
+    }
+    if build.flake_check {
+        config.set_flake_check(true);
+    }
+    if let Some(nix) = &global.nix {
+        config.set_nix(cwd.join(nix));
+    }
+    config.add_nix_args(global.nix_arg.clone());
+    if global.cross {
+        config.set_cross(true);
+    }
+    if global.remote_builders {
+        config.set_remote_builders(true);
+    }
+    if global.auto_systems {
+        config.set_auto_systems(true);
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    /// A run whose only problem is a missing required output, so it has an annotation
+    fn failed_summary() -> Summary {
+        let json = serde_json::json!({
+            "succeeded": false,
+            "skipped_outputs": [],
+            "missing_outputs": [{"output": "packages", "reason": "not found"}],
+            "successes": {},
+            "fails": {},
+            "skips": {},
+            "blocks": {},
+            "pins": [],
+            "systems": {},
+            "skipped_systems": [],
+            "no_systems_built": false,
+            "nix_version": "nix (Nix) 2.24.12",
+            "cachix_version": null,
+            "git_revision": {"hash": "abc1234", "branch": null, "describe": null, "dirty": false},
+            "flake_lock": null,
+            "flake_check_failed": false,
+            "warnings": [],
+            "inputs": {},
+        });
+        let (summary, _) =
+            Summary::from_json(&json.to_string(), PathBuf::from("/tmp"), 80).unwrap();
+        summary
+    }
+
+    #[rstest]
+    #[case(0, false, false, "error")]
+    #[case(1, false, false, "info")]
+    #[case(2, false, false, "debug")]
+    #[case(3, false, false, "trace")]
+    #[case(4, false, false, "trace")]
+    #[case(0, true, false, "warn")]
+    #[case(0, false, true, "info")]
+    #[case(0, true, true, "warn")]
+    #[case(2, false, true, "debug")]
+    fn test_log_filter(
+        #[case] verbose: u8,
+        #[case] quiet: bool,
+        #[case] dry_run: bool,
+        #[case] expected: &str,
+    ) {
+        assert_eq!(expected, log_filter(verbose, quiet, dry_run));
+    }
+
+    #[test]
+    fn test_json_summary_parses_with_github_annotations() {
+        let summary = failed_summary();
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        print_summary(
+            &summary,
+            SummaryFormat::Json,
+            false,
+            true,
+            &mut out,
+            &mut err,
+        )
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(Some(false), json["succeeded"].as_bool());
+        let err = String::from_utf8(err).unwrap();
+        assert!(err.starts_with("::error"), "{err}");
+    }
+}
@@ -1,40 +1,166 @@
 use anyhow::bail;
-use anyhow::Result;
-use log::debug;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
 use std::collections::HashMap;
-use std::os::unix::process::ExitStatusExt;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::app::Status;
 
-pub fn run(exec: &Path, args: &[&str]) -> Result<String> {
-    debug!("Running command: {} {:?}", exec.display(), args);
-    let output = Command::new(exec).args(args).output()?;
-    let status = output.status;
+/// How often to check on a command with a timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a timed out command gets to exit after being asked before it's killed
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Set once the user asks to stop
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Process groups of streamed commands that are still running, so they can be stopped on
+/// interrupt
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// The run was stopped by the user
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted")
+    }
+}
 
-    if !status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let stderr = stderr.trim();
-        if !stderr.is_empty() {
-            debug!("[stderr]{}", stderr);
+impl std::error::Error for Interrupted {}
+
+/// On ctrl-c (or SIGTERM), stop any running commands so the run can finish up early.
+/// Asking twice kills them outright instead of waiting for them to stop
+pub fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        let signal = if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            libc::SIGKILL
+        } else {
+            warn!("Interrupted, stopping running builds. Press ctrl-c again to kill them");
+            libc::SIGTERM
+        };
+        for pgid in RUNNING.lock().unwrap().iter() {
+            signal_group(*pgid, signal);
         }
+    })?;
+    Ok(())
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Start a command in its own process group and keep track of it until `forget` is called
+fn spawn(cmd: &mut Command) -> Result<Child> {
+    // Held across the spawn so an interrupt can't slip in between starting and tracking
+    let mut running = RUNNING.lock().unwrap();
+    if interrupted() {
+        bail!(Interrupted);
+    }
+    // Own process group, so the builders nix spawns can be killed along with it
+    let child = cmd.process_group(0).spawn()?;
+    running.push(child.id());
+    Ok(child)
+}
+
+fn forget(child: &Child) {
+    RUNNING.lock().unwrap().retain(|pgid| *pgid != child.id());
+}
+
+/// How much of a failed command's stderr to put in its error
+const MAX_STDERR_LEN: usize = 1000;
+
+/// Cut stderr down to something that fits in an error message
+fn truncate_stderr(stderr: &str) -> String {
+    match stderr.char_indices().nth(MAX_STDERR_LEN) {
+        Some((end, _)) => format!("{}...", &stderr[..end]),
+        None => stderr.to_owned(),
+    }
+}
+
+/// What a command run with `try_run` printed, and how it exited
+#[derive(Debug)]
+pub struct Ran {
+    pub stdout: String,
+    pub stderr: String,
+    /// None if it was killed by a signal
+    pub code: Option<i32>,
+    status: ExitStatus,
+}
+
+impl Ran {
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
 
-        let code = match status.code() {
+    /// Fail with the command's stderr if it failed
+    pub fn check(self, exec: &Path) -> Result<Self> {
+        if self.success() {
+            return Ok(self);
+        }
+        if !self.stderr.is_empty() {
+            debug!("[stderr]{}", self.stderr);
+        }
+
+        let code = match self.code {
             Some(code) => code,
-            None => status
+            None => self
+                .status
                 .signal()
                 .expect("Process was killed by a signal, but we couldn't get the signal type"),
         };
+        if self.stderr.is_empty() {
+            bail!(
+                "'{}' exited with non-zero exit code {}",
+                exec.display(),
+                code
+            );
+        }
         bail!(
-            "'{}' exited with non-zero exit code {}",
+            "'{}' exited with non-zero exit code {}: {}",
             exec.display(),
-            code
+            code,
+            truncate_stderr(&self.stderr)
         );
     }
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    Ok(stdout)
+pub fn run(exec: &Path, args: &[&str]) -> Result<String> {
+    run_with_env(exec, args, &HashMap::new())
+}
+
+/// Like `run`, with extra environment variables
+pub fn run_with_env(exec: &Path, args: &[&str], env: &HashMap<String, String>) -> Result<String> {
+    Ok(try_run(exec, args, env)?.check(exec)?.stdout)
+}
+
+/// Like `run`, but return what the command wrote to stderr instead of stdout
+pub fn run_stderr(exec: &Path, args: &[&str]) -> Result<String> {
+    Ok(try_run(exec, args, &HashMap::new())?.check(exec)?.stderr)
+}
+
+/// Run a command to completion. Only fails if it couldn't be run, so callers can tell apart
+/// the ways it exited
+pub fn try_run(exec: &Path, args: &[&str], env: &HashMap<String, String>) -> Result<Ran> {
+    debug!("Running command: {} {:?}", exec.display(), args);
+    let output = Command::new(exec).args(args).envs(env).output()?;
+    Ok(Ran {
+        stdout: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        code: output.status.code(),
+        status: output.status,
+    })
 }
 
 pub fn run_stream(
@@ -48,14 +174,289 @@ pub fn run_stream(
     cmd = cmd.args(args);
     if let Some(env) = env {
         cmd = cmd.envs(env);
-    };
-    let status = if dry_run {
-        println!("[DRYRUN] Would run '{cmd:?}'");
-        Status::Skipped
-    } else if cmd.status()?.success() {
+    }
+    if dry_run {
+        info!("[DRYRUN] Would run '{cmd:?}'");
+        return Ok(Status::Skipped);
+    }
+
+    let mut child = spawn(cmd)?;
+    let exit_status = child.wait();
+    forget(&child);
+    let status = if exit_status?.success() {
         Status::Success
     } else {
         Status::Fail
     };
     Ok(status)
 }
+
+/// What a command run with `run_stream_stdout` did
+#[derive(Debug)]
+pub struct Captured {
+    pub status: Status,
+    pub stdout: String,
+    /// The command was killed for running past its timeout
+    pub timed_out: bool,
+}
+
+/// Copy lines from `reader` to `out`, each starting with `[prefix] ` if there is one, and to
+/// `log` as they are. Lines that aren't UTF-8 are copied lossily
+fn tee_lines(
+    reader: impl BufRead,
+    prefix: Option<&str>,
+    out: &mut impl Write,
+    log: Option<&Mutex<impl Write>>,
+) -> io::Result<()> {
+    for line in reader.split(b'\n') {
+        let line = line?;
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches('\r');
+        match prefix {
+            Some(prefix) => writeln!(out, "[{prefix}] {line}")?,
+            None => writeln!(out, "{line}")?,
+        }
+        if let Some(log) = log {
+            writeln!(log.lock().unwrap(), "{line}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Like `run_stream`, but capture stdout instead of passing it through. Stderr still streams,
+/// with each line prefixed by `[prefix]` if there is one. Both are also written to `log` if
+/// there is one. With a timeout, the command and everything it spawned are killed once it runs
+/// too long
+pub fn run_stream_stdout(
+    exec: &Path,
+    args: &[&str],
+    env: Option<&HashMap<String, String>>,
+    dry_run: bool,
+    timeout: Option<Duration>,
+    prefix: Option<&str>,
+    log: Option<&Path>,
+) -> Result<Captured> {
+    debug!("Running command: {} {args:?}", exec.display());
+    let mut cmd = &mut Command::new(exec);
+    cmd = cmd
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(if prefix.is_some() || log.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        });
+    if let Some(env) = env {
+        cmd = cmd.envs(env);
+    }
+    if dry_run {
+        info!("[DRYRUN] Would run '{cmd:?}'");
+        return Ok(Captured {
+            status: Status::Skipped,
+            stdout: String::new(),
+            timed_out: false,
+        });
+    }
+
+    let log_file = log
+        .map(|path| {
+            File::create(path).with_context(|| format!("Failed to create '{}'", path.display()))
+        })
+        .transpose()?
+        .map(|file| Arc::new(Mutex::new(file)));
+    let tee = prefix.is_some() || log_file.is_some();
+    let mut child = match spawn(cmd) {
+        Ok(child) => child,
+        Err(e) => {
+            // Nothing ran, so an empty log would only mislead
+            if let Some(path) = log {
+                if let Err(e) = fs::remove_file(path) {
+                    debug!("Failed to remove '{}': {e}", path.display());
+                }
+            }
+            return Err(e);
+        }
+    };
+    let pipe = child.stdout.take().expect("stdout is piped");
+    let stdout_log = log_file.clone();
+    let reader = thread::spawn(move || {
+        let mut stdout = Vec::new();
+        tee_lines(
+            BufReader::new(pipe),
+            None,
+            &mut stdout,
+            stdout_log.as_deref(),
+        )
+        .map(|()| stdout)
+    });
+    let teer = tee.then(|| {
+        let pipe = child.stderr.take().expect("stderr is piped");
+        let prefix = prefix.map(str::to_owned);
+        let name = exec.display().to_string();
+        thread::spawn(move || {
+            if let Err(e) = tee_lines(
+                BufReader::new(pipe),
+                prefix.as_deref(),
+                &mut io::stderr(),
+                log_file.as_deref(),
+            ) {
+                debug!("Stopped passing through stderr of '{name}': {e}");
+            }
+        })
+    });
+
+    let waited = match timeout {
+        Some(timeout) => wait_with_timeout(&mut child, timeout),
+        None => child
+            .wait()
+            .map(|status| (status, false))
+            .map_err(Into::into),
+    };
+    forget(&child);
+    let (exit_status, timed_out) = waited?;
+    let stdout = reader.join().expect("stdout reader panicked")?;
+    if let Some(teer) = teer {
+        teer.join().expect("stderr passthrough panicked");
+    }
+    let stdout = String::from_utf8_lossy(&stdout).trim().to_string();
+
+    let status = if exit_status.success() && !timed_out {
+        Status::Success
+    } else {
+        Status::Fail
+    };
+    Ok(Captured {
+        status,
+        stdout,
+        timed_out,
+    })
+}
+
+/// Wait for a child started with `spawn`, killing the group if it outlives
+/// `timeout`. Returns whether it was killed
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Result<(ExitStatus, bool)> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    warn!(
+        "Timed out after {}s, killing process {}",
+        timeout.as_secs(),
+        child.id()
+    );
+    signal_group(child.id(), libc::SIGTERM);
+    let grace_deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < grace_deadline && child.try_wait()?.is_none() {
+        thread::sleep(POLL_INTERVAL);
+    }
+    // Also takes out anything still hanging around after the child itself exited
+    signal_group(child.id(), libc::SIGKILL);
+    Ok((child.wait()?, true))
+}
+
+fn signal_group(pgid: u32, signal: libc::c_int) {
+    let Ok(pgid) = libc::pid_t::try_from(pgid) else {
+        return;
+    };
+    // SAFETY: killpg has no memory safety requirements. The group may already be gone, which
+    // is fine
+    unsafe {
+        libc::killpg(pgid, signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_timeout_kills_command() {
+        let sh = Path::new("/bin/sh");
+        let timeout = Some(Duration::from_millis(200));
+
+        let captured =
+            run_stream_stdout(sh, &["-c", "sleep 30"], None, false, timeout, None, None).unwrap();
+        assert!(captured.timed_out);
+        assert!(matches!(captured.status, Status::Fail));
+
+        let captured =
+            run_stream_stdout(sh, &["-c", "echo hi"], None, false, timeout, None, None).unwrap();
+        assert!(!captured.timed_out);
+        assert!(matches!(captured.status, Status::Success));
+        assert_eq!("hi", captured.stdout);
+    }
+
+    #[test]
+    fn test_tee_lines() {
+        let logs = b"building '/nix/store/foo.drv'...\r\nfoo> \xffok\n".as_slice();
+        let mut out = Vec::new();
+        let log = Mutex::new(Vec::new());
+        let prefix = Some("packages.x86_64-linux.foo");
+        tee_lines(logs, prefix, &mut out, Some(&log)).unwrap();
+        let expected = "[packages.x86_64-linux.foo] building '/nix/store/foo.drv'...\n\
+                        [packages.x86_64-linux.foo] foo> \u{fffd}ok\n";
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+        let expected = "building '/nix/store/foo.drv'...\nfoo> \u{fffd}ok\n";
+        assert_eq!(
+            expected,
+            String::from_utf8(log.into_inner().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_log_has_stdout_and_stderr() {
+        let dir = TestDir::new("build-log");
+        let log = dir.join("build.log");
+        let sh = Path::new("/bin/sh");
+
+        let script = "echo building >&2; sleep 0.1; echo /nix/store/abc-foo";
+        let args = ["-c", script];
+        let captured = run_stream_stdout(sh, &args, None, false, None, None, Some(&log)).unwrap();
+        assert_eq!("/nix/store/abc-foo", captured.stdout);
+        let logged = fs::read_to_string(&log).unwrap();
+        assert_eq!("building\n/nix/store/abc-foo\n", logged);
+
+        // A command that never started leaves no log behind
+        let missing = dir.join("missing");
+        let result = run_stream_stdout(&missing, &[], None, false, None, None, Some(&log));
+        assert!(result.is_err());
+        assert!(!log.exists());
+    }
+
+    #[test]
+    fn test_try_run() {
+        let sh = Path::new("/bin/sh");
+        let env = HashMap::new();
+
+        let ran = try_run(sh, &["-c", "echo out; echo err >&2; exit 3"], &env).unwrap();
+        assert!(!ran.success());
+        assert_eq!(Some(3), ran.code);
+        assert_eq!("out", ran.stdout);
+        assert_eq!("err", ran.stderr);
+
+        let error = run(sh, &["-c", "echo err >&2; exit 3"]).unwrap_err();
+        assert_eq!(
+            "'/bin/sh' exited with non-zero exit code 3: err",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_short_stderr_untouched() {
+        let stderr = "error: flake 'path:/tmp' does not provide attribute 'packages'";
+        assert_eq!(stderr, truncate_stderr(stderr));
+    }
+
+    #[test]
+    fn test_long_stderr_truncated() {
+        let stderr = "é".repeat(MAX_STDERR_LEN + 1);
+        let expected = format!("{}...", "é".repeat(MAX_STDERR_LEN));
+        assert_eq!(expected, truncate_stderr(&stderr));
+    }
+}
@@ -0,0 +1,35 @@
+//! Scratch directories for tests
+
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// An empty directory under the system temp dir, removed when dropped so a failing test doesn't
+/// leave it behind
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// `name` keeps tests running at the same time out of each other's way
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("flake-ci-{name}-{}", std::process::id()));
+        // Left over from a run that was killed before it could clean up
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}